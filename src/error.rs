@@ -0,0 +1,183 @@
+use std::fmt::Display;
+use std::io::Error as IoError;
+
+/// Errors from reading MAF, TAF and VCF files
+#[derive(Debug)]
+pub enum OxidMafError {
+    /// The file could not be opened or read
+    Io { file: String, source: IoError },
+    /// A line could not be parsed. `line` is 1-based; for TAF files read through an index it
+    /// counts from the last seek.
+    Malformed {
+        file: String,
+        line: u64,
+        text: String,
+        message: String,
+    },
+}
+
+impl OxidMafError {
+    pub fn io(file: &str, source: IoError) -> Self {
+        OxidMafError::Io {
+            file: file.to_string(),
+            source,
+        }
+    }
+
+    pub fn malformed(file: &str, line: u64, text: &str, message: impl Into<String>) -> Self {
+        OxidMafError::Malformed {
+            file: file.to_string(),
+            line,
+            text: text.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// An argument or input file that cannot be used, e.g. stdin where a file is read twice
+    pub fn invalid(file: &str, message: impl Into<String>) -> Self {
+        OxidMafError::io(
+            file,
+            IoError::new(std::io::ErrorKind::InvalidInput, message.into()),
+        )
+    }
+
+    /// Malformed input can be skipped, I/O errors cannot
+    pub fn is_malformed(&self) -> bool {
+        matches!(self, OxidMafError::Malformed { .. })
+    }
+}
+
+impl Display for OxidMafError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OxidMafError::Io { file, source } => write!(f, "{}: {}", file, source),
+            OxidMafError::Malformed {
+                file,
+                line,
+                text,
+                message,
+            } => {
+                // Alignment rows can be very long, only show the start of the line
+                let text = match text.char_indices().nth(80) {
+                    Some((idx, _)) => format!("{}...", &text[..idx]),
+                    None => text.to_string(),
+                };
+                write!(f, "{}:{}: {}: {}", file, line, message, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OxidMafError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OxidMafError::Io { source, .. } => Some(source),
+            OxidMafError::Malformed { .. } => None,
+        }
+    }
+}
+
+/// Skips malformed records of the parsers, for functions taking `skip_malformed`
+///
+/// Without `skip_malformed`, every error is returned. With it, the errors of malformed records are
+/// kept in [`SkipMalformed::skipped`] and the records are dropped; I/O errors are still returned.
+#[derive(Debug, Default)]
+pub struct SkipMalformed {
+    skip_malformed: bool,
+    /// Errors of the records skipped, in the order they were read
+    pub skipped: Vec<OxidMafError>,
+    // First error ending an iterator from `filter`
+    error: Option<OxidMafError>,
+}
+
+impl SkipMalformed {
+    pub fn new(skip_malformed: bool) -> Self {
+        SkipMalformed {
+            skip_malformed,
+            ..Default::default()
+        }
+    }
+
+    /// The record, `None` if it was skipped, or the error
+    pub fn check<T>(&mut self, result: Result<T, OxidMafError>) -> Result<Option<T>, OxidMafError> {
+        match result {
+            Ok(x) => Ok(Some(x)),
+            Err(e) if self.skip_malformed && e.is_malformed() => {
+                self.skipped.push(e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The records of `iter`, skipping malformed records and ending at the first other error,
+    /// which is then returned by [`SkipMalformed::error`]
+    pub fn filter<'a, T: 'a>(
+        &'a mut self,
+        iter: impl IntoIterator<Item = Result<T, OxidMafError>> + 'a,
+    ) -> impl Iterator<Item = T> + 'a {
+        iter.into_iter()
+            .map_while(move |x| match self.check(x) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    self.error = Some(e);
+                    None
+                }
+            })
+            .flatten()
+    }
+
+    /// The error that ended the last iterator from [`SkipMalformed::filter`], if any
+    pub fn error(&mut self) -> Result<(), OxidMafError> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::MafReader;
+
+    // A block with a malformed s line, on line 5, between two good ones
+    const MAF: &str = "a score=1\ns hg38.chr1 0 2 + 100 AC\n\n\
+                       a score=2\ns hg38.chr1 2 x + 100 GT\n\n\
+                       a score=3\ns hg38.chr1 4 2 + 100 AC\n";
+
+    fn blocks() -> MafReader<&'static [u8]> {
+        MafReader::new(MAF.as_bytes()).with_file_name("skip.maf")
+    }
+
+    #[test]
+    fn skip_malformed() {
+        let mut skip = SkipMalformed::new(true);
+        let scores: Vec<String> = skip.filter(blocks()).map(|x| x[0].to_string()).collect();
+        assert_eq!(scores, vec!["a score=1", "a score=3"]);
+        assert!(skip.error().is_ok());
+        assert_eq!(skip.skipped.len(), 1);
+        assert!(skip.skipped[0]
+            .to_string()
+            .starts_with("skip.maf:5: Invalid size 'x'"));
+
+        // I/O errors are never skipped
+        let io = OxidMafError::io("skip.maf", IoError::other("disk"));
+        assert!(skip.check::<()>(Err(io)).is_err());
+        assert_eq!(skip.skipped.len(), 1);
+    }
+
+    #[test]
+    fn stop_at_malformed() {
+        let mut skip = SkipMalformed::new(false);
+        let scores: Vec<String> = skip.filter(blocks()).map(|x| x[0].to_string()).collect();
+        assert_eq!(scores, vec!["a score=1"]);
+        assert!(skip.skipped.is_empty());
+        match skip.error() {
+            Err(OxidMafError::Malformed { file, line, .. }) => {
+                assert_eq!((file.as_str(), line), ("skip.maf", 5))
+            }
+            _ => panic!("Expected a malformed error"),
+        }
+    }
+}
@@ -1,7 +1,26 @@
 mod annotate_ancestral_allele;
+mod conservation;
+mod convert;
 mod extract;
+mod gerp;
+mod index;
 mod remove_ref_indels;
+mod split;
+mod stats;
 
-pub use annotate_ancestral_allele::annotate_ancestral_allele;
-pub use extract::extract_snps;
-pub use remove_ref_indels::remove_ref_indels;
+pub use annotate_ancestral_allele::{annotate_ancestral_allele, AncestralMode, AncestralSummary};
+pub use conservation::{
+    annotate_conservation, Conservation, ConservationLookup, ConservationSummary,
+};
+pub use convert::{maf_to_taf, taf_to_maf, ConvertSummary};
+pub use extract::{
+    extract_interval, extract_snps, parse_region, read_bed, slice_block, ExtractSnpsSummary,
+    IntervalBlocks,
+};
+pub use gerp::{contig_lengths, process_gerp, GerpSite, GerpSites, GerpSummary};
+pub use index::{index, index_taf};
+pub use remove_ref_indels::{remove_ref_indels, RemoveRefIndelsSummary};
+pub use split::{split, SplitSummary};
+pub use stats::{
+    block_stats, duplicate_reference_rows, has_duplicate_reference, reference_gaps, BlockStats,
+};
@@ -1,144 +1,921 @@
+//! Annotate VCF records with their ancestral allele, from reconstructed ancestors in a MAF or TAF
+//! file
+
 use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{BufReader, Seek, SeekFrom, Write};
-
-// This function now expects that a corresponding TAF index (.tai) file exists.
-// It also assumes that the TAF file is uncompressed (or that seeking is supported).
-pub fn annotate_ancestral_allele(taf: &String, vcf: &String, ancestors: &String, output: &String) {
-    // Assert ancestors are a list of numbers, separated by commas.
-    assert!(
-        ancestors.chars().all(|x| x.is_numeric() || x == ','),
-        "Ancestors must be a list of numbers separated by commas"
-    );
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
 
-    let mut out_aa = std::fs::File::create(format!("{}.tsv", output)).unwrap();
-    let mut out_aa = std::io::BufWriter::new(out_aa);
-    // Write header
-    out_aa.write_all(b"#CHROM\tPOS\tAA\n").unwrap();
+/// Why a site has no ancestral allele, written to `<output>_remove.tsv`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RemoveReason {
+    /// The position is not in the alignment (or its contig is not in the index)
+    NoAlignment,
+    /// None of the ancestors has a base at the position
+    NoAncestor,
+    /// The ancestors' sequences are none of REF and the ALTs
+    NotRefOrAlt,
+    /// The ancestors disagree (strict), or alleles are tied (majority and parsimony)
+    Ambiguous,
+    /// An allele is empty, symbolic (e.g. `<DEL>`), a breakend or `*`
+    Unsupported,
+}
 
-    let mut out_remove = std::fs::File::create(format!("{}_remove.tsv", output)).unwrap();
-    let mut out_remove = std::io::BufWriter::new(out_remove);
+impl RemoveReason {
+    fn code(&self) -> &'static str {
+        match self {
+            RemoveReason::NoAlignment => "NO_ALIGNMENT",
+            RemoveReason::NoAncestor => "NO_ANCESTOR",
+            RemoveReason::NotRefOrAlt => "NOT_REF_OR_ALT",
+            RemoveReason::Ambiguous => "AMBIGUOUS",
+            RemoveReason::Unsupported => "UNSUPPORTED",
+        }
+    }
+}
 
-    let ancestors: Vec<usize> = ancestors.split(',').map(|x| x.parse().unwrap()).collect();
+/// How the ancestral allele is inferred from the bases of the ancestors and outgroups
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AncestralMode {
+    /// The first ancestor (then outgroup) with a sequence matching REF or an ALT
+    First,
+    /// Every ancestor and outgroup with a sequence must agree
+    Strict,
+    /// The allele carried by the most ancestors and outgroups
+    Majority,
+    /// The allele needing the fewest changes, where a change on an outgroup counts
+    /// `OUTGROUP_WEIGHT` times as much as one on an ancestor
+    Parsimony,
+}
+
+impl std::str::FromStr for AncestralMode {
+    type Err = String;
 
-    let mut vcf_reader = VcfParser::from_file(vcf); // Assume you have a VCF parser.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(AncestralMode::First),
+            "strict" => Ok(AncestralMode::Strict),
+            "majority" => Ok(AncestralMode::Majority),
+            "parsimony" => Ok(AncestralMode::Parsimony),
+            _ => Err(format!(
+                "Unknown mode '{}', expected first, strict, majority or parsimony",
+                s
+            )),
+        }
+    }
+}
+
+const OUTGROUP_WEIGHT: usize = 2;
+
+// An inferred ancestral allele
+struct AncestralCall {
+    allele: String,
+    // Index in the alleles of the record, 0 for REF
+    index: usize,
+    // Supported by at least two ancestors or outgroups, and contradicted by none
+    high_confidence: bool,
+    // Every ancestor and outgroup with a sequence agreed
+    unanimous: bool,
+}
+
+impl AncestralCall {
+    // Uppercase for high confidence, lowercase for low, as in the Ensembl and 1000 Genomes AA
+    fn aa(&self) -> String {
+        if self.high_confidence {
+            self.allele.clone()
+        } else {
+            self.allele.to_ascii_lowercase()
+        }
+    }
+}
+
+/// Counts of the records annotated by [`annotate_ancestral_allele`]
+#[derive(Debug, Default)]
+pub struct AncestralSummary {
+    /// Records where REF is ancestral
+    pub ancestral_matches: u64,
+    /// Records where an ALT is ancestral
+    pub alt_ancestral: u64,
+    /// Records with no ancestral allele, other than unsupported ones
+    pub ancestral_missing: u64,
+    /// Calls where the ancestors and outgroups disagreed
+    pub ancestral_by_majority: u64,
+    pub high_confidence: u64,
+    /// Records with symbolic alleles, breakends, `*` or an empty allele
+    pub unsupported: u64,
+    /// Malformed records and alignment blocks skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
 
-    // Load the TAF index from the .tai file (assumed to be at taf+".tai")
-    let taf_index_path = format!("{}.tai", taf);
+/// Find the ancestral allele of each VCF record from the bases of the `Anc<n>` genomes listed in
+/// `ancestors` (e.g. `0,1,2`, closest first) and the `outgroups` species, as set by `mode`.
+///
+/// Each genome's sequence is read from the alignment columns holding the REF allele, and, when an
+/// ALT is longer than REF, the columns following them where the reference has a gap. The
+/// ancestral allele is REF or the ALT that sequence is equal to, so multi-allelic sites, indels
+/// and MNPs are all polarised. Records with symbolic alleles, breakends, `*` or an empty REF are
+/// reported as unsupported.
+///
+/// Calls supported by at least two ancestors or outgroups and contradicted by none are high
+/// confidence, and written in uppercase; other calls are written in lowercase.
+///
+/// Writes `<output>.tsv` (CHROM, POS and AA) and `<output>_remove.tsv` (CHROM, POS and the
+/// reason there is no ancestral allele). With `write_vcf`, also writes `<output>.vcf`, the input
+/// with `AA` set on every record (`.` when unknown). With `polarize`, REF and ALT are swapped where
+/// ALT is ancestral, so that REF is always the ancestral allele, GTs are recoded to match and the
+/// `FLIPPED` flag is set. INFO and FORMAT fields defined with `Number=R` or `Number=G` (e.g. AD or
+/// PL) are reordered to match; those with `Number=A` (e.g. AF, AC or DS) have no value for REF and
+/// are dropped from flipped records (INFO) or set to `.` (FORMAT). Fields not defined in the
+/// header are left unchanged.
+///
+/// The alignment, MAF or TAF, must be plain text or bgzipped and indexed (`<alignment>.mafi` or
+/// `<alignment>.tai`), with the VCF's species as the reference. Blocks where the reference is on
+/// the minus strand are not used. The VCF must be sorted by position within each contig.
+#[allow(clippy::too_many_arguments)]
+pub fn annotate_ancestral_allele(
+    alignment: &str,
+    vcf: &str,
+    ancestors: &str,
+    outgroups: &[String],
+    mode: AncestralMode,
+    output: &str,
+    write_vcf: bool,
+    polarize: bool,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<AncestralSummary, OxidMafError> {
+    // Ancestors are a list of numbers, separated by commas
+    let ancestors: Vec<String> = ancestors
+        .split(',')
+        .map(|x| match x.parse::<usize>() {
+            Ok(x) => Ok(format!("Anc{}", x)),
+            Err(_) => Err(OxidMafError::invalid(
+                ancestors,
+                "Ancestors must be a list of numbers separated by commas",
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+
+    let aa_path = format!("{}.tsv", output);
+    let aa_error = |e| OxidMafError::io(&aa_path, e);
+    let out_aa = File::create(&aa_path).map_err(aa_error)?;
+    let mut out_aa = BufWriter::new(out_aa);
+    out_aa.write_all(b"#CHROM\tPOS\tAA\n").map_err(aa_error)?;
+
+    let remove_path = format!("{}_remove.tsv", output);
+    let remove_error = |e| OxidMafError::io(&remove_path, e);
+    let out_remove = File::create(&remove_path).map_err(remove_error)?;
+    let mut out_remove = BufWriter::new(out_remove);
+    out_remove
+        .write_all(b"#CHROM\tPOS\tREASON\n")
+        .map_err(remove_error)?;
+
+    let mut vcf_reader = VcfParser::from_file(vcf)?;
+    let vcf_header = vcf_reader.header().clone();
+    let vcf_path = format!("{}.vcf", output);
+    let vcf_error = |e| OxidMafError::io(&vcf_path, e);
+
+    let mut out_vcf = if write_vcf {
+        let mut header = vcf_header.clone();
+        header.add_info(VcfFieldDefinition {
+            id: "AA".to_string(),
+            number: "1".to_string(),
+            ty: VcfType::String,
+            description: "Ancestral allele, lowercase for low confidence calls".to_string(),
+            extra: Vec::new(),
+        });
+        if polarize {
+            header.add_info(VcfFieldDefinition {
+                id: "FLIPPED".to_string(),
+                number: "0".to_string(),
+                ty: VcfType::Flag,
+                description: "REF and ALT were swapped so that REF is the ancestral allele"
+                    .to_string(),
+                extra: Vec::new(),
+            });
+        }
+
+        let out_vcf = File::create(&vcf_path).map_err(vcf_error)?;
+        let mut out_vcf = VcfWriter::new(out_vcf);
+        out_vcf.write_header(&header).map_err(vcf_error)?;
+        Some(out_vcf)
+    } else {
+        None
+    };
+
+    let mut source = open_alignment(alignment, genome_names)?;
+    let mut skip = SkipMalformed::new(skip_malformed);
+    let mut skip_columns = SkipMalformed::new(skip_malformed);
 
     // Keep track of the current chromosome and alignment iterator.
     let mut current_chrom = String::new();
     // We use an Option so we can reinitialize when the chromosome changes.
-    let mut align_iter: Option<TafAlignmentIterator> = None;
-    // Cache the current alignment column.
-    let mut column: Option<TafAlignmentColumn> = None;
-
-    let mut taffy = TafParser::from_file(taf.as_str()).unwrap();
-    let taf_index = TaiIndex::from_file(&format!("{}.tai", taf)).unwrap();
+    let mut window: Option<ColumnWindow> = None;
 
-    // Statistics counters.
-    let mut ancestral_matches = 0;
-    let mut ancestral_not_found = 0;
-    let mut ancestral_missing = 0;
-    let mut ancestral_by_majority = 0;
+    let mut summary = AncestralSummary::default();
 
     for record in vcf_reader.records() {
-        // When the record's chromosome changes, seek to that contig.
+        let mut record = match skip.check(record)? {
+            Some(record) => record,
+            None => continue,
+        };
+
+        // When the record's chromosome changes, start again on that contig.
         if current_chrom != record.chrom {
-            println!("Switching to contig {} from {}", record.chrom, current_chrom);
             current_chrom = record.chrom.clone();
-            // Use the TAF index to get an iterator for this contig.
-            let seek_info = taf_index.get_seek_info(&current_chrom, 1).unwrap();
-            drop(align_iter);
-            taffy.seek_to(seek_info.0, seek_info.1);
-            align_iter = Some(TafAlignmentIterator::new(&mut taffy));
-            column = align_iter
-                .as_mut()
-                .and_then(|it| it.next().transpose().ok().flatten());
-        }
-
-        // If we don't have a current column, skip the record.
-        if column.is_none() {
-            continue;
-        }
-        let mut col = column.clone().unwrap();
-
-        // Advance alignment columns until the reference coordinate (row 0) matches the VCF position.
-        while !col.ref_matches_pos(0, record.pos) {
-            if let Some(next_result) = align_iter.as_mut().unwrap().next() {
-                match next_result {
-                    Ok(next_col) => {
-                        // If the new column isn’t for the current chromosome,
-                        // then we've reached the end of this contig's block.
-                        if next_col.ref_coord(&record.chrom).is_none() {
-                            col = next_col;
-                            break;
-                        }
-                        col = next_col;
-                    }
-                    Err(e) => {
-                        eprintln!("Error reading alignment column: {}", e);
-                        break;
-                    }
+            drop(window);
+            window = None;
+        }
+
+        // Use the index to seek to the record. Records before the first indexed line of the
+        // contig are not aligned, so try again with the next.
+        let pos = record.pos.saturating_sub(1);
+        let seeked = window.is_none();
+        if seeked {
+            drop(window);
+            window = source
+                .seek(&current_chrom, pos)?
+                .then(|| ColumnWindow::new(source.as_mut(), &mut skip_columns));
+        }
+
+        // Advance alignment columns until the reference reaches the record. Once they have
+        // moved on to another contig, the record may still be in a later block of this one, so
+        // seek again.
+        let mut found = window.as_mut().map(|x| x.advance_to(&record.chrom, pos));
+        if found == Some(ContainsResult::WrongChrom) && !seeked {
+            drop(window);
+            window = source
+                .seek(&current_chrom, pos)?
+                .then(|| ColumnWindow::new(source.as_mut(), &mut skip_columns));
+            found = window.as_mut().map(|x| x.advance_to(&record.chrom, pos));
+        }
+
+        let aligned = window
+            .as_mut()
+            .filter(|_| found == Some(ContainsResult::True));
+        let call = ancestral_call(aligned, &record, &ancestors, outgroups, mode);
+        if let Some(e) = window.as_mut().and_then(|x| x.error.take()) {
+            return Err(e);
+        }
+
+        match call {
+            Ok(call) => {
+                if call.index == 0 {
+                    summary.ancestral_matches += 1;
+                } else {
+                    summary.alt_ancestral += 1;
+                }
+                if !call.unanimous {
+                    summary.ancestral_by_majority += 1;
                 }
-            } else {
-                // End of alignment columns for this contig.
-                col = col;
-                break;
+                if call.high_confidence {
+                    summary.high_confidence += 1;
+                }
+
+                writeln!(out_aa, "{}\t{}\t{}", record.chrom, record.pos, call.aa())
+                    .map_err(aa_error)?;
+
+                record.set_info("AA", &call.aa());
+                if polarize && call.index > 0 {
+                    flip(&mut record, call.index, &vcf_header);
+                }
+            }
+            Err(reason) => {
+                if reason == RemoveReason::Unsupported {
+                    summary.unsupported += 1;
+                } else {
+                    summary.ancestral_missing += 1;
+                }
+                writeln!(
+                    out_remove,
+                    "{}\t{}\t{}",
+                    record.chrom,
+                    record.pos,
+                    reason.code()
+                )
+                .map_err(remove_error)?;
+
+                record.set_info("AA", ".");
             }
         }
 
-        // If the alignment column still does not match the SNP position, skip this record.
-        if !col.ref_matches_pos(0, record.pos) {
-            continue;
+        if let Some(out_vcf) = out_vcf.as_mut() {
+            out_vcf.write_record(&record).map_err(vcf_error)?;
+        }
+    }
+
+    out_aa.flush().map_err(aa_error)?;
+    out_remove.flush().map_err(remove_error)?;
+    if let Some(mut out_vcf) = out_vcf {
+        out_vcf.flush().map_err(vcf_error)?;
+    }
+
+    drop(window);
+    summary.skipped = skip.skipped;
+    summary.skipped.append(&mut skip_columns.skipped);
+    Ok(summary)
+}
+
+// Alignment columns from the one at or before the current record, read ahead as far as the
+// alleles of the record need
+struct ColumnWindow<'a> {
+    source: &'a mut dyn AlignmentSource,
+    skip: &'a mut SkipMalformed,
+    columns: VecDeque<AlignmentColumn>,
+    // The species of the first row read after seeking, which the index is of
+    reference: Option<Arc<str>>,
+    // The error that ended the columns, returned once the current record is done
+    error: Option<OxidMafError>,
+}
+
+impl<'a> ColumnWindow<'a> {
+    fn new(source: &'a mut dyn AlignmentSource, skip: &'a mut SkipMalformed) -> Self {
+        ColumnWindow {
+            source,
+            skip,
+            columns: VecDeque::new(),
+            reference: None,
+            error: None,
         }
+    }
 
-        // Try to extract an ancestral allele from one of the specified ancestors.
-        let mut ancestral_allele = None;
-        for anc in ancestors.iter() {
-            let species = format!("Anc{}", anc);
-            if let Some((_, allele)) = col.allele_for_species(&species) {
-                let allele = allele.to_ascii_uppercase();
-                if allele != '-' && allele != 'N' && allele != '*' {
-                    if allele == record.ref_.chars().nth(0).unwrap()
-                        || allele == record.alt.chars().nth(0).unwrap()
-                    {
-                        ancestral_allele = Some(allele);
-                        break;
+    fn next_column(&mut self) -> Option<AlignmentColumn> {
+        while self.error.is_none() {
+            match self.skip.check(self.source.next_column()?) {
+                Ok(Some(column)) => {
+                    if self.reference.is_none() {
+                        let first = column.rows.first().and_then(|x| x.as_ref());
+                        self.reference = first.map(|x| x.species.clone());
                     }
+                    return Some(column);
+                }
+                Ok(None) => continue,
+                Err(e) => self.error = Some(e),
+            }
+        }
+        None
+    }
+
+    // The column `i` columns after the current one
+    fn get(&mut self, i: usize) -> Option<&AlignmentColumn> {
+        while self.columns.len() <= i {
+            let column = self.next_column()?;
+            self.columns.push_back(column);
+        }
+        self.columns.get(i)
+    }
+
+    fn advance(&mut self) {
+        self.columns.pop_front();
+    }
+
+    // The reference row of `column` on `chrom`, if it is on the forward strand
+    fn reference_row(&self, column: &AlignmentColumn, chrom: &str) -> Option<usize> {
+        let row = column.row_on(self.reference.as_deref()?, chrom)?;
+        match column.rows[row].as_ref()?.strand {
+            Strand::Plus => Some(row),
+            Strand::Minus => None,
+        }
+    }
+
+    // Advance to the column of the reference's base at `pos` on `chrom` (True), or the first
+    // column past it (Before). Columns without a reference row on `chrom` are skipped, as are
+    // those where it is on the minus strand, which are not supported. Stops with WrongChrom at
+    // the first column where the reference is on another contig, or when there are no more.
+    fn advance_to(&mut self, chrom: &str, pos: u64) -> ContainsResult {
+        while self.get(0).is_some() {
+            let column = &self.columns[0];
+            let reference = self.reference.as_deref().unwrap_or_default();
+            let result = match self.reference_row(column, chrom) {
+                Some(_) => column.contains_pos(reference, chrom, pos),
+                None if column.row_on(reference, chrom).is_none()
+                    && column.allele_for_species(reference).is_some() =>
+                {
+                    ContainsResult::WrongChrom
                 }
+                None => ContainsResult::After,
+            };
+            if result != ContainsResult::After {
+                return result;
             }
+            self.advance();
         }
+        ContainsResult::WrongChrom
+    }
+
+    // Number of columns, from the current one, holding the `len` bases of the reference row on
+    // `chrom` from `pos`, and with `insertion`, the following columns where that row has a gap.
+    // None if the bases are not all in consecutive columns.
+    fn span(&mut self, chrom: &str, pos: u64, len: usize, insertion: bool) -> Option<usize> {
+        let mut bases = 0;
+        let mut i = 0;
+        while self.get(i).is_some() {
+            let column = &self.columns[i];
+            let row = match self.reference_row(column, chrom) {
+                Some(row) => row,
+                None => break,
+            };
 
-        if ancestral_allele.is_none() {
-            ancestral_missing += 1;
-            continue;
+            match column.position(row) {
+                Some(_) if bases == len => break,
+                Some(x) if x == pos + bases as u64 => bases += 1,
+                Some(_) => return None,
+                None if bases == len && !insertion => break,
+                None => {}
+            }
+            i += 1;
         }
 
-        let ancestral_allele = ancestral_allele.unwrap();
-        if ancestral_allele == record.ref_.chars().nth(0).unwrap() {
-            ancestral_matches += 1;
+        if bases == len {
+            Some(i)
         } else {
-            ancestral_not_found += 1;
+            None
+        }
+    }
+
+    // The ungapped sequence of `species` in the first `n` columns, if it has a row in all of them
+    fn sequence(&self, species: &str, n: usize) -> Option<String> {
+        let mut sequence = String::new();
+        for column in self.columns.range(..n) {
+            let (_, base) = column.allele_for_species(species)?;
+            if base != b'-' {
+                sequence.push(base.to_ascii_uppercase() as char);
+            }
         }
+        Some(sequence)
+    }
+}
 
-        // Write the annotation to output.
-        out_aa
-            .write_all(
-                format!("{}\t{}\t{}\n", record.chrom, record.pos, ancestral_allele).as_bytes(),
-            )
+fn ancestral_call(
+    window: Option<&mut ColumnWindow>,
+    record: &Record,
+    ancestors: &[String],
+    outgroups: &[String],
+    mode: AncestralMode,
+) -> Result<AncestralCall, RemoveReason> {
+    let alleles: Vec<String> = record.alleles().map(|x| x.to_ascii_uppercase()).collect();
+    if alleles
+        .iter()
+        .any(|x| x.is_empty() || !x.chars().all(|x| matches!(x, 'A' | 'C' | 'G' | 'T' | 'N')))
+    {
+        return Err(RemoveReason::Unsupported);
+    }
+
+    let window = match window {
+        Some(window) => window,
+        None => return Err(RemoveReason::NoAlignment),
+    };
+    let pos = record.pos.saturating_sub(1);
+    let ref_len = alleles[0].len();
+    let insertion = alleles.iter().any(|x| x.len() > ref_len);
+    let n = window
+        .span(&record.chrom, pos, ref_len, insertion)
+        .ok_or(RemoveReason::NoAlignment)?;
+
+    // Sequences of the ancestors, then outgroups, skipping those without one
+    let mut sequences: Vec<(String, bool)> = Vec::new();
+    for (species, outgroup) in ancestors
+        .iter()
+        .map(|x| (x, false))
+        .chain(outgroups.iter().map(|x| (x, true)))
+    {
+        if let Some(sequence) = window.sequence(species, n) {
+            if !sequence.is_empty() && sequence.chars().all(|x| matches!(x, 'A' | 'C' | 'G' | 'T'))
+            {
+                sequences.push((sequence, outgroup));
+            }
+        }
+    }
+
+    if sequences.is_empty() {
+        return Err(RemoveReason::NoAncestor);
+    }
+    let index_of = |sequence: &str| alleles.iter().position(|x| x == sequence);
+    if !sequences.iter().any(|(x, _)| index_of(x).is_some()) {
+        return Err(RemoveReason::NotRefOrAlt);
+    }
+
+    let allele = match mode {
+        AncestralMode::First => sequences
+            .iter()
+            .map(|(x, _)| x)
+            .find(|x| index_of(x).is_some())
+            .unwrap()
+            .clone(),
+        AncestralMode::Strict => {
+            let first = &sequences[0].0;
+            if sequences.iter().any(|(x, _)| x != first) || index_of(first).is_none() {
+                return Err(RemoveReason::Ambiguous);
+            }
+            first.clone()
+        }
+        AncestralMode::Majority | AncestralMode::Parsimony => {
+            let weight = |outgroup: bool| match mode {
+                AncestralMode::Parsimony if outgroup => OUTGROUP_WEIGHT,
+                _ => 1,
+            };
+
+            // Fewest changes is the same as most (weighted) support
+            let mut scores: Vec<(&String, usize)> = Vec::new();
+            for (sequence, outgroup) in sequences.iter() {
+                if index_of(sequence).is_none() {
+                    continue;
+                }
+                match scores.iter_mut().find(|(x, _)| *x == sequence) {
+                    Some((_, score)) => *score += weight(*outgroup),
+                    None => scores.push((sequence, weight(*outgroup))),
+                }
+            }
+            scores.sort_by_key(|x| std::cmp::Reverse(x.1));
+            if scores.len() > 1 && scores[0].1 == scores[1].1 {
+                return Err(RemoveReason::Ambiguous);
+            }
+            scores[0].0.clone()
+        }
+    };
+
+    let support = sequences.iter().filter(|(x, _)| *x == allele).count();
+    let unanimous = support == sequences.len();
+
+    Ok(AncestralCall {
+        index: index_of(&allele).unwrap(),
+        allele,
+        high_confidence: unanimous && support >= 2,
+        unanimous,
+    })
+}
+
+// Swap REF with the ALT allele at `index` (1 for the first ALT), recoding GTs and the allele
+// specific fields defined in `header` to match
+fn flip(record: &mut Record, index: usize, header: &VcfHeader) {
+    std::mem::swap(&mut record.ref_, &mut record.alt[index - 1]);
+    let n_alleles = record.alt.len() + 1;
+    let swap = |allele: usize| match allele {
+        0 => index,
+        x if x == index => 0,
+        x => x,
+    };
+
+    for sample in 0..record.samples.len() {
+        if let Some(Ok(mut genotype)) = record.genotype(sample) {
+            for allele in genotype.alleles.iter_mut().flatten() {
+                *allele = swap(*allele);
+            }
+            record.set_genotype(sample, &genotype);
+        }
+    }
+
+    record.info.retain(
+        |(key, _)| !matches!(header.info(key), Some(definition) if definition.number == "A"),
+    );
+    for (key, value) in record.info.iter_mut() {
+        if let Some(definition) = header.info(key) {
+            *value = recode(value, &definition.number, n_alleles, swap);
+        }
+    }
+
+    for (i, key) in record.format.iter().enumerate() {
+        let number = match header.format(key) {
+            Some(definition) if key != "GT" => &definition.number,
+            _ => continue,
+        };
+        for value in record.samples.iter_mut().filter_map(|x| x.get_mut(i)) {
+            *value = recode(value, number, n_alleles, swap);
+        }
+    }
+
+    record.set_info("FLIPPED", "");
+}
+
+// The values of a field with `number` values per allele (R), per genotype (G) or per ALT (A),
+// reordered by `swap`. Values of other fields are unchanged, and those that cannot be reordered
+// are missing.
+fn recode(value: &str, number: &str, n_alleles: usize, swap: impl Fn(usize) -> usize) -> String {
+    if value == "." || !matches!(number, "R" | "G" | "A") {
+        return value.to_string();
+    }
+    let values: Vec<&str> = value.split(',').collect();
+    let mut recoded = values.clone();
+
+    match number {
+        // Haploid genotypes are ordered as the alleles
+        "R" | "G" if values.len() == n_alleles => {
+            for (allele, value) in values.iter().enumerate() {
+                recoded[swap(allele)] = value;
+            }
+        }
+        // Diploid genotypes j/k (j <= k) are at k * (k + 1) / 2 + j
+        "G" if values.len() == n_alleles * (n_alleles + 1) / 2 => {
+            let genotype = |j: usize, k: usize| k * (k + 1) / 2 + j;
+            for k in 0..n_alleles {
+                for j in 0..=k {
+                    let (a, b) = (swap(j), swap(k));
+                    recoded[genotype(a.min(b), a.max(b))] = values[genotype(j, k)];
+                }
+            }
+        }
+        _ => return ".".to_string(),
+    }
+    recoded.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/ancestral.maf");
+    const VCF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/ancestral.vcf");
+    const INDELS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/indels.maf");
+
+    struct Annotated {
+        summary: AncestralSummary,
+        // POS and AA of each annotated record
+        aa: Vec<(u64, String)>,
+        // POS and REASON of each record without an ancestral allele
+        removed: Vec<(u64, String)>,
+        records: Vec<Record>,
+    }
+
+    // Annotate the fixture VCF with ancestors Anc0 and Anc1 and outgroups panTro6 and mm10
+    fn annotate(name: &str, mode: AncestralMode, polarize: bool) -> Annotated {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let maf = dir.join("ancestral.maf");
+        std::fs::copy(MAF, &maf).unwrap();
+        let maf = maf.to_str().unwrap();
+        crate::functions::index(maf, &[]).unwrap();
+        let output = dir.join("annotated");
+        let output = output.to_str().unwrap();
+
+        let outgroups = ["panTro6".to_string(), "mm10".to_string()];
+        let summary = annotate_ancestral_allele(
+            maf,
+            VCF,
+            "0,1",
+            &outgroups,
+            mode,
+            output,
+            true,
+            polarize,
+            &[],
+            false,
+        )
+        .unwrap();
+
+        let read_tsv = |path: String| -> Vec<(u64, String)> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|x| {
+                    let fields: Vec<&str> = x.split('\t').collect();
+                    (fields[1].parse().unwrap(), fields[2].to_string())
+                })
+                .collect()
+        };
+        let aa = read_tsv(format!("{}.tsv", output));
+        let removed = read_tsv(format!("{}_remove.tsv", output));
+        let records = VcfParser::from_file(&format!("{}.vcf", output))
+            .unwrap()
+            .records()
+            .collect::<Result<_, _>>()
             .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        Annotated {
+            summary,
+            aa,
+            removed,
+            records,
+        }
+    }
+
+    fn pairs(x: &[(u64, &str)]) -> Vec<(u64, String)> {
+        x.iter().map(|(pos, x)| (*pos, x.to_string())).collect()
+    }
+
+    #[test]
+    fn polarize() {
+        let annotated = annotate("polarize", AncestralMode::First, true);
+        let summary = &annotated.summary;
+        assert_eq!((summary.ancestral_matches, summary.alt_ancestral), (4, 2));
+        assert_eq!((summary.ancestral_missing, summary.unsupported), (2, 1));
+        assert_eq!(
+            (summary.ancestral_by_majority, summary.high_confidence),
+            (2, 3)
+        );
+        assert_eq!(
+            annotated.aa,
+            pairs(&[(1, "A"), (3, "T"), (4, "t"), (5, "a"), (6, "c"), (7, "T")])
+        );
+        assert_eq!(
+            annotated.removed,
+            pairs(&[
+                (8, "NOT_REF_OR_ALT"),
+                (9, "UNSUPPORTED"),
+                (20, "NO_ALIGNMENT")
+            ])
+        );
+
+        let records = &annotated.records;
+        assert_eq!(records[0].alleles().collect::<Vec<_>>(), vec!["A", "C"]);
+        assert_eq!(records[0].info("FLIPPED"), None);
+
+        // Number=A fields are dropped, Number=R and Number=G fields reordered
+        let flipped = &records[1];
+        assert_eq!(
+            (flipped.ref_.as_str(), flipped.alt.join(",")),
+            ("T", "G".to_string())
+        );
+        assert_eq!(flipped.info, {
+            let info = [("DP", "10"), ("AA", "T"), ("FLIPPED", "")];
+            info.map(|(k, v)| (k.to_string(), v.to_string())).to_vec()
+        });
+        assert_eq!(flipped.samples[0], vec!["1/0", "3,4", "40,0,30", "."]);
+        assert_eq!(flipped.samples[1], vec!["0|0", "6,0", "0,30,90", "."]);
+
+        // The second ALT is ancestral, the first ALT keeps its index
+        let multiallelic = &records[5];
+        assert_eq!(
+            multiallelic.alleles().collect::<Vec<_>>(),
+            vec!["T", "A", "G"]
+        );
+        assert_eq!(multiallelic.info("AF"), None);
+        assert_eq!(
+            multiallelic.samples[0],
+            vec!["2/0", "3,2,1", "50,40,20,30,10,0"]
+        );
+        assert_eq!(multiallelic.samples[1], vec!["1/0", "1,1,0", "."]);
 
-        // Cache the column for the next record.
-        column = Some(col.clone());
+        // Records without an ancestral allele are kept as they are
+        assert_eq!(records[6].alleles().collect::<Vec<_>>(), vec!["T", "C"]);
+        assert_eq!(records[6].info("AA"), Some("."));
     }
 
-    println!("Done");
-    println!("Ancestral matches: {}", ancestral_matches);
-    println!("Ancestral not found: {}", ancestral_not_found);
-    println!("Ancestral missing: {}", ancestral_missing);
-    println!("Ancestral by majority: {}", ancestral_by_majority);
-    let total = ancestral_matches + ancestral_not_found;
-    let fraction = ancestral_matches as f64 / total as f64;
-    println!("Fraction ancestral matches: {}", fraction);
+    #[test]
+    fn modes() {
+        // Every genome agrees at 1, 3 and 7 (the second ALT of a multi-allelic site), only Anc0
+        // has a base at 4, whatever the mode. At 5 the ancestors disagree, with panTro6 on Anc1's side; at 6 they
+        // agree, and both outgroups differ.
+        let every_mode = [(1, "A"), (3, "T"), (4, "t"), (7, "T")];
+        let annotated = |mode, name| {
+            let annotated = annotate(name, mode, false);
+            let aa: Vec<_> = annotated
+                .aa
+                .into_iter()
+                .filter(|x| !every_mode.contains(&(x.0, x.1.as_str())))
+                .collect();
+            let removed: Vec<_> = annotated.removed.into_iter().filter(|x| x.0 < 8).collect();
+            (aa, removed, annotated.summary)
+        };
+
+        let (aa, removed, summary) = annotated(AncestralMode::Strict, "strict");
+        assert_eq!(aa, pairs(&[]));
+        assert_eq!(removed, pairs(&[(5, "AMBIGUOUS"), (6, "AMBIGUOUS")]));
+        assert_eq!(
+            (summary.ancestral_by_majority, summary.high_confidence),
+            (0, 3)
+        );
+
+        let (aa, removed, summary) = annotated(AncestralMode::Majority, "majority");
+        assert_eq!(aa, pairs(&[(5, "c")]));
+        assert_eq!(removed, pairs(&[(6, "AMBIGUOUS")]));
+        assert_eq!(summary.alt_ancestral, 3);
+        assert_eq!(
+            (summary.ancestral_by_majority, summary.high_confidence),
+            (1, 3)
+        );
+
+        // Outgroups count double
+        let (aa, removed, summary) = annotated(AncestralMode::Parsimony, "parsimony");
+        assert_eq!(aa, pairs(&[(5, "c"), (6, "g")]));
+        assert_eq!(removed, pairs(&[]));
+        assert_eq!(summary.alt_ancestral, 4);
+        assert_eq!((summary.ancestral_missing, summary.unsupported), (2, 1));
+    }
+
+    // Columns and Anc0's sequence of the alleles at `pos` in indels.maf
+    fn span(pos: u64, len: usize, insertion: bool) -> Option<(usize, String)> {
+        let mut source = open_alignment(INDELS, &[]).unwrap();
+        let mut skip = SkipMalformed::new(false);
+        let mut window = ColumnWindow::new(source.as_mut(), &mut skip);
+        window.advance_to("chr1", pos);
+        let n = window.span("chr1", pos, len, insertion)?;
+        Some((n, window.sequence("Anc0", n).unwrap()))
+    }
+
+    #[test]
+    fn indel_spans() {
+        let some = |n: usize, x: &str| Some((n, x.to_string()));
+
+        // A SNP stops at the next column, an insertion takes the reference gaps after it
+        assert_eq!(span(2, 1, false), some(1, "G"));
+        assert_eq!(span(2, 1, true), some(3, "GTT"));
+
+        // The reference gap opening the next block
+        assert_eq!(span(4, 1, false), some(1, "A"));
+        assert_eq!(span(4, 1, true), some(2, "AA"));
+        assert_eq!(span(4, 2, false), some(3, "AAC"));
+
+        // The next block does not follow on, so a deletion cannot span them
+        assert_eq!(span(6, 1, true), some(1, "G"));
+        assert_eq!(span(6, 2, false), None);
+        assert_eq!(span(10, 2, false), some(2, "AC"));
+    }
+
+    #[test]
+    fn recode_fields() {
+        let swap = |x: usize| match x {
+            0 => 1,
+            1 => 0,
+            x => x,
+        };
+        assert_eq!(recode("1,2", "R", 2, swap), "2,1");
+        assert_eq!(recode("1,2", "G", 2, swap), "2,1");
+        assert_eq!(recode("1,2,3", "G", 2, swap), "3,2,1");
+        assert_eq!(recode("0.5", "A", 2, swap), ".");
+        assert_eq!(recode("1,2,3", "R", 2, swap), ".");
+        assert_eq!(recode(".", "R", 2, swap), ".");
+        assert_eq!(recode("1,2", "2", 2, swap), "1,2");
+    }
+
+    #[test]
+    fn two_contigs() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_aa_contigs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let maf = dir.join("contigs.maf");
+        let vcf = dir.join("contigs.vcf");
+        let output = dir.join("annotated");
+        let (maf, vcf, output) = (
+            maf.to_str().unwrap(),
+            vcf.to_str().unwrap(),
+            output.to_str().unwrap(),
+        );
+
+        // The chr2 block comes between those of chr1, and has an mm10 row on chr1. The third
+        // block has the reference on the minus strand, covering 87-90.
+        std::fs::write(
+            maf,
+            "##maf version=1\n\n\
+             a\ns hg38.chr1 0 4 + 100 ACGT\ns Anc0.anc1 0 4 + 100 ACGA\n\
+             s mm10.chr2 0 4 + 100 ACGG\n\n\
+             a\ns hg38.chr2 0 4 + 100 TTTT\ns Anc0.anc2 0 4 + 100 TTCT\n\
+             s mm10.chr1 0 4 + 100 GGGG\n\n\
+             a\ns hg38.chr1 10 4 - 100 ACGT\ns Anc0.anc1 10 4 + 100 ACGT\n\n\
+             a\ns hg38.chr1 90 2 + 100 GG\ns Anc0.anc1 20 2 + 100 GA\n",
+        )
+        .unwrap();
+        crate::functions::index(maf, &[]).unwrap();
+        std::fs::write(
+            vcf,
+            "##fileformat=VCFv4.2\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+             chr1\t4\t.\tT\tA\t.\t.\t.\n\
+             chr1\t88\t.\tC\tA\t.\t.\t.\n\
+             chr1\t92\t.\tG\tA\t.\t.\t.\n\
+             chr2\t3\t.\tT\tC\t.\t.\t.\n",
+        )
+        .unwrap();
+
+        let summary = annotate_ancestral_allele(
+            maf,
+            vcf,
+            "0",
+            &[],
+            AncestralMode::First,
+            output,
+            false,
+            false,
+            &[],
+            false,
+        )
+        .unwrap();
+        let aa = std::fs::read_to_string(format!("{}.tsv", output)).unwrap();
+        let removed = std::fs::read_to_string(format!("{}_remove.tsv", output)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // chr1 continues after the chr2 block, and the minus strand block is skipped
+        assert_eq!(aa, "#CHROM\tPOS\tAA\nchr1\t4\ta\nchr1\t92\ta\nchr2\t3\tc\n");
+        assert_eq!(removed, "#CHROM\tPOS\tREASON\nchr1\t88\tNO_ALIGNMENT\n");
+        assert_eq!((summary.ancestral_missing, summary.alt_ancestral), (1, 3));
+    }
+
+    #[test]
+    fn bad_ancestors() {
+        let e = annotate_ancestral_allele(
+            MAF,
+            VCF,
+            "0,x",
+            &[],
+            AncestralMode::First,
+            "unused",
+            false,
+            false,
+            &[],
+            false,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("Ancestors must be a list"), "{}", e);
+    }
 }
@@ -0,0 +1,416 @@
+//! Annotate VCF records with GERP++, phyloP and phastCons conservation scores
+
+use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
+
+use super::gerp::{check_rates_lines, contig_lengths};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+
+/// Conservation scores of a reference position
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Conservation {
+    /// GERP++ rejected substitutions score, `None` where the position is not aligned
+    pub gerp_rs: Option<f64>,
+    /// The GERP++ constrained element containing the position
+    pub gerp_element: Option<GerpElement>,
+    pub phylop: Option<f64>,
+    pub phastcons: Option<f64>,
+}
+
+/// Conservation scores of a set of 1-based reference positions, such as the sites of a VCF
+///
+/// Positions are added first, then each source fills in its scores in a single pass, whatever the
+/// order of its contigs.
+#[derive(Debug, Default)]
+pub struct ConservationLookup {
+    sites: HashMap<String, BTreeMap<u64, Conservation>>,
+}
+
+impl ConservationLookup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_site(&mut self, contig: &str, pos: u64) {
+        if let Some(sites) = self.sites.get_mut(contig) {
+            sites.entry(pos).or_default();
+        } else {
+            let mut sites = BTreeMap::new();
+            sites.insert(pos, Conservation::default());
+            self.sites.insert(contig.to_string(), sites);
+        }
+    }
+
+    pub fn get(&self, contig: &str, pos: u64) -> Option<&Conservation> {
+        self.sites.get(contig)?.get(&pos)
+    }
+
+    /// Set the RS score of the sites from the lines of a GERP++ rates file, whose contigs are
+    /// `contigs` (see [`GerpRatesReader::contigs`])
+    pub fn add_gerp_rates(
+        &mut self,
+        contigs: &[(String, u64)],
+        rates: impl IntoIterator<Item = GerpRate>,
+    ) {
+        // The sites of the contig of the last rate
+        let mut current: Option<(usize, Option<&mut BTreeMap<u64, Conservation>>)> = None;
+
+        for rate in rates {
+            // Unaligned positions have neither
+            if rate.neutral_rate == 0.0 && rate.rs_score == 0.0 {
+                continue;
+            }
+
+            if !matches!(current, Some((i, _)) if i == rate.contig) {
+                let sites = self.sites.get_mut(&contigs[rate.contig].0);
+                current = Some((rate.contig, sites));
+            }
+            if let Some((_, Some(sites))) = current.as_mut() {
+                if let Some(site) = sites.get_mut(&rate.pos) {
+                    site.gerp_rs = Some(rate.rs_score);
+                }
+            }
+        }
+    }
+
+    /// Set the constrained element containing each site
+    pub fn add_gerp_elements(&mut self, elements: &mut GerpElements) {
+        for (contig, sites) in self.sites.iter_mut() {
+            for (pos, site) in sites.iter_mut() {
+                site.gerp_element = elements.find(contig, *pos).cloned();
+            }
+        }
+    }
+
+    /// Set the phyloP score of the sites from a bedGraph or wig track
+    pub fn add_phylop(&mut self, intervals: impl IntoIterator<Item = TrackInterval>) {
+        self.add_track(intervals, |site, value| site.phylop = Some(value));
+    }
+
+    /// Set the phastCons score of the sites from a bedGraph or wig track
+    pub fn add_phastcons(&mut self, intervals: impl IntoIterator<Item = TrackInterval>) {
+        self.add_track(intervals, |site, value| site.phastcons = Some(value));
+    }
+
+    fn add_track(
+        &mut self,
+        intervals: impl IntoIterator<Item = TrackInterval>,
+        set: impl Fn(&mut Conservation, f64),
+    ) {
+        for interval in intervals {
+            if let Some(sites) = self.sites.get_mut(&interval.contig) {
+                // Intervals are 0-based and half-open, sites 1-based
+                for (_, site) in sites.range_mut(interval.start + 1..interval.end + 1) {
+                    set(site, interval.value);
+                }
+            }
+        }
+    }
+}
+
+/// Counts of the records annotated by [`annotate_conservation`]
+#[derive(Debug, Default)]
+pub struct ConservationSummary {
+    pub records: u64,
+    /// Records with a GERP++ RS score
+    pub gerp_rs: u64,
+    /// Records in a GERP++ constrained element
+    pub gerp_elements: u64,
+    pub phylop: u64,
+    pub phastcons: u64,
+    /// Malformed records skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
+
+/// Add conservation scores of the first base of each record of `vcf` to its INFO, writing
+/// `<output>.vcf`
+///
+/// - `GERP_RS`: GERP++ RS score, from `gerp_rates`. Rates files have no coordinates, so need the
+///   contig lengths from `lengths` (a `.fai` index or MAF file, see [`contig_lengths`]), or
+///   otherwise the `##contig` lines of the VCF, each of which must then have a length. The rates
+///   must be concatenated in the order of those contigs. Only the total number of lines of the
+///   rates can be checked (for lengths from a MAF file or the VCF), not the order.
+/// - `GERP_ELEM` and `GERP_ELEM_P`: RS score and p-value of the GERP++ constrained element, from
+///   `gerp_elems`
+/// - `PHYLOP` and `PHASTCONS`: from bedGraph or wig files
+///
+/// Fields are only set on records with a score. The VCF is read twice, so cannot be stdin.
+#[allow(clippy::too_many_arguments)]
+pub fn annotate_conservation(
+    vcf: &str,
+    output: &str,
+    gerp_rates: Option<&str>,
+    gerp_elems: Option<&str>,
+    lengths: Option<&str>,
+    phylop: Option<&str>,
+    phastcons: Option<&str>,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<ConservationSummary, OxidMafError> {
+    let mut vcf_reader = VcfParser::from_file(vcf)?;
+    let mut header = vcf_reader.header().clone();
+    let mut skip = SkipMalformed::new(skip_malformed);
+    let mut summary = ConservationSummary::default();
+
+    let mut lookup = ConservationLookup::new();
+    for record in vcf_reader.records() {
+        if let Some(record) = skip.check(record)? {
+            lookup.add_site(&record.chrom, record.pos);
+            summary.records += 1;
+        }
+    }
+
+    let mut info = |id: &str, description: &str| {
+        header.add_info(VcfFieldDefinition {
+            id: id.to_string(),
+            number: "1".to_string(),
+            ty: VcfType::Float,
+            description: description.to_string(),
+            extra: Vec::new(),
+        })
+    };
+
+    if let Some(rates) = gerp_rates {
+        let contigs = match lengths {
+            Some(lengths) => contig_lengths(lengths, rates, genome_names, skip_malformed)?,
+            None => {
+                let mut contigs = Vec::new();
+                for (id, length) in vcf_reader.header().contigs() {
+                    match length {
+                        Some(length) => contigs.push((id.to_string(), length)),
+                        None => {
+                            return Err(OxidMafError::invalid(
+                                vcf,
+                                format!(
+                                    "##contig {} has no length, which the GERP rates need. Give \
+                                     the lengths with --lengths",
+                                    id
+                                ),
+                            ))
+                        }
+                    }
+                }
+                if contigs.is_empty() {
+                    return Err(OxidMafError::invalid(
+                        vcf,
+                        "GERP rates need contig lengths, from --lengths or ##contig lines with a \
+                         length in the VCF",
+                    ));
+                }
+                check_rates_lines(rates, &contigs, vcf)?;
+                contigs
+            }
+        };
+
+        let rates = GerpRatesReader::from_file(rates, contigs)?;
+        let contigs = rates.contigs().to_vec();
+        lookup.add_gerp_rates(&contigs, skip.filter(rates));
+        skip.error()?;
+        info("GERP_RS", "GERP++ rejected substitutions score");
+    }
+
+    if let Some(elems) = gerp_elems {
+        let mut elements = GerpElements::new(skip.filter(GerpElementReader::from_file(elems)?));
+        skip.error()?;
+        lookup.add_gerp_elements(&mut elements);
+        info(
+            "GERP_ELEM",
+            "RS score of the GERP++ constrained element containing the site",
+        );
+        info(
+            "GERP_ELEM_P",
+            "p-value of the GERP++ constrained element containing the site",
+        );
+    }
+
+    if let Some(phylop) = phylop {
+        lookup.add_phylop(skip.filter(TrackReader::from_file(phylop)?));
+        skip.error()?;
+        info("PHYLOP", "phyloP conservation score");
+    }
+
+    if let Some(phastcons) = phastcons {
+        lookup.add_phastcons(skip.filter(TrackReader::from_file(phastcons)?));
+        skip.error()?;
+        info("PHASTCONS", "phastCons conservation score");
+    }
+
+    let vcf_path = format!("{}.vcf", output);
+    let vcf_error = |e| OxidMafError::io(&vcf_path, e);
+    let out_vcf = File::create(&vcf_path).map_err(vcf_error)?;
+    let mut out_vcf = VcfWriter::new(out_vcf);
+    out_vcf.write_header(&header).map_err(vcf_error)?;
+
+    // Malformed records were reported on the first pass
+    let mut skip_again = SkipMalformed::new(skip_malformed);
+    let mut vcf_reader = VcfParser::from_file(vcf)?;
+    for record in vcf_reader.records() {
+        let mut record = match skip_again.check(record)? {
+            Some(record) => record,
+            None => continue,
+        };
+
+        if let Some(site) = lookup.get(&record.chrom, record.pos) {
+            if let Some(rs) = site.gerp_rs {
+                record.set_info("GERP_RS", &rs.to_string());
+                summary.gerp_rs += 1;
+            }
+            if let Some(e) = &site.gerp_element {
+                record.set_info("GERP_ELEM", &e.rs_score.to_string());
+                record.set_info("GERP_ELEM_P", &e.p_value.to_string());
+                summary.gerp_elements += 1;
+            }
+            if let Some(phylop) = site.phylop {
+                record.set_info("PHYLOP", &phylop.to_string());
+                summary.phylop += 1;
+            }
+            if let Some(phastcons) = site.phastcons {
+                record.set_info("PHASTCONS", &phastcons.to_string());
+                summary.phastcons += 1;
+            }
+        }
+
+        out_vcf.write_record(&record).map_err(vcf_error)?;
+    }
+    out_vcf.flush().map_err(vcf_error)?;
+
+    summary.skipped = skip.skipped;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VCF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/conservation.vcf");
+    const SMALL_VCF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/small.vcf");
+    const RATES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.rates");
+    const ELEMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.elems");
+    const FAI: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.fai");
+    const WIG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/conservation.wig");
+
+    #[test]
+    fn lookup() {
+        let mut lookup = ConservationLookup::new();
+        for (contig, pos) in [
+            ("chr1", 1),
+            ("chr1", 2),
+            ("chr1", 3),
+            ("chr2", 4),
+            ("chr3", 1),
+        ] {
+            lookup.add_site(contig, pos);
+        }
+
+        let rates = GerpRatesReader::from_file(RATES, read_fai(FAI).unwrap()).unwrap();
+        let contigs = rates.contigs().to_vec();
+        lookup.add_gerp_rates(&contigs, rates.map(|x| x.unwrap()));
+        lookup.add_phylop(TrackReader::from_file(WIG).unwrap().map(|x| x.unwrap()));
+
+        // Unaligned, with a rate and score of 0
+        assert_eq!(lookup.get("chr1", 1).unwrap().gerp_rs, None);
+        assert_eq!(lookup.get("chr1", 2).unwrap().gerp_rs, Some(1.2));
+        assert_eq!(lookup.get("chr2", 4).unwrap().gerp_rs, Some(-1.0));
+
+        // The wig starts chr1 at 1-based position 3
+        assert_eq!(lookup.get("chr1", 2).unwrap().phylop, None);
+        assert_eq!(lookup.get("chr1", 3).unwrap().phylop, Some(2.5));
+        assert_eq!(lookup.get("chr2", 4).unwrap().phylop, Some(-1.25));
+
+        assert_eq!(lookup.get("chr3", 1), Some(&Conservation::default()));
+        assert_eq!(lookup.get("chr1", 4), None);
+    }
+
+    #[test]
+    fn annotate_vcf() {
+        let dir =
+            std::env::temp_dir().join(format!("oxid_maf_conservation_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("annotated");
+        let output = output.to_str().unwrap();
+
+        // Lengths from the ##contig lines of the VCF
+        let summary = annotate_conservation(
+            VCF,
+            output,
+            Some(RATES),
+            Some(ELEMS),
+            None,
+            Some(WIG),
+            None,
+            &[],
+            false,
+        )
+        .unwrap();
+        assert_eq!(summary.records, 6);
+        assert_eq!(summary.gerp_rs, 5);
+        assert_eq!(summary.gerp_elements, 4);
+        assert_eq!(summary.phylop, 3);
+        assert_eq!(summary.phastcons, 0);
+
+        let mut parser = VcfParser::from_file(&format!("{}.vcf", output)).unwrap();
+        assert!(parser.header().info("GERP_RS").is_some());
+        assert!(parser.header().info("PHASTCONS").is_none());
+        let info: Vec<Vec<Option<String>>> = parser
+            .records()
+            .map(|x| x.unwrap())
+            .map(|x| {
+                ["DP", "GERP_RS", "GERP_ELEM", "GERP_ELEM_P", "PHYLOP"]
+                    .iter()
+                    .map(|key| x.info(key).map(|x| x.to_string()))
+                    .collect()
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let row = |x: [Option<&str>; 5]| -> Vec<Option<String>> {
+            x.iter().map(|x| x.map(|x| x.to_string())).collect()
+        };
+        assert_eq!(
+            info,
+            vec![
+                row([Some("10"), None, None, None, None]),
+                row([Some("11"), Some("1.2"), Some("0.5"), Some("0.2"), None]),
+                row([
+                    Some("12"),
+                    Some("-0.7"),
+                    Some("0.5"),
+                    Some("0.2"),
+                    Some("2.5")
+                ]),
+                row([None, Some("2.1"), Some("4.2"), Some("0.01"), None]),
+                row([None, Some("2.5"), Some("5"), Some("0.001"), Some("0.5")]),
+                row([None, Some("-1"), None, None, Some("-1.25")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn vcf_contigs_need_lengths() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_contigs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("annotated");
+
+        // ##contig=<ID=chr2> has no length
+        let e = annotate_conservation(
+            SMALL_VCF,
+            output.to_str().unwrap(),
+            Some(RATES),
+            None,
+            None,
+            None,
+            None,
+            &[],
+            false,
+        )
+        .unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            e.to_string().contains("##contig chr2 has no length"),
+            "{}",
+            e
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Convert between MAF and TAF
+
+use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
+
+use std::collections::HashMap;
+use std::fs::File;
+
+/// What [`maf_to_taf`] or [`taf_to_maf`] converted
+#[derive(Debug, Default)]
+pub struct ConvertSummary {
+    pub blocks: u64,
+    /// Malformed blocks skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
+
+/// Stream a MAF file into TAF at `output`, optionally bgzipped and run length encoded.
+///
+/// The coordinates of every row are repeated every `repeat_coordinates` columns (0 for only when
+/// the rows change). With `index`, `<output>.tai` is written in the same pass, with an entry
+/// roughly every `index` reference bases where coordinates are repeated.
+pub fn maf_to_taf(
+    maf: &str,
+    output: &str,
+    bgzip: bool,
+    run_length_encode: bool,
+    repeat_coordinates: usize,
+    index: Option<u64>,
+    skip_malformed: bool,
+) -> Result<ConvertSummary, OxidMafError> {
+    let mut maf_parser = MafParser::from_path(maf)?;
+    let mut skip = SkipMalformed::new(skip_malformed);
+
+    let io_error = |e| OxidMafError::io(output, e);
+    let out_fh = File::create(output).map_err(io_error)?;
+    let out_fh = if bgzip {
+        TafWriter::bgzf(out_fh)
+    } else {
+        TafWriter::new(out_fh)
+    };
+    let mut out_fh = out_fh
+        .with_run_length_encoding(run_length_encode)
+        .with_repeat_coordinates(repeat_coordinates);
+    if let Some(block_size) = index {
+        out_fh = out_fh.with_index(block_size);
+    }
+
+    let mut wrote_header = false;
+    let mut blocks = 0;
+
+    while let Some(block) = maf_parser.next() {
+        let block = match skip.check(block)? {
+            Some(block) => block,
+            None => continue,
+        };
+
+        // Header is only known after the first block has been read
+        if !wrote_header {
+            out_fh
+                .write_header(&taf_header(maf_parser.header()))
+                .map_err(io_error)?;
+            wrote_header = true;
+        }
+
+        out_fh.write_block(&block).map_err(io_error)?;
+        blocks += 1;
+    }
+
+    if !wrote_header {
+        out_fh
+            .write_header(&taf_header(maf_parser.header()))
+            .map_err(io_error)?;
+    }
+
+    let tai = out_fh.finish().map_err(io_error)?;
+    if let Some(tai) = tai {
+        tai.write(&format!("{}.tai", output))?;
+    }
+
+    Ok(ConvertSummary {
+        blocks,
+        skipped: skip.skipped,
+    })
+}
+
+/// Convert a TAF file (plain, gzip or bgzipped) to MAF at `output`, starting a new block wherever
+/// the rows change.
+///
+/// Tags of the `#taf` header, other than `version` and `run_length_encode_bases`, are copied to
+/// the `##maf` line.
+pub fn taf_to_maf(
+    taf: &str,
+    output: &str,
+    skip_malformed: bool,
+) -> Result<ConvertSummary, OxidMafError> {
+    let mut taf_parser = TafParser::from_file(taf)?;
+    let mut skip = SkipMalformed::new(skip_malformed);
+
+    let io_error = |e| OxidMafError::io(output, e);
+    let out_fh = File::create(output).map_err(io_error)?;
+    let mut out_fh = MafWriter::new(out_fh);
+
+    out_fh
+        .write_header(&[taf_parser.header.maf_header()])
+        .map_err(io_error)?;
+
+    let mut blocks = 0;
+    for block in TafBlockIterator::new(&mut taf_parser) {
+        if let Some(block) = skip.check(block)? {
+            out_fh.write_block(&block).map_err(io_error)?;
+            blocks += 1;
+        }
+    }
+
+    out_fh.flush().map_err(io_error)?;
+
+    Ok(ConvertSummary {
+        blocks,
+        skipped: skip.skipped,
+    })
+}
+
+// Tags of the ##maf line (other than version), as TAF header tags
+fn taf_header(header: &[MafLine]) -> TafHeader {
+    let mut tags = HashMap::new();
+    for line in header {
+        if let MafLine::Comment(c) = line {
+            if let Some(maf_tags) = c.strip_prefix("#maf") {
+                for (key, value) in maf_tags
+                    .split_whitespace()
+                    .filter_map(|x| x.split_once('='))
+                {
+                    tags.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+    }
+    TafHeader { tags }
+}
@@ -1,45 +1,846 @@
-//! Extract SNPs from a MAF file
+//! Extract SNPs and intervals from a MAF or TAF file
 
-pub use crate::*;
+use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
 
-// todo optional pass in reference genome
-pub fn extract_snps(maf: &String, output_prefix: &String, coordinates: bool) {
-    let maf_fh = std::fs::File::open(maf).expect("Unable to open maf file");
-    let maf_parser = maf_parser(maf_fh);
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
 
-    let mut reference = None;
+/// What [`extract_snps`] wrote
+#[derive(Debug, Default)]
+pub struct ExtractSnpsSummary {
+    /// The reference species, `None` if the input has no alignment blocks
+    pub reference: Option<String>,
+    pub snps: u64,
+    /// Blocks skipped as they do not start with the reference
+    pub other_reference_blocks: u64,
+    /// Malformed blocks skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
+
+/// Write the variable columns of each alignment block of a MAF or TAF file to
+/// `<output_prefix>.vcf`.
+///
+/// The reference is the species of the first `s` line of the first block; blocks starting with
+/// another species are skipped. The VCF has one haploid sample per species, with CHROM and POS
+/// in reference coordinates and a missing call (`.`) for species with a gap, an ambiguous base or
+/// no row in the block. When a species has more than one row in a block, the first is used.
+///
+/// With `coordinates`, `<output_prefix>.coordinates.tsv` is also written, with one line per VCF
+/// record giving the 1-based source position of each species as `contig:pos:strand`.
+///
+/// Records are sorted by POS within each block, so blocks where the reference is on the minus
+/// strand are written in forward order. The blocks themselves must be sorted by reference contig
+/// and position, as otherwise the VCF would not be; an error is returned at the first block that
+/// is not.
+///
+/// The input is read twice (once to collect the samples and contigs for the VCF header), so it
+/// cannot be stdin. No files are written if it has no alignment blocks.
+pub fn extract_snps(
+    input: &str,
+    output_prefix: &str,
+    coordinates: bool,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<ExtractSnpsSummary, OxidMafError> {
+    if input == "-" {
+        return Err(OxidMafError::invalid(
+            input,
+            "extract-snps reads the input twice, and cannot read from stdin",
+        ));
+    }
+
+    let Samples {
+        reference,
+        species,
+        contigs,
+    } = collect_samples(input, genome_names, skip_malformed)?;
+    let reference = match reference {
+        Some(reference) => reference,
+        None => return Ok(ExtractSnpsSummary::default()),
+    };
+
+    let sample_idx: HashMap<&str, usize> = species
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.as_str(), i))
+        .collect();
+
+    let vcf_path = format!("{}.vcf", output_prefix);
+    let vcf_error = |e| OxidMafError::io(&vcf_path, e);
+    let vcf_fh = File::create(&vcf_path).map_err(vcf_error)?;
+    let mut vcf_fh = BufWriter::new(vcf_fh);
+    write_vcf_header(&mut vcf_fh, &species, &contigs).map_err(vcf_error)?;
+
+    let coords_path = format!("{}.coordinates.tsv", output_prefix);
+    let coords_error = |e| OxidMafError::io(&coords_path, e);
+    let mut coords_fh = if coordinates {
+        let fh = File::create(&coords_path).map_err(coords_error)?;
+        let mut fh = BufWriter::new(fh);
+        writeln!(fh, "#CHROM\tPOS\t{}", species.join("\t")).map_err(coords_error)?;
+        Some(fh)
+    } else {
+        None
+    };
+
+    let source = open_alignment(input, genome_names)?;
+    let mut skip = SkipMalformed::new(skip_malformed);
+
+    let mut other_reference_blocks = 0;
+    let mut snps = 0;
+
+    // The last POS written, and the contigs before its contig, to check the records are sorted
+    let mut last: Option<(String, u64)> = None;
+    let mut done_contigs: HashSet<String> = HashSet::new();
+
+    // Reused for every site
+    let mut rows: Vec<Option<usize>> = vec![None; species.len()];
+    let mut alleles: Vec<u8> = Vec::new();
+    let mut genotypes = String::new();
+
+    for block in source {
+        let block = match skip.check(block)?.and_then(AlignmentBlock::from_lines) {
+            Some(block) => block,
+            None => continue,
+        };
+        if block.species[0] != reference {
+            other_reference_blocks += 1;
+            continue;
+        }
+
+        let seqs: Vec<&MafSequence> = block
+            .lines
+            .iter()
+            .filter_map(|x| match x {
+                MafLine::SequenceLine(seq) => Some(seq),
+                _ => None,
+            })
+            .collect();
+
+        // First row of each species in this block
+        rows.iter_mut().for_each(|x| *x = None);
+        for (i, seq) in seqs.iter().enumerate() {
+            let sample = &mut rows[sample_idx[seq.species.as_str()]];
+            if sample.is_none() {
+                *sample = Some(i);
+            }
+        }
+
+        let mut sites = block.extract_snps();
+        sites.sort_by_key(|site| site.pos);
+
+        if let Some(first) = sites.first() {
+            let sorted = match &last {
+                Some((contig, pos)) if *contig == block.seqid => first.pos >= *pos,
+                _ => !done_contigs.contains(&block.seqid),
+            };
+            if !sorted {
+                let (contig, pos) = last.as_ref().unwrap();
+                return Err(OxidMafError::invalid(
+                    input,
+                    format!(
+                        "Blocks are not sorted by reference position: {}:{} follows {}:{}",
+                        block.seqid,
+                        first.pos + 1,
+                        contig,
+                        pos + 1
+                    ),
+                ));
+            }
+            let last_pos = sites.last().unwrap().pos;
+            match last.as_mut() {
+                Some((contig, pos)) if *contig == block.seqid => *pos = last_pos,
+                _ => {
+                    if let Some((contig, _)) = last.replace((block.seqid.clone(), last_pos)) {
+                        done_contigs.insert(contig);
+                    }
+                }
+            }
+        }
+
+        for site in sites {
+            alleles.clear();
+            alleles.push(site.ref_base);
+
+            genotypes.clear();
+            for row in rows.iter() {
+                genotypes.push('\t');
+                match row.and_then(|i| site.bases[i]) {
+                    Some(base) => {
+                        let allele = match alleles.iter().position(|x| *x == base) {
+                            Some(allele) => allele,
+                            None => {
+                                alleles.push(base);
+                                alleles.len() - 1
+                            }
+                        };
+                        genotypes.push_str(&allele.to_string());
+                    }
+                    None => genotypes.push('.'),
+                }
+            }
+
+            // Only a duplicate row differs from the reference
+            if alleles.len() == 1 {
+                continue;
+            }
+            snps += 1;
+
+            let alt: Vec<String> = alleles[1..]
+                .iter()
+                .map(|x| (*x as char).to_string())
+                .collect();
+            writeln!(
+                vcf_fh,
+                "{}\t{}\t.\t{}\t{}\t.\t.\t.\tGT{}",
+                block.seqid,
+                site.pos + 1,
+                site.ref_base as char,
+                alt.join(","),
+                genotypes
+            )
+            .map_err(vcf_error)?;
+
+            if let Some(coords_fh) = coords_fh.as_mut() {
+                let coords: Vec<String> = rows
+                    .iter()
+                    .map(
+                        |row| match row.and_then(|i| site.positions[i].map(|pos| (i, pos))) {
+                            Some((i, pos)) => {
+                                format!("{}:{}:{}", seqs[i].contig, pos + 1, seqs[i].strand)
+                            }
+                            None => ".".to_string(),
+                        },
+                    )
+                    .collect();
+                writeln!(
+                    coords_fh,
+                    "{}\t{}\t{}",
+                    block.seqid,
+                    site.pos + 1,
+                    coords.join("\t")
+                )
+                .map_err(coords_error)?;
+            }
+        }
+    }
+
+    vcf_fh.flush().map_err(vcf_error)?;
+    if let Some(mut coords_fh) = coords_fh {
+        coords_fh.flush().map_err(coords_error)?;
+    }
+
+    Ok(ExtractSnpsSummary {
+        reference: Some(reference),
+        snps,
+        other_reference_blocks,
+        skipped: skip.skipped,
+    })
+}
+
+// The reference species, every species in order of first appearance (the reference first), and
+// the reference contigs with their lengths
+struct Samples {
+    reference: Option<String>,
+    species: Vec<String>,
+    contigs: Vec<(String, u64)>,
+}
+
+// First pass over the alignment
+fn collect_samples(
+    input: &str,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<Samples, OxidMafError> {
+    let source = open_alignment(input, genome_names)?;
+    // Malformed blocks are only reported from the second pass
+    let mut skip = SkipMalformed::new(skip_malformed);
+
+    let mut reference: Option<String> = None;
+    let mut species: Vec<String> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut contigs: Vec<(String, u64)> = Vec::new();
+    let mut seen_contigs: HashSet<String> = HashSet::new();
+
+    for block in source {
+        let block = match skip.check(block)?.and_then(AlignmentBlock::from_lines) {
+            Some(block) => block,
+            None => continue,
+        };
+        let reference = reference.get_or_insert_with(|| block.species[0].clone());
+
+        for x in block.species.iter() {
+            if seen.insert(x.clone()) {
+                species.push(x.clone());
+            }
+        }
+
+        if block.species[0] == *reference {
+            if let Some(MafLine::SequenceLine(seq)) = block.lines.first() {
+                if seen_contigs.insert(seq.contig.clone()) {
+                    contigs.push((seq.contig.clone(), seq.src_size));
+                }
+            }
+        }
+    }
+
+    Ok(Samples {
+        reference,
+        species,
+        contigs,
+    })
+}
+
+fn write_vcf_header<W: Write>(
+    out: &mut W,
+    species: &[String],
+    contigs: &[(String, u64)],
+) -> std::io::Result<()> {
+    writeln!(out, "##fileformat=VCFv4.2")?;
+    writeln!(out, "##source=oxid_maf extract-snps")?;
+    for (contig, length) in contigs {
+        writeln!(out, "##contig=<ID={},length={}>", contig, length)?;
+    }
+    writeln!(
+        out,
+        "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">"
+    )?;
+    writeln!(
+        out,
+        "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{}",
+        species.join("\t")
+    )
+}
+
+/// Trim the blocks of `input`, a MAF or TAF file, to each region, given in the coordinates of `species` (0-based,
+/// half-open), for printing as MAF or FASTA.
+///
+/// Every block where the first row of `species` on the region's contig overlaps the region is
+/// trimmed to exactly the reference columns in the region, along with any insertions between
+/// them, and `start` and `size` are recomputed for each row. Rows left with no bases are dropped.
+/// `i` lines are dropped, as they no longer describe the trimmed rows.
+///
+/// If `<input>.mafi` exists (see `index`) and `species` is its reference, only the indexed blocks
+/// overlapping each region are read, in region order. Otherwise the whole file is read, and
+/// blocks are returned in file order. TAF is regrouped into blocks wherever its rows change (see
+/// [`TafBlockIterator`]).
+pub fn extract_interval(
+    input: &str,
+    species: &str,
+    regions: Vec<(String, Range<u64>)>,
+    genome_names: &[String],
+) -> Result<IntervalBlocks, OxidMafError> {
+    // Seek straight to the overlapping blocks if the file has been indexed
+    let index_path = format!("{}.mafi", input);
+    if input != "-" && Path::new(&index_path).exists() {
+        let reader = MafIndexedReader::from_path(input)?.with_genome_names(genome_names);
+        if reader.index().has_species(species) {
+            return Ok(IntervalBlocks {
+                source: IntervalSource::Indexed {
+                    reader: Box::new(reader),
+                    regions: regions.into_iter(),
+                },
+                species: species.to_string(),
+                pending: VecDeque::new(),
+            });
+        }
+    }
+
+    let mut by_contig: HashMap<String, Vec<Range<u64>>> = HashMap::new();
+    for (chrom, region) in regions {
+        by_contig.entry(chrom).or_default().push(region);
+    }
+    for x in by_contig.values_mut() {
+        x.sort_by_key(|region| region.start);
+    }
+
+    Ok(IntervalBlocks {
+        source: IntervalSource::Scan {
+            source: open_alignment(input, genome_names)?,
+            regions: by_contig,
+        },
+        species: species.to_string(),
+        pending: VecDeque::new(),
+    })
+}
+
+/// The blocks of a MAF or TAF file trimmed to a set of regions, from [`extract_interval`]
+pub struct IntervalBlocks {
+    source: IntervalSource,
+    species: String,
+    // Trimmed blocks from the last block or region read, not yet returned
+    pending: VecDeque<Vec<MafLine>>,
+}
+
+enum IntervalSource {
+    // Fetch the blocks of each region in turn
+    Indexed {
+        reader: Box<MafIndexedReader>,
+        regions: std::vec::IntoIter<(String, Range<u64>)>,
+    },
+    // Read the whole file, with the regions of each contig sorted by start
+    Scan {
+        source: Box<dyn AlignmentSource>,
+        regions: HashMap<String, Vec<Range<u64>>>,
+    },
+}
+
+impl IntervalBlocks {
+    /// Comment lines preceding the first block of the file, once a block has been returned
+    pub fn header(&self) -> &[MafLine] {
+        match &self.source {
+            IntervalSource::Indexed { reader, .. } => reader.header(),
+            IntervalSource::Scan { source, .. } => source.header(),
+        }
+    }
+
+    /// True if the blocks are read through the index, rather than the whole file
+    pub fn is_indexed(&self) -> bool {
+        matches!(self.source, IntervalSource::Indexed { .. })
+    }
+}
 
-    let mut alignment_block = AlignmentBlock::default();
+impl Iterator for IntervalBlocks {
+    type Item = Result<Vec<MafLine>, OxidMafError>;
 
-    for block in maf_parser {
-        for line in block.iter() {
-            match line {
-                // Do nothing for these two...
-                MafLine::Comment(_) => (),
-                MafLine::BlankLine => (),
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(block) = self.pending.pop_front() {
+                return Some(Ok(block));
+            }
+
+            match &mut self.source {
+                IntervalSource::Indexed { reader, regions } => {
+                    let (chrom, region) = regions.next()?;
+                    let blocks = match reader.fetch(&self.species, &chrom, region.clone()) {
+                        Ok(blocks) => blocks,
+                        Err(e) => return Some(Err(e)),
+                    };
 
-                // This should trigger the start of a new alignment block
-                // i.e., process the previous alignment block, then reset the state
-                MafLine::AlignmentBlockLine(_) => (),
+                    for block in blocks {
+                        let reference = block.iter().find_map(|line| match line {
+                            MafLine::SequenceLine(seq)
+                                if seq.species == self.species && seq.contig == chrom =>
+                            {
+                                Some(seq)
+                            }
+                            _ => None,
+                        });
 
-                MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text) => {
-                    if reference.is_none() {
-                        reference = Some(species.clone());
+                        if let Some(sliced) =
+                            reference.and_then(|x| slice_block(&block, x, &region))
+                        {
+                            self.pending.push_back(sliced);
+                        }
                     }
+                }
+                IntervalSource::Scan { source, regions } => {
+                    let block = match source.next()? {
+                        Ok(block) => block,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    let reference = block.iter().find_map(|line| match line {
+                        MafLine::SequenceLine(seq)
+                            if seq.species == self.species && regions.contains_key(&seq.contig) =>
+                        {
+                            Some(seq)
+                        }
+                        _ => None,
+                    });
+
+                    let reference = match reference {
+                        Some(reference) => reference,
+                        None => continue,
+                    };
 
-                    // If the alignment block is empty, this is the first line of the block
-                    if alignment_block.lines.is_empty() {
-                        assert!(
-                            species == reference.as_ref().unwrap(),
-                            "First line of alignment block is not the reference genome"
-                        );
-                        alignment_block.seqid = seqid.clone();
-                        alignment_block.start = *start;
+                    // Block in forward strand coordinates
+                    let forward = reference.forward_range();
+
+                    for region in regions[&reference.contig].iter() {
+                        if region.start >= forward.end {
+                            break;
+                        }
+                        if region.end <= forward.start {
+                            continue;
+                        }
+
+                        if let Some(sliced) = slice_block(&block, reference, region) {
+                            self.pending.push_back(sliced);
+                        }
                     }
+                }
+            }
+        }
+    }
+}
+
+/// Trim a block to the columns of `region` (forward strand) in the `reference` row, `None` if
+/// they do not overlap
+pub fn slice_block(
+    block: &[MafLine],
+    reference: &MafSequence,
+    region: &Range<u64>,
+) -> Option<Vec<MafLine>> {
+    let mut first_col = None;
+    let mut last_col = None;
+    let mut offset = 0;
+    for (col, base) in reference.text.bytes().enumerate() {
+        if base == b'-' {
+            continue;
+        }
+        if region.contains(&reference.forward_position(offset)) {
+            first_col.get_or_insert(col);
+            last_col = Some(col);
+        }
+        offset += 1;
+    }
+
+    let cols = first_col?..last_col? + 1;
+
+    let mut sliced = Vec::with_capacity(block.len());
+
+    // q lines belong to the s line before them
+    let mut dropped_src: Option<&str> = None;
 
-                    alignment_block.add_line(line.clone());
+    for line in block {
+        match line {
+            MafLine::SequenceLine(row) => {
+                let seq = row.slice_columns(cols.clone());
+                if seq.size == 0 {
+                    dropped_src = Some(&row.src);
+                } else {
+                    dropped_src = None;
+                    sliced.push(MafLine::SequenceLine(seq));
                 }
             }
+            MafLine::QualityLine(q) => {
+                if dropped_src != Some(q.src.as_str()) {
+                    let mut q = q.clone();
+                    q.quality = q.quality.get(cols.clone()).unwrap_or_default().to_string();
+                    sliced.push(MafLine::QualityLine(q));
+                }
+            }
+            MafLine::InfoLine(_) => (),
+            line => sliced.push(line.clone()),
+        }
+    }
+
+    Some(sliced)
+}
+
+/// Parse `chr:start-end` (1-based, inclusive) or `chr:pos` into a 0-based half-open range
+pub fn parse_region(query: &str) -> Result<(String, Range<u64>), String> {
+    let invalid = || {
+        format!(
+            "Invalid region {}, expected chr:start-end or chr:pos",
+            query
+        )
+    };
+
+    // Contig names may themselves contain a colon
+    let (chrom, range) = query.rsplit_once(':').ok_or_else(invalid)?;
+    let range = range.replace(',', "");
+
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start, end),
+        None => (range.as_str(), range.as_str()),
+    };
+    let start: u64 = start.parse().map_err(|_| invalid())?;
+    let end: u64 = end.parse().map_err(|_| invalid())?;
+
+    if start == 0 || end < start {
+        return Err(invalid());
+    }
+
+    Ok((chrom.to_string(), start - 1..end))
+}
+
+/// Read the regions (0-based, half-open) of a BED file
+pub fn read_bed(bed: &str) -> Result<Vec<(String, Range<u64>)>, OxidMafError> {
+    let reader = open_input(bed).map_err(|e| OxidMafError::io(bed, e))?;
+    let mut regions = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| OxidMafError::io(bed, e))?;
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+
+        let mut fields = line.split('\t');
+        let chrom = fields.next().unwrap();
+        let start = fields.next().and_then(|x| x.trim().parse::<u64>().ok());
+        let end = fields.next().and_then(|x| x.trim().parse::<u64>().ok());
+
+        match (start, end) {
+            (Some(start), Some(end)) if start < end => {
+                regions.push((chrom.to_string(), start..end));
+            }
+            _ => {
+                return Err(OxidMafError::malformed(
+                    bed,
+                    i as u64 + 1,
+                    &line,
+                    "Invalid BED line",
+                ))
+            }
+        }
+    }
+
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROWS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/rows.maf");
+    const INDELS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/indels.maf");
+
+    fn blocks(path: &str) -> Vec<Vec<MafLine>> {
+        MafParser::from_path(path)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect()
+    }
+
+    // src, start, size and text of each row of a block sliced to `region` of row `reference`
+    fn slice(
+        block: &[MafLine],
+        reference: usize,
+        region: Range<u64>,
+    ) -> Option<Vec<(String, u64, u64, String)>> {
+        let seqs: Vec<&MafSequence> = block
+            .iter()
+            .filter_map(|x| match x {
+                MafLine::SequenceLine(seq) => Some(seq),
+                _ => None,
+            })
+            .collect();
+        let sliced = slice_block(block, seqs[reference], &region)?;
+        Some(
+            sliced
+                .iter()
+                .filter_map(|x| match x {
+                    MafLine::SequenceLine(x) => {
+                        Some((x.src.clone(), x.start, x.size, x.text.clone()))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    fn rows(x: &[(&str, u64, u64, &str)]) -> Option<Vec<(String, u64, u64, String)>> {
+        Some(
+            x.iter()
+                .map(|(src, start, size, text)| (src.to_string(), *start, *size, text.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn slice_region_edges() {
+        let indels = blocks(INDELS);
+        let hg38 = "hg38.chr1";
+        let anc0 = "Anc0.Anc0refChr1";
+
+        // Insertions between the reference bases of the region are kept, those at its edges
+        // are not
+        assert_eq!(
+            slice(&indels[0], 0, 2..4),
+            rows(&[(hg38, 2, 2, "G--T"), (anc0, 2, 4, "GTTT")])
+        );
+        assert_eq!(
+            slice(&indels[0], 0, 3..5),
+            rows(&[(hg38, 3, 2, "TA"), (anc0, 5, 2, "TA")])
+        );
+        assert_eq!(
+            slice(&indels[0], 0, 4..100),
+            rows(&[(hg38, 4, 1, "A"), (anc0, 6, 1, "A")])
+        );
+        assert_eq!(slice(&indels[0], 0, 5..100), None);
+        assert_eq!(slice(&indels[1], 0, 0..5), None);
+    }
+
+    #[test]
+    fn slice_minus_strand() {
+        let blocks = blocks(ROWS);
+        let hg38 = "hg38.chr1";
+        let anc0 = "Anc0.Anc0refChr1";
+        let mm10 = "mm10.chr5";
+
+        // Minus strand starts count from the end of the source
+        assert_eq!(
+            slice(&blocks[0], 0, 11..13),
+            rows(&[(hg38, 11, 2, "CG"), (anc0, 6, 1, "C-"), (mm10, 21, 2, "CG")])
+        );
+
+        // A minus strand reference is sliced by its forward strand coordinates: Anc0's bases
+        // are at 44, 43 and 42
+        assert_eq!(
+            slice(&blocks[0], 1, 42..44),
+            rows(&[
+                (hg38, 11, 3, "CGT"),
+                (anc0, 6, 2, "C-T"),
+                (mm10, 21, 3, "CGA")
+            ])
+        );
+
+        // mm10 has no bases left
+        assert_eq!(
+            slice(&blocks[2], 0, 17..18),
+            rows(&[(hg38, 17, 1, "T"), (anc0, 15, 1, "T")])
+        );
+    }
+
+    #[test]
+    fn regions() {
+        let region = |x: &str, start, end| Ok((x.to_string(), start..end));
+        assert_eq!(parse_region("chr1:1,000-2,000"), region("chr1", 999, 2000));
+        assert_eq!(parse_region("chr1:5"), region("chr1", 4, 5));
+        assert_eq!(parse_region("HLA:A:3-4"), region("HLA:A", 2, 4));
+        for bad in ["chr1", "chr1:0-5", "chr1:5-4", "chr1:a-b", "chr1:1-"] {
+            let e = parse_region(bad).unwrap_err();
+            assert!(e.starts_with("Invalid region"), "{}", e);
+        }
+
+        let dir = std::env::temp_dir().join(format!("oxid_maf_bed_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bed = dir.join("regions.bed");
+        let bed = bed.to_str().unwrap();
+        std::fs::write(
+            bed,
+            "track name=x\n#chrom\n\nchr1\t2\t4\tname\nchr2\t 5\t6\n",
+        )
+        .unwrap();
+        let regions = read_bed(bed);
+        std::fs::write(bed, "chr1\t2\t4\nchr1\t4\t4\n").unwrap();
+        let e = read_bed(bed).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            regions.unwrap(),
+            vec![("chr1".to_string(), 2..4), ("chr2".to_string(), 5..6)]
+        );
+        match e {
+            OxidMafError::Malformed { line, .. } => assert_eq!(line, 2),
+            e => panic!("{}", e),
         }
     }
+
+    #[test]
+    fn snps() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_snps_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("snps");
+        let output = output.to_str().unwrap();
+
+        let summary = extract_snps(ROWS, output, true, &[], false).unwrap();
+        assert_eq!(summary.reference.as_deref(), Some("hg38"));
+        assert_eq!((summary.snps, summary.other_reference_blocks), (3, 0));
+
+        let mut parser = VcfParser::from_file(&format!("{}.vcf", output)).unwrap();
+        assert_eq!(parser.header().samples, ["hg38", "Anc0", "mm10", "panTro6"]);
+        assert_eq!(
+            parser.header().contigs().collect::<Vec<_>>(),
+            vec![("chr1", Some(100))]
+        );
+        let records: Vec<Record> = parser.records().map(|x| x.unwrap()).collect();
+        let coordinates = std::fs::read_to_string(format!("{}.coordinates.tsv", output)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The column at 13, where Anc0 has a gap, is not a SNP. panTro6 has no row in these
+        // blocks, and mm10 a gap at 18.
+        let sites: Vec<(u64, Vec<&str>, Vec<&str>)> = records
+            .iter()
+            .map(|x| {
+                let gt = x.samples.iter().map(|x| x[0].as_str()).collect();
+                (x.pos, x.alleles().collect(), gt)
+            })
+            .collect();
+        assert_eq!(
+            sites,
+            vec![
+                (14, vec!["T", "A"], vec!["0", "0", "1", "."]),
+                (19, vec!["T", "C", "A"], vec!["0", "1", "2", "."]),
+                (32, vec!["A", "G"], vec!["0", "0", "1", "."]),
+            ]
+        );
+
+        // Minus strand rows are given in forward strand coordinates
+        let coordinates: Vec<&str> = coordinates.lines().collect();
+        assert_eq!(
+            coordinates,
+            vec![
+                "#CHROM\tPOS\thg38\tAnc0\tmm10\tpanTro6",
+                "chr1\t14\tchr1:14:+\tAnc0refChr1:43:-\tchr5:57:-\t.",
+                "chr1\t19\tchr1:19:+\tAnc0refChr1:34:-\tchr5:40:-\t.",
+                "chr1\t32\tchr1:32:+\tAnc0refChr1:5:+\tchr5:38:-\t.",
+            ]
+        );
+    }
+
+    #[test]
+    fn snps_sorted_by_pos() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_snps_sorted_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.maf");
+        let input = input.to_str().unwrap();
+        let output = dir.join("snps");
+        let output = output.to_str().unwrap();
+
+        // The first block has the reference on the minus strand, covering forward positions
+        // 0-3 of chr1 backwards, with SNPs at its first and third columns
+        let minus = "a\n\
+                     s hg38.chr1 6 4 - 10 ACGT\n\
+                     s mm10.chr1 0 4 + 10 TCCT\n\n";
+        let plus = "a\n\
+                    s hg38.chr1 4 2 + 10 AC\n\
+                    s mm10.chr1 4 2 + 10 AG\n\n";
+        std::fs::write(input, format!("##maf version=1\n\n{}{}", minus, plus)).unwrap();
+        extract_snps(input, output, false, &[], false).unwrap();
+        let mut parser = VcfParser::from_file(&format!("{}.vcf", output)).unwrap();
+        let sites: Vec<(u64, Vec<String>)> = parser
+            .records()
+            .map(|x| {
+                let x = x.unwrap();
+                (x.pos, x.alleles().map(|x| x.to_string()).collect())
+            })
+            .collect();
+        let site =
+            |pos: u64, alleles: &[&str]| (pos, alleles.iter().map(|x| x.to_string()).collect());
+        assert_eq!(
+            sites,
+            vec![
+                site(2, &["C", "G"]),
+                site(4, &["T", "A"]),
+                site(6, &["C", "G"])
+            ]
+        );
+
+        // Blocks out of order are an error, rather than an unsorted VCF
+        std::fs::write(input, format!("##maf version=1\n\n{}{}", plus, minus)).unwrap();
+        let e = extract_snps(input, output, false, &[], false).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            e.to_string()
+                .contains("not sorted by reference position: chr1:2 follows chr1:6"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn snps_from_stdin() {
+        let e = extract_snps("-", "unused", false, &[], false).unwrap_err();
+        assert!(e.to_string().contains("cannot read from stdin"), "{}", e);
+    }
 }
@@ -0,0 +1,409 @@
+//! Join GERP++ scores and constrained elements, genome-wide
+
+use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
+
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+/// A scored position of a GERP++ rates file, with the constrained element containing it
+#[derive(Clone, Debug, PartialEq)]
+pub struct GerpSite {
+    pub rate: GerpRate,
+    pub element: Option<GerpElement>,
+}
+
+/// Joins the positions of a GERP++ rates file with the constrained elements containing them, in a
+/// single sweep. Unaligned positions, with a neutral rate and RS score of 0, are skipped.
+pub struct GerpSites {
+    rates: GerpRatesReader,
+    elements: GerpElements,
+}
+
+impl GerpSites {
+    pub fn new(rates: GerpRatesReader, elements: GerpElements) -> Self {
+        GerpSites { rates, elements }
+    }
+
+    /// Contigs of the rates file, indexed by [`GerpRate::contig`]
+    pub fn contigs(&self) -> &[(String, u64)] {
+        self.rates.contigs()
+    }
+
+    pub fn elements(&self) -> &GerpElements {
+        &self.elements
+    }
+
+    /// See [`GerpRatesReader::remaining`]
+    pub fn remaining(&self) -> u64 {
+        self.rates.remaining()
+    }
+}
+
+impl Iterator for GerpSites {
+    type Item = Result<GerpSite, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let rate = match self.rates.next()? {
+                Ok(rate) => rate,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if rate.neutral_rate == 0.0 && rate.rs_score == 0.0 {
+                continue;
+            }
+
+            let contig = &self.rates.contigs()[rate.contig].0;
+            let element = self.elements.find(contig, rate.pos).cloned();
+            return Some(Ok(GerpSite { rate, element }));
+        }
+    }
+}
+
+/// What [`process_gerp`] wrote
+#[derive(Debug, Default)]
+pub struct GerpSummary {
+    /// Contigs of the rates file
+    pub contigs: usize,
+    /// Positions with a score, written to the TSV
+    pub scored: u64,
+    /// Constrained elements written to the BED
+    pub elements: u64,
+    /// Contigs with constrained elements but no length, whose elements are not written
+    pub unplaced_contigs: Vec<String>,
+    /// Lines missing from the end of the rates file, see [`GerpRatesReader::remaining`]
+    pub missing_rates: u64,
+    /// Malformed rates and elements lines skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
+
+/// Join the positions of a GERP++ rates file with the constrained elements of an `.elems` file,
+/// one contig after another, in the order of [`GerpRatesReader::contigs`].
+///
+/// Writes, for positions with a score:
+/// - `tsv`: CHROM, POS, neutral rate, RS score, whether the position is in an element, and that
+///   element's start, end, RS score, p-value, expected, observed and length
+/// - `bedgraph`: RS scores, merging adjacent positions with the same score
+/// - `bed`: the constrained elements, with their RS score and p-value
+///
+/// The outputs are named `<output>.tsv`, `<output>.bedGraph` and `<output>.bed` in errors.
+pub fn process_gerp(
+    rates: GerpRatesReader,
+    elements: GerpElementReader,
+    output: &str,
+    mut tsv: impl Write,
+    mut bedgraph: impl Write,
+    mut bed: impl Write,
+    skip_malformed: bool,
+) -> Result<GerpSummary, OxidMafError> {
+    let mut skip = SkipMalformed::new(skip_malformed);
+    let elements = GerpElements::new(skip.filter(elements));
+    skip.error()?;
+
+    let tsv_path = format!("{}.tsv", output);
+    let tsv_error = |e| OxidMafError::io(&tsv_path, e);
+    let bedgraph_path = format!("{}.bedGraph", output);
+    let bedgraph_error = |e| OxidMafError::io(&bedgraph_path, e);
+    let bed_path = format!("{}.bed", output);
+    let bed_error = |e| OxidMafError::io(&bed_path, e);
+
+    let mut summary = GerpSummary {
+        contigs: rates.contigs().len(),
+        unplaced_contigs: elements
+            .contigs()
+            .filter(|x| !rates.contigs().iter().any(|(name, _)| name == x))
+            .map(|x| x.to_string())
+            .collect(),
+        ..Default::default()
+    };
+
+    // Constrained elements, in contig order
+    for (contig, _) in rates.contigs().iter() {
+        for e in elements.get(contig) {
+            writeln!(
+                bed,
+                "{}\t{}\t{}\t{}\t{}",
+                contig,
+                e.start - 1,
+                e.end,
+                e.rs_score,
+                e.p_value
+            )
+            .map_err(bed_error)?;
+            summary.elements += 1;
+        }
+    }
+
+    writeln!(
+        tsv,
+        "#CHROM\tPOS\tNEUTRAL_RATE\tRS\tIN_ELEM\tELEM_START\tELEM_END\tELEM_RS\tELEM_P\
+         \tELEM_EXPECTED\tELEM_OBSERVED\tELEM_LENGTH"
+    )
+    .map_err(tsv_error)?;
+
+    let mut sites = GerpSites::new(rates, elements);
+    let contigs = sites.contigs().to_vec();
+
+    // The bedGraph interval being extended: contig, 0-based start and end, and score
+    let mut interval: Option<(usize, u64, u64, f64)> = None;
+
+    for site in sites.by_ref() {
+        let (rate, element) = match skip.check(site)? {
+            Some(site) => (site.rate, site.element),
+            None => continue,
+        };
+        let contig = &contigs[rate.contig].0;
+        summary.scored += 1;
+
+        match element {
+            Some(e) => writeln!(
+                tsv,
+                "{}\t{}\t{}\t{}\ttrue\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                contig,
+                rate.pos,
+                rate.neutral_rate,
+                rate.rs_score,
+                e.start,
+                e.end,
+                e.rs_score,
+                e.p_value,
+                e.expected,
+                e.observed,
+                e.length
+            ),
+            None => writeln!(
+                tsv,
+                "{}\t{}\t{}\t{}\tfalse\t\t\t\t\t\t\t",
+                contig, rate.pos, rate.neutral_rate, rate.rs_score
+            ),
+        }
+        .map_err(tsv_error)?;
+
+        match interval.as_mut() {
+            Some((i, _, end, score))
+                if *i == rate.contig && *end == rate.pos - 1 && *score == rate.rs_score =>
+            {
+                *end = rate.pos
+            }
+            _ => {
+                if let Some((i, start, end, score)) = interval {
+                    writeln!(bedgraph, "{}\t{}\t{}\t{}", contigs[i].0, start, end, score)
+                        .map_err(bedgraph_error)?;
+                }
+                interval = Some((rate.contig, rate.pos - 1, rate.pos, rate.rs_score));
+            }
+        }
+    }
+
+    if let Some((i, start, end, score)) = interval {
+        writeln!(bedgraph, "{}\t{}\t{}\t{}", contigs[i].0, start, end, score)
+            .map_err(bedgraph_error)?;
+    }
+
+    tsv.flush().map_err(tsv_error)?;
+    bedgraph.flush().map_err(bedgraph_error)?;
+    bed.flush().map_err(bed_error)?;
+
+    summary.missing_rates = sites.remaining();
+    summary.skipped = skip.skipped;
+    Ok(summary)
+}
+
+/// Contig lengths of the GERP++ rates file `rates`, in order, from a `.fai` index, or a MAF file,
+/// using the `src_size` of the reference (first) row of the blocks of each contig. Malformed MAF
+/// blocks are skipped with `skip_malformed`.
+///
+/// Rates lines are placed by counting, so a contig missing from the lengths moves every later line
+/// onto the wrong contig and position. A MAF file leaves out contigs with no blocks (or only
+/// malformed ones), so its lengths are checked against the number of lines of `rates`, which
+/// must then be a file (not stdin); if they differ, a `.fai` index is needed.
+pub fn contig_lengths(
+    lengths: &str,
+    rates: &str,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<Vec<(String, u64)>, OxidMafError> {
+    if lengths.ends_with(".fai") {
+        return read_fai(lengths);
+    }
+
+    let parser = MafParser::from_path(lengths)?.with_genome_names(genome_names);
+
+    let mut contigs = Vec::new();
+    let mut seen = HashSet::new();
+    for block in parser {
+        let block = match block {
+            Ok(block) => block,
+            Err(e) if skip_malformed && e.is_malformed() => continue,
+            Err(e) => return Err(e),
+        };
+
+        let reference = block.iter().find_map(|line| match line {
+            MafLine::SequenceLine(seq) => Some(seq),
+            _ => None,
+        });
+        if let Some(seq) = reference {
+            if seen.insert(seq.contig.clone()) {
+                contigs.push((seq.contig.clone(), seq.src_size));
+            }
+        }
+    }
+
+    check_rates_lines(rates, &contigs, lengths)?;
+    Ok(contigs)
+}
+
+// Check that `rates` has one line per position of `contigs`, read from `lengths`
+pub(crate) fn check_rates_lines(
+    rates: &str,
+    contigs: &[(String, u64)],
+    lengths: &str,
+) -> Result<(), OxidMafError> {
+    if rates == "-" {
+        return Err(OxidMafError::invalid(
+            rates,
+            format!(
+                "The contig lengths of {} cannot be checked against rates from stdin, use a .fai \
+                 index",
+                lengths
+            ),
+        ));
+    }
+
+    let mut reader = open_input(rates).map_err(|e| OxidMafError::io(rates, e))?;
+    let mut lines = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => lines += 1,
+            Err(e) => return Err(OxidMafError::io(rates, e)),
+        }
+    }
+
+    let total: u64 = contigs.iter().map(|(_, length)| length).sum();
+    if lines != total {
+        return Err(OxidMafError::invalid(
+            rates,
+            format!(
+                "{} lines, but the contigs of {} have {} positions. Contigs with no alignment \
+                 blocks are missing from a MAF file, use a .fai index",
+                lines, lengths, total
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.rates");
+    const ELEMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.elems");
+    const FAI: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.fai");
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.maf");
+    const MISSING_CONTIG: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_data/gerp_missing_contig.maf"
+    );
+
+    #[test]
+    fn join_rates_and_elements() {
+        let (mut tsv, mut bedgraph, mut bed) = (Vec::new(), Vec::new(), Vec::new());
+        let rates = GerpRatesReader::from_file(RATES, read_fai(FAI).unwrap()).unwrap();
+        let elements = GerpElementReader::from_file(ELEMS).unwrap();
+        let summary = process_gerp(
+            rates,
+            elements,
+            "gerp",
+            &mut tsv,
+            &mut bedgraph,
+            &mut bed,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            (summary.contigs, summary.scored, summary.elements),
+            (2, 8, 3)
+        );
+        assert!(summary.unplaced_contigs.is_empty());
+        assert_eq!(summary.missing_rates, 0);
+
+        let text = |x: Vec<u8>| String::from_utf8(x).unwrap();
+        assert_eq!(
+            text(bed),
+            "chr1\t1\t3\t0.5\t0.2\nchr1\t4\t6\t4.2\t0.01\nchr2\t1\t3\t5\t0.001\n"
+        );
+        // Adjacent positions with the same score are merged
+        assert_eq!(
+            text(bedgraph),
+            "chr1\t1\t2\t1.2\nchr1\t2\t3\t-0.7\nchr1\t3\t4\t0.8\nchr1\t4\t6\t2.1\n\
+             chr2\t1\t3\t2.5\nchr2\t3\t4\t-1\n"
+        );
+        let tsv = text(tsv);
+        let tsv: Vec<&str> = tsv.lines().collect();
+        assert_eq!(tsv.len(), 9);
+        assert_eq!(
+            tsv[1],
+            "chr1\t2\t1.2\t1.2\ttrue\t2\t3\t0.5\t0.2\t2.7\t2.2\t2"
+        );
+        assert_eq!(tsv[3], "chr1\t4\t0.8\t0.8\tfalse\t\t\t\t\t\t\t");
+    }
+
+    #[test]
+    fn elements_without_length() {
+        // Only chr1, so chr2's elements have no length and the rates file runs on past it
+        let contigs = vec![("chr1".to_string(), 6)];
+        let rates = GerpRatesReader::from_file(RATES, contigs).unwrap();
+        let elements = GerpElementReader::from_file(ELEMS).unwrap();
+        let e = process_gerp(
+            rates,
+            elements,
+            "gerp",
+            std::io::sink(),
+            std::io::sink(),
+            std::io::sink(),
+            false,
+        )
+        .unwrap_err();
+        assert!(
+            e.to_string().contains("More lines than the total length"),
+            "{}",
+            e
+        );
+
+        let contigs = vec![("chr1".to_string(), 6), ("chr3".to_string(), 6)];
+        let rates = GerpRatesReader::from_file(RATES, contigs).unwrap();
+        let elements = GerpElementReader::from_file(ELEMS).unwrap();
+        let summary = process_gerp(
+            rates,
+            elements,
+            "gerp",
+            std::io::sink(),
+            std::io::sink(),
+            std::io::sink(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(summary.unplaced_contigs, vec!["chr2"]);
+        assert_eq!((summary.elements, summary.missing_rates), (2, 2));
+    }
+
+    #[test]
+    fn maf_lengths_must_cover_rates() {
+        let contigs = contig_lengths(MAF, RATES, &[], false).unwrap();
+        assert_eq!(
+            contigs,
+            vec![("chr1".to_string(), 6), ("chr2".to_string(), 4)]
+        );
+
+        // chr1 has no blocks, so its rates would be read as chr2's
+        let e = contig_lengths(MISSING_CONTIG, RATES, &[], false).unwrap_err();
+        assert!(!e.is_malformed());
+        assert!(e.to_string().contains("10 lines"), "{}", e);
+    }
+}
@@ -0,0 +1,18 @@
+//! Build indexes for random access to alignment files
+
+use crate::parsers::*;
+use crate::OxidMafError;
+
+/// Index a plain or bgzipped MAF file, writing `<input>.mafi`
+pub fn index(input: &str, genome_names: &[String]) -> Result<(), OxidMafError> {
+    let index = MafIndex::build(input, genome_names)?;
+    index.write(&format!("{}.mafi", input))
+}
+
+/// Index a plain or bgzipped TAF file, writing `<input>.tai`, with an entry roughly every `block_size`
+/// reference bases
+pub fn index_taf(input: &str, block_size: u64) -> Result<(), OxidMafError> {
+    let mut parser = TafParser::from_file(input)?;
+    let index = TaiIndex::build(&mut parser, block_size)?;
+    index.write(&format!("{}.tai", input))
+}
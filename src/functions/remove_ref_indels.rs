@@ -1,109 +1,209 @@
-//! Extract SNPs from a MAF file
-
-pub use crate::*;
-
-// todo optional pass in reference genome
-pub fn remove_ref_indels(maf: &String, output_prefix: &String) {
-    let maf_fh = std::fs::File::open(maf).expect("Unable to open maf file");
-    let maf_parser = maf_parser(maf_fh);
-
-    let mut reference = None;
-
-    let mut alignment_block = AlignmentBlock::default();
-
-    for block in maf_parser {
-        for line in block.iter() {
-            match line {
-                // Do nothing for these two...
-                MafLine::Comment(_) => (),
-                MafLine::BlankLine => (),
-
-                // This should trigger the start of a new alignment block
-                // i.e., process the previous alignment block, then reset the state
-                MafLine::AlignmentBlockLine(_) => (),
-
-                MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text) => {
-                    if reference.is_none() {
-                        reference = Some(species.clone());
-                        alignment_block.reference = species.clone();
-                    }
-
-                    // If the alignment block is empty, this is the first line of the block
-                    if alignment_block.lines.is_empty() {
-                        assert!(
-                            species == reference.as_ref().unwrap(),
-                            "First line of alignment block is not the reference genome"
-                        );
-                        alignment_block.seqid = seqid.clone();
-                        alignment_block.start = *start;
-                    }
-
-                    alignment_block.add_line(line.clone());
-                }
-            }
+//! Project a MAF file onto the reference, removing columns where the reference has a gap
+
+use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
+
+use std::fs::File;
+
+/// What [`remove_ref_indels`] removed
+#[derive(Debug, Default)]
+pub struct RemoveRefIndelsSummary {
+    pub columns_removed: usize,
+    /// Malformed blocks skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
+
+/// Write `<output_prefix>.maf` with every reference gap column removed, so that each column of
+/// the output corresponds to one reference base.
+///
+/// The reference is the first `s` line of each block. With `all_gaps`, columns where any row has
+/// a gap are removed as well. The `start` and `size` of each row are recomputed, see
+/// [`MafSequence::retain_columns`]; rows left with no bases are dropped, along with their `i` and
+/// `q` lines. Blocks left with no columns are dropped.
+pub fn remove_ref_indels(
+    maf: &str,
+    output_prefix: &str,
+    all_gaps: bool,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<RemoveRefIndelsSummary, OxidMafError> {
+    let mut maf_parser = MafParser::from_path(maf)?.with_genome_names(genome_names);
+    let mut skip = SkipMalformed::new(skip_malformed);
+
+    let output = format!("{}.maf", output_prefix);
+    let io_error = |e| OxidMafError::io(&output, e);
+    let out_fh = File::create(&output).map_err(io_error)?;
+    let mut out_fh = MafWriter::new(out_fh);
+
+    let mut wrote_header = false;
+    let mut columns_removed = 0;
+
+    while let Some(block) = maf_parser.next() {
+        let block = match skip.check(block)? {
+            Some(block) => block,
+            None => continue,
+        };
+
+        // Header is only known after the first block has been read
+        if !wrote_header {
+            out_fh.write_header(maf_parser.header()).map_err(io_error)?;
+            wrote_header = true;
         }
 
-        if !alignment_block.is_empty() {
-            alignment_block.remove_ref_indels();
+        let keep = match columns_to_keep(&block, all_gaps) {
+            Some(keep) => keep,
+            // Stray comments, nothing to write
+            None => continue,
+        };
+
+        columns_removed += keep.iter().filter(|x| !**x).count();
+
+        if !keep.iter().any(|x| *x) {
+            continue;
         }
-    }
-}
 
-// For accumulating the alignment block before processing
-#[derive(Default)]
-pub struct AlignmentBlock {
-    reference: String,
-    lines: Vec<MafLine>,
-    seqid: String,
-    start: u64,
-}
+        let block = project_block(block, &keep);
+        out_fh.write_block(&block).map_err(io_error)?;
+    }
 
-impl AlignmentBlock {
-    pub fn add_line(&mut self, line: MafLine) {
-        self.lines.push(line);
+    // Empty input, still write a valid MAF
+    if !wrote_header {
+        out_fh.write_header(maf_parser.header()).map_err(io_error)?;
     }
 
-    pub fn remove_ref_indels(&self) {
-        let mut columns_to_remove: Vec<usize> = Vec::new();
-
-        let reference = &self.lines[0];
-        if let MafLine::SequenceLine(_, _, _, _, _, _, reference_text) = reference {
-            for (i, line) in self.lines.iter().enumerate() {
-                if let MafLine::SequenceLine(_, _, _, _, _, _, text) = line {
-                    for (j, (ref_base, base)) in
-                        reference_text.chars().zip(text.chars()).enumerate()
-                    {
-                        if ref_base == '-' || base == '-' {
-                            columns_to_remove.push(j);
-                        }
-                    }
+    out_fh.flush().map_err(io_error)?;
+
+    Ok(RemoveRefIndelsSummary {
+        columns_removed,
+        skipped: skip.skipped,
+    })
+}
+
+// Mask of the alignment columns to keep, or None if the block has no s lines
+fn columns_to_keep(block: &[MafLine], all_gaps: bool) -> Option<Vec<bool>> {
+    let mut rows = block.iter().filter_map(|line| match line {
+        MafLine::SequenceLine(seq) => Some(seq),
+        _ => None,
+    });
+
+    let reference = rows.next()?;
+    let mut keep: Vec<bool> = reference.text.bytes().map(|b| b != b'-').collect();
+
+    if all_gaps {
+        for seq in rows {
+            for (keep, base) in keep.iter_mut().zip(seq.text.bytes()) {
+                if base == b'-' {
+                    *keep = false;
                 }
             }
-        } else {
-            unreachable!();
         }
+    }
+
+    Some(keep)
+}
 
-        // Remove the columns from the alignment block
-        for line in self.lines.iter() {
-            if let MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text) =
-                line
-            {
-                let new_text: String = text
-                    .chars()
-                    .enumerate()
-                    .filter(|(i, _)| !columns_to_remove.contains(i))
-                    .map(|(_, c)| c)
-                    .collect();
-                println!("{}", new_text);
+fn project_block(block: Vec<MafLine>, keep: &[bool]) -> Vec<MafLine> {
+    let mut projected = Vec::with_capacity(block.len());
+
+    // i and q lines belong to the s line before them
+    let mut dropped_src: Option<String> = None;
+
+    for line in block {
+        match line {
+            MafLine::SequenceLine(mut seq) => {
+                seq.retain_columns(keep);
+                if seq.size == 0 {
+                    dropped_src = Some(seq.src);
+                } else {
+                    dropped_src = None;
+                    projected.push(MafLine::SequenceLine(seq));
+                }
+            }
+            MafLine::QualityLine(mut q) => {
+                if dropped_src.as_ref() != Some(&q.src) {
+                    q.quality = q
+                        .quality
+                        .chars()
+                        .zip(keep)
+                        .filter(|(_, keep)| **keep)
+                        .map(|(c, _)| c)
+                        .collect();
+                    projected.push(MafLine::QualityLine(q));
+                }
+            }
+            MafLine::InfoLine(i) => {
+                if dropped_src.as_ref() != Some(&i.src) {
+                    projected.push(MafLine::InfoLine(i));
+                }
+            }
+            line => {
+                dropped_src = None;
+                projected.push(line);
             }
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.lines.len()
+    projected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/ref_indels.maf");
+
+    // The output of remove_ref_indels on the fixture, and the number of columns removed
+    fn project(name: &str, all_gaps: bool) -> (String, usize) {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("projected");
+        let output = output.to_str().unwrap();
+
+        let summary = remove_ref_indels(MAF, output, all_gaps, &[], false).unwrap();
+        let projected = std::fs::read_to_string(format!("{}.maf", output)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (projected, summary.columns_removed)
+    }
+
+    #[test]
+    fn reference_gaps() {
+        // panTro6 only has bases in the reference insertion, and is dropped with its q and i
+        // lines. mm10 is on the minus strand, and starts after the bases removed before its
+        // first one kept.
+        let (projected, removed) = project("ref_gaps", false);
+        assert_eq!(removed, 5);
+        assert_eq!(
+            projected,
+            "##maf version=1\n\n\
+             a score=1.0\n\
+             s hg38.chr1 10  5 + 100 ACGTA\n\
+             s mm10.chr5 20  5 -  80 ACGTA\n\
+             q mm10.chr5             98543\n\
+             i mm10.chr5 C 0 C 0\n\
+             e rn6.chr2   5 10 + 200 I\n\n\
+             a score=2.0\n\
+             s hg38.chr1 15 2 + 100 AC\n\
+             s mm10.chr5 29 2 -  80 AC\n\n\
+             a score=3.0\n\
+             s hg38.chr1 17 3 + 100 ACG\n\
+             s mm10.chr5 31 2 -  80 A-G\n\n"
+        );
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.lines.is_empty()
+    #[test]
+    fn all_gaps() {
+        // Every column of the first block has a gap
+        let (projected, removed) = project("all_gaps", true);
+        assert_eq!(removed, 11);
+        assert_eq!(
+            projected,
+            "##maf version=1\n\n\
+             a score=2.0\n\
+             s hg38.chr1 15 2 + 100 AC\n\
+             s mm10.chr5 29 2 -  80 AC\n\n\
+             a score=3.0\n\
+             s hg38.chr1 17 2 + 100 AG\n\
+             s mm10.chr5 31 2 -  80 AG\n\n"
+        );
     }
 }
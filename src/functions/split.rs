@@ -0,0 +1,252 @@
+//! Split a MAF file into one file per reference sequence
+
+use crate::parsers::*;
+use crate::{OxidMafError, SkipMalformed};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// What [`split`] wrote
+#[derive(Debug, Default)]
+pub struct SplitSummary {
+    pub blocks: u64,
+    /// Number of output files, one per reference sequence
+    pub files: usize,
+    /// Malformed blocks skipped, with `skip_malformed`
+    pub skipped: Vec<OxidMafError>,
+}
+
+/// Stream `input` and write every alignment block to `<output_path>/<reference seqid>.maf`.
+///
+/// The reference is the first `s` line of each block. The `##maf` header of the input is copied
+/// to the top of every output file. Reference seqids that are not plain file names (containing
+/// a path separator, or `.` or `..`) are an error, rather than writing outside `output_path`.
+///
+/// At most `max_open_files` handles are kept open at once (and at least one); when the cap is
+/// reached the least recently used file is flushed and closed, and reopened in append mode if it
+/// is needed again. This keeps memory and file descriptor use bounded on assemblies with many
+/// scaffolds.
+pub fn split(
+    input: &str,
+    output_path: &str,
+    max_open_files: usize,
+    genome_names: &[String],
+    skip_malformed: bool,
+) -> Result<SplitSummary, OxidMafError> {
+    let mut parser = MafParser::from_path(input)?.with_genome_names(genome_names);
+    let mut skip = SkipMalformed::new(skip_malformed);
+
+    std::fs::create_dir_all(output_path).map_err(|e| OxidMafError::io(output_path, e))?;
+
+    let mut outputs = SplitOutputs::new(Path::new(output_path), max_open_files.max(1));
+    let mut blocks = 0;
+
+    while let Some(block) = parser.next() {
+        let block = match skip.check(block)? {
+            Some(block) => block,
+            None => continue,
+        };
+
+        let reference = block.iter().find_map(|line| match line {
+            MafLine::SequenceLine(seq) => Some(seq.contig.clone()),
+            _ => None,
+        });
+
+        // Stray comments, nothing to write
+        let reference = match reference {
+            Some(reference) => reference,
+            None => continue,
+        };
+        if reference.is_empty()
+            || reference == "."
+            || reference == ".."
+            || reference.contains(['/', '\\'])
+        {
+            return Err(OxidMafError::invalid(
+                input,
+                format!(
+                    "Reference seqid '{}' cannot be used as a file name",
+                    reference
+                ),
+            ));
+        }
+
+        // The header is written when each file is created
+        outputs.write_block(&reference, parser.header(), &block)?;
+        blocks += 1;
+    }
+
+    outputs.flush_all()?;
+
+    Ok(SplitSummary {
+        blocks,
+        files: outputs.created.len(),
+        skipped: skip.skipped,
+    })
+}
+
+/// Output handles keyed by reference seqid, limited to a maximum number of open files
+struct SplitOutputs {
+    output_path: PathBuf,
+    max_open_files: usize,
+    // With the value of `uses` when each was last written to
+    open: HashMap<String, (MafWriter<File>, u64)>,
+    // Blocks written, to find the least recently used file
+    uses: u64,
+    // Files created during this run, these are appended to (not truncated) when reopened
+    created: HashSet<String>,
+}
+
+impl SplitOutputs {
+    fn new(output_path: &Path, max_open_files: usize) -> Self {
+        SplitOutputs {
+            output_path: output_path.to_path_buf(),
+            max_open_files,
+            open: HashMap::new(),
+            uses: 0,
+            created: HashSet::new(),
+        }
+    }
+
+    fn path(&self, seqid: &str) -> PathBuf {
+        self.output_path.join(format!("{}.maf", seqid))
+    }
+
+    fn write_block(
+        &mut self,
+        seqid: &str,
+        header: &[MafLine],
+        block: &[MafLine],
+    ) -> Result<(), OxidMafError> {
+        let path = self.path(seqid);
+        let io_error = |e| OxidMafError::io(&path.to_string_lossy(), e);
+
+        if !self.open.contains_key(seqid) {
+            if self.open.len() >= self.max_open_files {
+                let oldest = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(seqid, _)| seqid.clone());
+                if let Some(oldest) = oldest {
+                    let (mut fh, _) = self.open.remove(&oldest).unwrap();
+                    fh.flush()
+                        .map_err(|e| OxidMafError::io(&self.path(&oldest).to_string_lossy(), e))?;
+                }
+            }
+
+            let fh = if self.created.contains(seqid) {
+                OpenOptions::new().append(true).open(&path)
+            } else {
+                File::create(&path)
+            };
+            let mut fh = MafWriter::new(fh.map_err(io_error)?);
+
+            if self.created.insert(seqid.to_string()) {
+                fh.write_header(header).map_err(io_error)?;
+            }
+
+            self.open.insert(seqid.to_string(), (fh, 0));
+        }
+
+        self.uses += 1;
+        let (fh, used) = self.open.get_mut(seqid).unwrap();
+        *used = self.uses;
+        fh.write_block(block).map_err(io_error)
+    }
+
+    fn flush_all(&mut self) -> Result<(), OxidMafError> {
+        for (seqid, (fh, _)) in self.open.iter_mut() {
+            let path = self.output_path.join(format!("{}.maf", seqid));
+            fh.flush()
+                .map_err(|e| OxidMafError::io(&path.to_string_lossy(), e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn more_contigs_than_open_files() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_split_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.maf");
+        let input = input.to_str().unwrap();
+
+        // Three rounds of blocks on five contigs, in turn
+        let mut maf = "##maf version=1 scoring=N/A\n\n".to_string();
+        for round in 0..3 {
+            for contig in 1..=5 {
+                maf.push_str(&format!(
+                    "a score={}\ns hg38.chr{} {} 2 + 100 AC\ns mm10.chr1 0 2 + 100 AC\n\n",
+                    round,
+                    contig,
+                    2 * round
+                ));
+            }
+        }
+        std::fs::write(input, maf).unwrap();
+
+        // Files are closed and reopened, twice into the same directory, then with no cap
+        let capped = dir.join("capped");
+        let capped = capped.to_str().unwrap();
+        let summary = split(input, capped, 2, &[], false).unwrap();
+        assert_eq!((summary.blocks, summary.files), (15, 5));
+        let summary = split(input, capped, 0, &[], false).unwrap();
+        assert_eq!((summary.blocks, summary.files), (15, 5));
+        let uncapped = dir.join("uncapped");
+        let uncapped = uncapped.to_str().unwrap();
+        split(input, uncapped, 512, &[], false).unwrap();
+
+        let read = |dir: &str, contig: usize| {
+            std::fs::read_to_string(Path::new(dir).join(format!("chr{}.maf", contig))).unwrap()
+        };
+        let outputs: Vec<(String, String)> = (1..=5)
+            .map(|x| (read(capped, x), read(uncapped, x)))
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for (capped, uncapped) in outputs.iter() {
+            assert_eq!(capped, uncapped);
+        }
+        assert_eq!(
+            outputs[1].0,
+            "##maf version=1 scoring=N/A\n\n\
+             a score=0\n\
+             s hg38.chr2 0 2 + 100 AC\n\
+             s mm10.chr1 0 2 + 100 AC\n\n\
+             a score=1\n\
+             s hg38.chr2 2 2 + 100 AC\n\
+             s mm10.chr1 0 2 + 100 AC\n\n\
+             a score=2\n\
+             s hg38.chr2 4 2 + 100 AC\n\
+             s mm10.chr1 0 2 + 100 AC\n\n"
+        );
+    }
+    #[test]
+    fn seqids_must_be_file_names() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_split_names_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.maf");
+        let input = input.to_str().unwrap();
+        let output = dir.join("split");
+        let output = output.to_str().unwrap();
+
+        for contig in ["../escaped", "..", "a/b"] {
+            let maf = format!("##maf version=1\n\na\ns hg38.{} 0 2 + 100 AC\n", contig);
+            std::fs::write(input, maf).unwrap();
+            let e = split(input, output, 2, &[], false).unwrap_err();
+            assert!(
+                e.to_string().contains("cannot be used as a file name"),
+                "{}",
+                e
+            );
+        }
+        assert!(!dir.join("escaped.maf").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
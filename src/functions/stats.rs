@@ -0,0 +1,95 @@
+//! Per block statistics, and duplicated reference rows
+
+use crate::parsers::*;
+
+use std::collections::HashMap;
+
+/// Statistics of one alignment block, from [`block_stats`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockStats {
+    /// `species:contig:start:size` of the reference (first row)
+    pub name: String,
+    /// Ungapped length of the reference
+    pub length: u64,
+    /// Number of distinct species
+    pub species: usize,
+    /// Number of species with more than one row
+    pub duplicated_species: usize,
+    /// Gaps in all rows
+    pub total_gaps: u64,
+    /// Gaps per reference base per species
+    pub gap_density: f64,
+}
+
+/// Statistics of an alignment block, `None` if it has no `s` lines
+pub fn block_stats(block: &[MafLine]) -> Option<BlockStats> {
+    let mut rows = block.iter().filter_map(|line| match line {
+        MafLine::SequenceLine(seq) => Some(seq),
+        _ => None,
+    });
+
+    let reference = rows.next()?;
+
+    // How often each species occurs
+    let mut species_counts: HashMap<&str, u16> = HashMap::new();
+    let mut total_gaps = 0;
+    for seq in std::iter::once(reference).chain(rows) {
+        *species_counts.entry(seq.species.as_str()).or_insert(0) += 1;
+        total_gaps += seq.text.len() as u64 - seq.ungapped_len();
+    }
+
+    let length = reference.size;
+    let species = species_counts.len();
+
+    Some(BlockStats {
+        name: format!(
+            "{}:{}:{}:{}",
+            reference.species, reference.contig, reference.start, reference.size
+        ),
+        length,
+        species,
+        duplicated_species: species_counts.values().filter(|x| **x > 1).count(),
+        total_gaps,
+        gap_density: total_gaps as f64 / length as f64 / species as f64,
+    })
+}
+
+/// Number of gaps in the reference (first) row of a block
+pub fn reference_gaps(block: &[MafLine]) -> u64 {
+    match block.iter().find(|x| x.is_seqline()) {
+        Some(MafLine::SequenceLine(seq)) => seq.text.len() as u64 - seq.ungapped_len(),
+        _ => 0,
+    }
+}
+
+/// Rows of a block with the same source sequence as the reference (first) row, other than the
+/// reference itself: their number and total aligned length
+pub fn duplicate_reference_rows(block: &[MafLine]) -> (u64, u64) {
+    let mut rows = block.iter().filter_map(|line| match line {
+        MafLine::SequenceLine(seq) => Some(seq),
+        _ => None,
+    });
+
+    let reference = match rows.next() {
+        Some(reference) => reference,
+        None => return (0, 0),
+    };
+
+    rows.filter(|x| x.src == reference.src)
+        .fold((0, 0), |(count, length), x| {
+            (count + 1, length + x.text.len() as u64)
+        })
+}
+
+/// Whether any row other than the reference (first) row is from the reference species
+pub fn has_duplicate_reference(block: &[MafLine]) -> bool {
+    let mut rows = block.iter().filter_map(|line| match line {
+        MafLine::SequenceLine(seq) => Some(seq),
+        _ => None,
+    });
+
+    match rows.next() {
+        Some(reference) => rows.any(|x| x.species == reference.species),
+        None => false,
+    }
+}
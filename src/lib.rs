@@ -0,0 +1,28 @@
+//! Reading, writing and processing of MAF and TAF multiple alignments, and annotation of VCF
+//! files from them
+//!
+//! [`parsers`] has the readers and writers: [`parsers::maf`], [`parsers::taffy`] (TAF) and
+//! [`parsers::vcf42`], along with indexes, BGZF and GERP++ and conservation tracks. [`functions`]
+//! has the operations behind the `oxid_maf` command line tool, as iterators (e.g.
+//! [`functions::extract_interval`] and [`functions::GerpSites`]), or functions writing output
+//! files and returning a summary of what they wrote (e.g. [`functions::split`]). None of them
+//! print anything or exit: errors are returned as [`OxidMafError`], and with `skip_malformed`,
+//! the errors of the malformed records skipped are kept in the summary (see [`SkipMalformed`]).
+//!
+//! ```
+//! use oxid_maf::functions::block_stats;
+//! use oxid_maf::parsers::MafParser;
+//!
+//! let maf = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.maf");
+//! for block in MafParser::from_path(maf)? {
+//!     let stats = block_stats(&block?).unwrap();
+//!     assert!(stats.species > 0);
+//! }
+//! # Ok::<(), oxid_maf::OxidMafError>(())
+//! ```
+
+mod error;
+pub mod functions;
+pub mod parsers;
+
+pub use error::*;
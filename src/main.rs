@@ -6,14 +6,13 @@
 // use bevy_tasks::TaskPool;
 use clap::{Parser, Subcommand};
 
-use std::collections::HashMap;
-use std::io::BufRead;
-use std::io::Write;
+use oxid_maf::functions;
+use oxid_maf::parsers::*;
+use oxid_maf::OxidMafError;
 
-mod functions;
-mod parsers;
-
-pub use parsers::*;
+use std::fs::File;
+use std::io::{BufWriter, StdoutLock, Write};
+use std::path::Path;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,6 +20,15 @@ pub use parsers::*;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Comma separated list of genome names, for genomes whose names contain dots (e.g. Homo.sapiens).
+    /// Sequence names are otherwise split into genome and contig on the first dot.
+    #[arg(long, global = true, value_delimiter = ',')]
+    genome_names: Vec<String>,
+
+    /// Log malformed blocks and records to stderr and continue, rather than stopping
+    #[arg(long, global = true)]
+    skip_malformed: bool,
 }
 
 #[derive(Subcommand)]
@@ -32,30 +40,91 @@ enum Commands {
     #[command(about = "Remove Alignment Blocks with Duplicate Reference Entries")]
     RemoveDupeRefBlocks { input: String },
     #[command(
-        about = "Extract interval containing a given position. Prints to stdout. Currently only accepts specific locations (Chr1:1234) not yet ranges."
+        about = "Extract the alignment of an interval (Chr1:1000-2000 or Chr1:1234) or the intervals in a BED file, trimming blocks to the interval. Prints MAF or FASTA to stdout."
     )]
     ExtractInterval {
+        /// MAF or TAF file
         input: String,
+        /// Species the query coordinates refer to
         species: String,
-        query: String,
+        /// Region as chr:start-end (1-based, inclusive) or chr:pos
+        #[arg(required_unless_present = "bed")]
+        query: Option<String>,
+        /// BED file of regions to extract (0-based, half-open)
+        #[arg(short, long)]
+        bed: Option<String>,
+        /// Print FASTA instead of MAF
+        #[arg(short, long)]
+        fasta: bool,
+    },
+    #[command(
+        about = "Index a plain or bgzipped MAF file by reference coordinate (<input>.mafi), for extract-interval and annotate-ancestral-allele"
+    )]
+    Index { input: String },
+    #[command(about = "Index a plain or bgzipped TAF file (<input>.tai), as taffy index does")]
+    IndexTaf {
+        input: String,
+        /// Approximate number of reference bases between index entries
+        #[arg(short, long, default_value_t = 10000)]
+        block_size: u64,
+    },
+    #[command(about = "Convert MAF to TAF, optionally bgzipped and indexed (<output>.tai)")]
+    MafToTaf {
+        maf: String,
+        output: String,
+        /// Compress the output with bgzip
+        #[arg(short = 'z', long)]
+        bgzip: bool,
+        /// Run length encode the bases of each column
+        #[arg(short, long)]
+        run_length_encode: bool,
+        /// Repeat the coordinates of every row at least this many columns apart, 0 for never
+        #[arg(short = 's', long, default_value_t = 10000)]
+        repeat_coordinates: usize,
+        /// Also write <output>.tai
+        #[arg(short, long)]
+        index: bool,
+        /// Approximate number of reference bases between index entries
+        #[arg(short, long, default_value_t = 10000)]
+        block_size: u64,
+    },
+    #[command(about = "Convert TAF (plain, gzip or bgzipped) to MAF")]
+    TafToMaf { taf: String, output: String },
+    #[command(
+        about = "Split MAF File into one file per reference sequence (<output_path>/<seqid>.maf)"
+    )]
+    Split {
+        input: String,
+        output_path: String,
+        /// Maximum number of output files to keep open at once
+        #[arg(short, long, default_value_t = 512)]
+        max_open_files: usize,
     },
-    #[command(about = "Split MAF File")]
-    Split { input: String, output_path: String },
     #[command(
-        about = "Process GERP Scores from .maf, .rates, and .elems files and export to .tsv"
+        about = "Join genome-wide GERP++ .rates and .elems files, writing <output>.tsv, <output>.bedGraph and <output>.bed"
     )]
     ProcessGerp {
+        /// .fai index, or MAF file (contig lengths from the src_size of the reference rows, which
+        /// must cover every line of the rates)
         maf: String,
+        /// GERP++ rates, concatenated in the contig order of the MAF or .fai
         rates: String,
         elems: String,
         output: String,
     },
     #[command(about = "Generate stats for each alignment block, as tab separated values")]
-    Stats { maf: String },
-    #[command(about = "Extract SNP sites from MAF, optionally return the coordinates")]
+    Stats {
+        /// MAF or TAF file
+        input: String,
+    },
+    #[command(
+        about = "Extract SNP sites from MAF or TAF as a VCF with one sample per species (<output_prefix>.vcf), optionally return the coordinates"
+    )]
     ExtractSnps {
-        maf: String,
+        /// MAF or TAF file
+        input: String,
         output_prefix: String,
+        /// Also write the source coordinates of each species to <output_prefix>.coordinates.tsv
         #[arg(short, long)]
         coordinates: bool,
         // / Optional: Also extract bases at the given positions from the reference. File should be a tab separated file with the following columns: Chromosome, Position
@@ -63,34 +132,94 @@ enum Commands {
         // ref_positions: Option<String>,
     },
 
-    #[command(about = "Remove Ref Indels")]
-    RemoveRefIndels { maf: String, output_prefix: String },
+    #[command(
+        about = "Remove columns where the reference has a gap, writing <output_prefix>.maf in reference coordinates"
+    )]
+    RemoveRefIndels {
+        maf: String,
+        output_prefix: String,
+        /// Also remove columns where any species has a gap
+        #[arg(short, long)]
+        all_gaps: bool,
+    },
 
     #[command(
-        about = "Annotate VCF with Ancestral Allele (AA=..) using MAF file from ProgressiveCactus"
+        about = "Annotate VCF with Ancestral Allele (AA=..) using a MAF or TAF file from ProgressiveCactus"
     )]
     AnnotateAncestralAllele {
-        maf: String,
+        /// Indexed MAF (<alignment>.mafi) or TAF (<alignment>.tai) file
+        alignment: String,
         vcf: String,
         ancestors: String,
         output: String,
+        /// Comma separated outgroup species, also used to infer the ancestral allele
+        #[arg(long, value_delimiter = ',')]
+        outgroups: Vec<String>,
+        /// How to combine the ancestors and outgroups: first (the first with a base matching REF
+        /// or ALT), strict (all must agree), majority, or parsimony (outgroups weighted double)
+        #[arg(long, default_value = "first")]
+        mode: functions::AncestralMode,
+        /// Also write <output>.vcf, the input VCF with AA set on every record
+        #[arg(long)]
+        write_vcf: bool,
+        /// Swap REF and ALT where ALT is ancestral, recoding GTs and Number=R/G fields and dropping
+        /// Number=A fields (with --write-vcf)
+        #[arg(long, requires = "write_vcf")]
+        polarize: bool,
+    },
+    #[command(
+        about = "Annotate VCF with GERP++ (GERP_RS, GERP_ELEM, GERP_ELEM_P), phyloP (PHYLOP) and phastCons (PHASTCONS) scores, writing <output>.vcf"
+    )]
+    AnnotateConservation {
+        vcf: String,
+        output: String,
+        /// GERP++ rates, concatenated in the contig order of --lengths
+        #[arg(long)]
+        gerp_rates: Option<String>,
+        /// Genome-wide GERP++ constrained elements
+        #[arg(long)]
+        gerp_elems: Option<String>,
+        /// MAF file or .fai index with the contig lengths of the GERP++ rates. Defaults to the
+        /// ##contig lines of the VCF, which must then all have a length and be in the order of
+        /// the rates.
+        #[arg(long)]
+        lengths: Option<String>,
+        /// phyloP scores, as bedGraph or wig
+        #[arg(long)]
+        phylop: Option<String>,
+        /// phastCons scores, as bedGraph or wig
+        #[arg(long)]
+        phastcons: Option<String>,
     },
 }
 
 fn main() {
     let cli = Cli::parse();
+    let genome_names = &cli.genome_names;
+    let skip_malformed = cli.skip_malformed;
     match &cli.command {
-        Commands::Split { input, output_path } => {
-            println!("split was used")
+        Commands::Split {
+            input,
+            output_path,
+            max_open_files,
+        } => {
+            let summary = unwrap_or_exit(functions::split(
+                input,
+                output_path,
+                *max_open_files,
+                genome_names,
+                skip_malformed,
+            ));
+            report_skipped(&summary.skipped);
         }
         Commands::CountRefGaps { input } => {
-            count_ref_gaps(input);
+            count_ref_gaps(input, genome_names, skip_malformed);
         }
         Commands::CountDupeRefs { input } => {
-            count_dupe_refs(input);
+            count_dupe_refs(input, genome_names, skip_malformed);
         }
         Commands::RemoveDupeRefBlocks { input } => {
-            remove_dupe_ref_blocks(input);
+            remove_dupe_ref_blocks(input, genome_names, skip_malformed);
         }
         Commands::ProcessGerp {
             maf,
@@ -98,546 +227,432 @@ fn main() {
             elems,
             output,
         } => {
-            process_gerp(maf, rates, elems, output);
+            process_gerp(maf, rates, elems, output, genome_names, skip_malformed);
         }
         Commands::ExtractInterval {
             input,
             species,
             query,
+            bed,
+            fasta,
         } => {
-            extract_interval(input, species, query);
+            extract_interval(
+                input,
+                species,
+                query.as_deref(),
+                bed.as_deref(),
+                *fasta,
+                genome_names,
+                skip_malformed,
+            );
         }
-        Commands::Stats { maf } => {
-            stats(maf);
+        Commands::Index { input } => {
+            unwrap_or_exit(functions::index(input, genome_names));
         }
-        Commands::ExtractSnps {
+        Commands::IndexTaf { input, block_size } => {
+            unwrap_or_exit(functions::index_taf(input, *block_size));
+        }
+        Commands::MafToTaf {
             maf,
+            output,
+            bgzip,
+            run_length_encode,
+            repeat_coordinates,
+            index,
+            block_size,
+        } => {
+            let summary = unwrap_or_exit(functions::maf_to_taf(
+                maf,
+                output,
+                *bgzip,
+                *run_length_encode,
+                *repeat_coordinates,
+                index.then_some(*block_size),
+                skip_malformed,
+            ));
+            report_skipped(&summary.skipped);
+        }
+        Commands::TafToMaf { taf, output } => {
+            let summary = unwrap_or_exit(functions::taf_to_maf(taf, output, skip_malformed));
+            report_skipped(&summary.skipped);
+        }
+        Commands::Stats { input } => {
+            stats(input, genome_names, skip_malformed);
+        }
+        Commands::ExtractSnps {
+            input,
             output_prefix,
             coordinates,
         } => {
-            functions::extract_snps(maf, output_prefix, *coordinates);
+            let summary = unwrap_or_exit(functions::extract_snps(
+                input,
+                output_prefix,
+                *coordinates,
+                genome_names,
+                skip_malformed,
+            ));
+            report_skipped(&summary.skipped);
+
+            match summary.reference {
+                Some(reference) => {
+                    eprintln!("Extracted {} SNPs", summary.snps);
+                    if summary.other_reference_blocks > 0 {
+                        eprintln!(
+                            "Skipped {} blocks not starting with the reference ({})",
+                            summary.other_reference_blocks, reference
+                        );
+                    }
+                }
+                None => eprintln!("No alignment blocks found in {}", input),
+            }
         }
         Commands::AnnotateAncestralAllele {
-            maf,
+            alignment,
             vcf,
             ancestors,
             output,
+            outgroups,
+            mode,
+            write_vcf,
+            polarize,
+        } => {
+            let summary = unwrap_or_exit(functions::annotate_ancestral_allele(
+                alignment,
+                vcf,
+                ancestors,
+                outgroups,
+                *mode,
+                output,
+                *write_vcf,
+                *polarize,
+                genome_names,
+                skip_malformed,
+            ));
+            report_skipped(&summary.skipped);
+
+            println!("Done");
+            println!("Ancestral matches: {}", summary.ancestral_matches);
+            println!("ALT ancestral: {}", summary.alt_ancestral);
+            println!("Ancestral missing: {}", summary.ancestral_missing);
+            println!("Ancestral by majority: {}", summary.ancestral_by_majority);
+            println!("High confidence: {}", summary.high_confidence);
+            println!("Unsupported variants: {}", summary.unsupported);
+            let total = summary.ancestral_matches + summary.alt_ancestral;
+            if total > 0 {
+                let fraction = summary.ancestral_matches as f64 / total as f64;
+                println!("Fraction ancestral matches: {}", fraction);
+            }
+        }
+        Commands::AnnotateConservation {
+            vcf,
+            output,
+            gerp_rates,
+            gerp_elems,
+            lengths,
+            phylop,
+            phastcons,
         } => {
-            functions::annotate_ancestral_allele(maf, vcf, ancestors, output);
+            let summary = unwrap_or_exit(functions::annotate_conservation(
+                vcf,
+                output,
+                gerp_rates.as_deref(),
+                gerp_elems.as_deref(),
+                lengths.as_deref(),
+                phylop.as_deref(),
+                phastcons.as_deref(),
+                genome_names,
+                skip_malformed,
+            ));
+            report_skipped(&summary.skipped);
+
+            println!("Records: {}", summary.records);
+            println!("GERP RS scores: {}", summary.gerp_rs);
+            println!("In GERP constrained elements: {}", summary.gerp_elements);
+            println!("phyloP scores: {}", summary.phylop);
+            println!("phastCons scores: {}", summary.phastcons);
         }
-        Commands::RemoveRefIndels { maf, output_prefix } => {
-            functions::remove_ref_indels(maf, output_prefix);
+        Commands::RemoveRefIndels {
+            maf,
+            output_prefix,
+            all_gaps,
+        } => {
+            let summary = unwrap_or_exit(functions::remove_ref_indels(
+                maf,
+                output_prefix,
+                *all_gaps,
+                genome_names,
+                skip_malformed,
+            ));
+            report_skipped(&summary.skipped);
+            eprintln!("Removed {} columns", summary.columns_removed);
         }
     }
 }
 
-fn stats(input: &String) {
-    let file = std::fs::File::open(input).unwrap();
-    let parser = maf_parser(file);
-
-    let mut block_name = String::new();
-    let mut block_length;
-
-    let mut seq_gap_count: HashMap<String, u64> = Default::default();
-
-    let mut species_counts: HashMap<String, u16> = Default::default();
-
-    println!("Block\tLength\tSpecies\tDuplicated Species\tTotal Gaps\tGap Density");
-
-    for block in parser {
-        // Alignment block is name of the reference sequence, start position, and length
-        block_name.clear();
-
-        // Count number of sequences in each block, and how often they occur
-        species_counts.clear();
-
-        // Count gaps
-        seq_gap_count.clear();
-
-        // Block Length
-        block_length = 0;
-
-        for line in block.iter() {
-            match line {
-                MafLine::SequenceLine(species, seqid, start, length, _strand, _src_size, text) => {
-                    if block_name.is_empty() {
-                        block_name.push_str(species);
-                        block_name.push_str(":");
-                        block_name.push_str(seqid);
-                        block_name.push_str(":");
-                        block_name.push_str(&start.to_string());
-                        block_name.push_str(":");
-                        block_name.push_str(&length.to_string());
-
-                        block_length = *length;
-                    }
-
-                    let species = seqid.split(".").next().unwrap().to_string();
-                    // TODO: String clone
-                    let count = species_counts.entry(species.clone()).or_insert(0);
-                    *count += 1;
-
-                    // Count gaps in each line
-                    let mut gap_count_line = 0;
-                    for c in text.chars() {
-                        if c == '-' {
-                            gap_count_line += 1;
-                        }
-                    }
-
-                    // TODO: String clone
-                    let count = seq_gap_count.entry(seqid.clone()).or_insert(0);
-                    *count += gap_count_line;
-                }
-                _ => {}
+/// Unwrap a result, reporting the error and exiting on failure
+fn unwrap_or_exit<T>(result: Result<T, OxidMafError>) -> T {
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            if e.is_malformed() {
+                eprintln!("Use --skip-malformed to skip malformed records and continue");
             }
+            std::process::exit(1);
         }
-
-        // Print stats per block
-        let duplicated_species = species_counts.iter().filter(|(_, v)| **v > 1).count();
-        let total_gaps = seq_gap_count.values().sum::<u64>();
-
-        // Gap density
-        let gap_density = total_gaps as f64 / block_length as f64 / species_counts.len() as f64;
-        let gap_density = format!("{:.4}", gap_density);
-
-        println!(
-            "{}\t{}\t{}\t{}\t{}\t{}",
-            block_name,
-            block_length,
-            species_counts.len(),
-            duplicated_species,
-            total_gaps,
-            gap_density
-        );
     }
 }
 
-/* #[command(about = "Extract interval containing a given position. Prints to stdout")]
-ExtractInterval {
-    input: String,
-    species: String,
-    query: String,
-}, */
-fn extract_interval(input: &String, species: &String, query: &String) {
-    // Get write buffer (so we don't flush prematurely)
-    let stdout = std::io::stdout();
-    let mut out_fh = std::io::BufWriter::new(stdout.lock());
-
-    // Open file
-    let file = std::fs::File::open(input).unwrap();
-    let parser = maf_parser(file);
-
-    // Parse query
-    // let chrom = query.split(":").next().unwrap();
-    let position = query.split(":").nth(1).unwrap().parse::<u64>().unwrap();
-
-    // Iterate through blocks
-    // If the interval matches, print out the entire block and flush
-    for block in parser {
-        for line in block.iter() {
-            match line {
-                MafLine::SequenceLine(
-                    seqspecies,
-                    seqid,
-                    start,
-                    length,
-                    _strand,
-                    _src_size,
-                    _text,
-                ) => {
-                    let seqchr = seqid.split(":").next().unwrap();
-
-                    if seqspecies == species && seqchr == seqid {
-                        if *start <= position && position <= start + length {
-                            for line in block.iter() {
-                                if line.is_seqline() {
-                                    out_fh.write(line.fasta_out().as_bytes()).unwrap();
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {}
-            }
+/// Unwrap a parser result. With `skip_malformed`, malformed records are logged to stderr and
+/// skipped (returns `None`). Any other error is reported and exits the program.
+fn unwrap_or_skip<T>(result: Result<T, OxidMafError>, skip_malformed: bool) -> Option<T> {
+    match result {
+        Err(e) if skip_malformed && e.is_malformed() => {
+            eprintln!("Skipping malformed record: {}", e);
+            None
         }
+        result => Some(unwrap_or_exit(result)),
     }
 }
 
-fn count_ref_gaps(input: &String) {
-    // Open file
-    let file = std::fs::File::open(input).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let mut lines = reader.lines();
-
-    let mut count = 0;
-    let mut state;
-    let mut seqcount = 0;
+/// Log the malformed records skipped by a function run with `--skip-malformed`
+fn report_skipped(skipped: &[OxidMafError]) {
+    for e in skipped {
+        eprintln!("Skipped malformed record: {}", e);
+    }
+}
 
-    while let Some(line) = lines.next() {
-        let line = line.unwrap();
+fn stats(input: &str, genome_names: &[String], skip_malformed: bool) {
+    let source = unwrap_or_exit(open_alignment(input, genome_names));
 
-        // Match on the first character
-        match line.chars().next() {
-            Some('#') => {
-                state = MafReadState::Comment;
-                continue;
-            }
-            Some('a') => {
-                state = MafReadState::AlignmentBlockLine;
-                seqcount = 0;
-                continue;
-            }
-            Some('s') => {
-                state = MafReadState::SequenceLine;
-                seqcount += 1;
-            }
-            None => {
-                state = MafReadState::BlankLine;
-                continue;
-            }
-            _ => {
-                state = MafReadState::BlankLine;
-                continue;
-            }
-        }
+    println!("Block\tLength\tSpecies\tDuplicated Species\tTotal Gaps\tGap Density");
 
-        if seqcount == 1 {
-            let mut gapcount = 0;
-            for c in line.chars() {
-                if c == '-' {
-                    gapcount += 1;
-                }
-            }
-            count += gapcount;
+    for block in source.filter_map(|b| unwrap_or_skip(b, skip_malformed)) {
+        if let Some(stats) = functions::block_stats(&block) {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{:.4}",
+                stats.name,
+                stats.length,
+                stats.species,
+                stats.duplicated_species,
+                stats.total_gaps,
+                stats.gap_density
+            );
         }
     }
-
-    println!("Count: {}", count);
 }
 
-// https://genome.ucsc.edu/FAQ/FAQformat.html#format5
-pub enum MafReadState {
-    Comment,
-    AlignmentBlockLine,
-    SequenceLine,
-    BlankLine,
+fn count_ref_gaps(input: &str, genome_names: &[String], skip_malformed: bool) {
+    let parser = unwrap_or_exit(MafParser::from_path(input)).with_genome_names(genome_names);
+
+    let count: u64 = parser
+        .filter_map(|b| unwrap_or_skip(b, skip_malformed))
+        .map(|block| functions::reference_gaps(&block))
+        .sum();
+
+    println!("Count: {}", count);
 }
 
-fn count_dupe_refs(input: &String) {
-    // Open file
-    let file = std::fs::File::open(input).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let mut lines = reader.lines();
+fn count_dupe_refs(input: &str, genome_names: &[String], skip_malformed: bool) {
+    let parser = unwrap_or_exit(MafParser::from_path(input)).with_genome_names(genome_names);
 
     let mut count = 0;
-    let mut state = MafReadState::BlankLine;
-    let mut seqcount = 0;
-    let mut reference = String::new();
     let mut seqlengths = 0;
-
-    while let Some(line) = lines.next() {
-        let line = line.unwrap();
-
-        // Match on the first character
-        match line.chars().next() {
-            Some('#') => {
-                state = MafReadState::Comment;
-                continue;
-            }
-            Some('a') => {
-                state = MafReadState::AlignmentBlockLine;
-                seqcount = 0;
-                continue;
-            }
-            Some('s') => {
-                state = MafReadState::SequenceLine;
-                seqcount += 1;
-            }
-            None => {
-                state = MafReadState::BlankLine;
-                continue;
-            }
-            _ => {
-                state = MafReadState::BlankLine;
-                continue;
-            }
-        }
-
-        if seqcount == 1 {
-            // Get reference ID
-            // Split: s Kakapo.S18                                14671 248
-            let mut split = line.split_whitespace();
-            reference = split.nth(1).unwrap().to_string();
-        } else if seqcount > 1 {
-            // Get sequence ID
-            // Split: s Kakapo.S18                                14671 248
-            let mut split = line.split_whitespace();
-            let seqid = split.nth(1).unwrap().to_string();
-
-            if seqid == reference {
-                // println!("{:#?}", split);
-                count += 1;
-                seqlengths += split.nth(4).unwrap().len();
-            }
-        }
+    for block in parser.filter_map(|b| unwrap_or_skip(b, skip_malformed)) {
+        let (rows, length) = functions::duplicate_reference_rows(&block);
+        count += rows;
+        seqlengths += length;
     }
 
     println!("Count: {}", count);
     println!("Length: {}", seqlengths);
 }
 
-// remove_dupe_ref_blocks
-fn remove_dupe_ref_blocks(input: &String) {
-    // Open file
-    let file = std::fs::File::open(input).unwrap();
-    let mut parser = maf_parser(file);
-    let mut reference = String::new();
-    let mut refcount = 0;
-    let mut seqcount = 0;
+fn remove_dupe_ref_blocks(input: &str, genome_names: &[String], skip_malformed: bool) {
+    let mut parser = unwrap_or_exit(MafParser::from_path(input)).with_genome_names(genome_names);
     let mut removed_count = 0;
 
-    'outer: while let Some(block) = parser.next() {
-        seqcount = 0;
-        refcount = 0;
-
-        for line in block.iter() {
-            match line {
-                MafLine::SequenceLine(speciesid, seqid, _, _, _, _, _) => {
-                    seqcount += 1;
-
-                    if seqcount == 1 {
-                        reference = speciesid.to_string();
-                    } else if seqcount > 1 && *speciesid == reference {
-                        removed_count += 1;
-                        continue 'outer;
-                    }
-                    // println!("{}, {}, {}", seqid, reference, seqcount);
-                }
-                _ => {}
-            }
+    let stdout = std::io::stdout();
+    let mut writer = MafWriter::new(stdout.lock());
+    let mut header_written = false;
+
+    while let Some(block) = parser.next() {
+        let block = match unwrap_or_skip(block, skip_malformed) {
+            Some(block) => block,
+            None => continue,
+        };
+
+        // Header is available once the first block has been read
+        if !header_written {
+            writer
+                .write_header(parser.header())
+                .expect("Unable to write output");
+            header_written = true;
         }
 
-        for line in block {
-            println!("{}", line);
+        if functions::has_duplicate_reference(&block) {
+            removed_count += 1;
+            continue;
         }
-        println!();
+
+        writer.write_block(&block).expect("Unable to write output");
     }
 
-    // Print to STDERR
-    // eprintln!("Removed {} blocks", removed_count);
-}
+    writer.flush().expect("Unable to write output");
 
-#[derive(Clone, Debug, PartialEq)]
-struct GERPElem {
-    region: String,
-    start: u64,
-    end: u64,
-    score: f32,
-    pval: f64,
-    expected: f64,
-    observed: f64,
-    length: f64,
+    // Print to STDERR
+    eprintln!("Removed {} blocks", removed_count);
 }
 
-// Handy one liner function to create GERPElem
-impl GERPElem {
-    fn new(
-        region: String,
-        start: u64,
-        end: u64,
-        score: f32,
-        pval: f64,
-        expected: f64,
-        observed: f64,
-        length: f64,
-    ) -> GERPElem {
-        GERPElem {
-            region,
-            start,
-            end,
-            score,
-            pval,
-            expected,
-            observed,
-            length,
-        }
+/// Print the alignment of each query region to stdout, as MAF or (with `fasta`) FASTA.
+///
+/// Regions are given in the coordinates of `species`, either as `query` (`chr:start-end`, 1-based
+/// and inclusive, or a single position `chr:pos`) or as a BED file (0-based, half-open), or both.
+/// See [`functions::extract_interval`].
+fn extract_interval(
+    input: &str,
+    species: &str,
+    query: Option<&str>,
+    bed: Option<&str>,
+    fasta: bool,
+    genome_names: &[String],
+    skip_malformed: bool,
+) {
+    let mut regions: Vec<(String, std::ops::Range<u64>)> = Vec::new();
+
+    if let Some(query) = query {
+        match functions::parse_region(query) {
+            Ok(x) => regions.push(x),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
     }
 
-    // Is given position within this element?
-    fn contains(&self, pos: u64) -> bool {
-        if pos >= self.start && pos <= self.end {
-            return true;
-        } else {
-            return false;
-        }
+    if let Some(bed) = bed {
+        regions.extend(unwrap_or_exit(functions::read_bed(bed)));
     }
-}
-
-// process_gerp(maf, rates, elems, output);
-fn process_gerp(maf: &String, rates: &String, elems: &String, output: &String) {
-    let maf_fh = std::fs::File::open(maf).expect("Unable to open maf file");
-    let rates_fh = std::fs::File::open(rates).expect("Unable to open rates file");
-    let elems_fh = std::fs::File::open(elems).expect("Unable to open elems file");
-    let output_fh = std::fs::File::create(output).expect("Unable to create file");
 
-    let mut rates_fh = std::io::BufReader::new(rates_fh);
-    let mut elems_fh = std::io::BufReader::new(elems_fh);
-    let mut output_fh = std::io::BufWriter::new(output_fh);
-
-    // Read first line of MAF file
-    let mut maf_parser = maf_parser(maf_fh);
-    let mut maf_block = maf_parser.next();
-
-    // If none, then we're done
-    if maf_block.is_none() {
-        return;
+    let mut blocks = unwrap_or_exit(functions::extract_interval(
+        input,
+        species,
+        regions,
+        genome_names,
+    ));
+
+    let index_path = format!("{}.mafi", input);
+    if !blocks.is_indexed() && input != "-" && Path::new(&index_path).exists() {
+        eprintln!(
+            "{} is not the reference of {}, reading the whole file",
+            species, index_path
+        );
     }
 
-    let mut maf_block = maf_block.unwrap();
-
-    // Get length of chromosome
-    let mut chrom_length = 0;
-    for line in maf_block.iter() {
-        match line {
-            // MafLine::SequenceLine(seqid, start, length, strand, src_size, text) => {
-            MafLine::SequenceLine(_, _, _, _, _, length, _) => {
-                chrom_length = *length;
-                break;
-            }
-            _ => {}
+    // Get write buffer (so we don't flush prematurely)
+    let stdout = std::io::stdout();
+    let mut out_fh = if fasta {
+        IntervalOutput::Fasta(BufWriter::new(stdout.lock()))
+    } else {
+        IntervalOutput::Maf(MafWriter::new(stdout.lock()), false)
+    };
+
+    while let Some(block) = blocks.next() {
+        if let Some(block) = unwrap_or_skip(block, skip_malformed) {
+            out_fh
+                .write(&block, blocks.header())
+                .expect("Unable to write to stdout");
         }
     }
 
-    // Count lines in rates file
-    let mut rates_count = 0;
-    // Borrow lines iterator
-    let mut lines = rates_fh.lines();
-    while let Some(_) = lines.next() {
-        rates_count += 1;
-    }
-
-    // GERP Sanity Check
-    println!(
-        "Rates Length: {} Chrom Length: {}",
-        rates_count, chrom_length
-    );
-    assert!(rates_count <= chrom_length, "Rates file length does not match chromosome length - This program expect GERP scores to be split by chromosome");
-
-    // Rewind file
-    let rates_fh = std::fs::File::open(rates).expect("Unable to open rates file");
-    let mut rates_fh = std::io::BufReader::new(rates_fh);
-
-    // Import elems file
-    let mut elems: Vec<GERPElem> = elems_fh
-        .lines()
-        .map(|line| {
-            let line = line.unwrap();
-            let split: Vec<&str> = line.split_whitespace().collect();
-
-            let region = split[0].to_string();
-            let start = split[1].parse::<u64>().unwrap();
-            let end = split[2].parse::<u64>().unwrap();
-            let score = split[3].parse::<f32>().unwrap();
-            let pval = split[4].parse::<f64>().unwrap();
-            let expected = split[5].parse::<f64>().unwrap();
-            let observed = split[6]
-                .parse::<f64>()
-                .expect(format!("Unable to parse observed value: {}", split[6]).as_str());
-            let length = split[7]
-                .parse::<f64>()
-                .expect(format!("Unable to parse length value: {}", split[7]).as_str());
-
-            GERPElem::new(region, start, end, score, pval, expected, observed, length)
-        })
-        .collect();
-
-    // Sort elems by start position
-    elems.sort_by(|a, b| a.start.cmp(&b.start));
-
-    let mut pos = 0;
-
-    // Iterate through rates file
-    let mut lines = rates_fh.lines();
-    let mut in_elem = false;
-    while let Some(line) = lines.next() {
-        pos += 1;
-        in_elem = false;
-
-        let line = line.unwrap();
-        let split: Vec<&str> = line.split_whitespace().collect();
-
-        let neutral_rate = split[0]
-            .parse::<f64>()
-            .expect(format!("Unable to parse neutral rate: {}", split[0]).as_str());
-        let score = split[1].parse::<f32>().unwrap();
-
-        // If both are zero skip
-        if neutral_rate == 0.0 && score == 0.0 {
-            continue;
-        }
-
-        // Find element that contains this position
-        let mut elem: Option<&GERPElem> = None;
-        let mut remove = Vec::new();
-        for i in 0..elems.len() {
-            let e = &elems[i];
-            if e.contains(pos) {
-                elem = Some(e);
-                in_elem = true;
-                break;
-            }
+    out_fh.flush().expect("Unable to write to stdout");
+}
 
-            // If pos is past end of element, remove it from vector
-            if pos > e.end {
-                remove.push(i);
-            }
+enum IntervalOutput<'a> {
+    // The flag is set once the header has been written
+    Maf(MafWriter<StdoutLock<'a>>, bool),
+    Fasta(BufWriter<StdoutLock<'a>>),
+}
 
-            // If pos is past end of chromosome, break
-            if pos > chrom_length {
-                break;
+impl IntervalOutput<'_> {
+    fn write(&mut self, block: &[MafLine], header: &[MafLine]) -> std::io::Result<()> {
+        match self {
+            IntervalOutput::Maf(out, wrote_header) => {
+                if !*wrote_header {
+                    out.write_header(header)?;
+                    *wrote_header = true;
+                }
+                out.write_block(block)
             }
-
-            // If pos is before start of element, break
-            if pos < e.start {
-                break;
+            IntervalOutput::Fasta(out) => {
+                for line in block.iter().filter(|x| x.is_seqline()) {
+                    out.write_all(line.fasta_out().as_bytes())?;
+                }
+                Ok(())
             }
         }
+    }
 
-        // Write to output
-        match elem {
-            Some(e) => {
-                writeln!(
-                    output_fh,
-                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                    pos,
-                    neutral_rate,
-                    score,
-                    in_elem,
-                    e.start,
-                    e.end,
-                    e.score,
-                    e.pval,
-                    e.expected,
-                    e.observed,
-                    e.length
-                )
-                .unwrap();
-            }
-            None => {
-                // Write to output, NA for element if not contained within a conserved element
-                writeln!(
-                    output_fh,
-                    "{}\t{}\t{}\t{}\t\t\t\t\t\t\t",
-                    pos, neutral_rate, score, in_elem
-                )
-                .unwrap();
-            }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            IntervalOutput::Maf(out, _) => out.flush(),
+            IntervalOutput::Fasta(out) => out.flush(),
         }
+    }
+}
 
-        // Remove elements that are before the current position
-        for i in remove.iter() {
-            elems.remove(*i);
-        }
+/// Join a GERP++ `.rates` file with the constrained elements of an `.elems` file, writing
+/// `<output>.tsv`, `<output>.bedGraph` and `<output>.bed` (see [`functions::process_gerp`]).
+///
+/// Contig lengths, which place each line of the rates file, are read from `lengths`: a `.fai`
+/// index, or a MAF file (see [`functions::contig_lengths`], which only covers the contigs with
+/// blocks). Rates files for several contigs must be concatenated in that order.
+fn process_gerp(
+    lengths: &str,
+    rates: &str,
+    elems: &str,
+    output: &str,
+    genome_names: &[String],
+    skip_malformed: bool,
+) {
+    let contigs = unwrap_or_exit(functions::contig_lengths(
+        lengths,
+        rates,
+        genome_names,
+        skip_malformed,
+    ));
+    let rates = unwrap_or_exit(GerpRatesReader::from_file(rates, contigs));
+    let elements = unwrap_or_exit(GerpElementReader::from_file(elems));
+
+    let create = |suffix: &str| {
+        let path = format!("{}.{}", output, suffix);
+        let file = unwrap_or_exit(File::create(&path).map_err(|e| OxidMafError::io(&path, e)));
+        BufWriter::new(file)
+    };
+
+    let summary = unwrap_or_exit(functions::process_gerp(
+        rates,
+        elements,
+        output,
+        create("tsv"),
+        create("bedGraph"),
+        create("bed"),
+        skip_malformed,
+    ));
+    report_skipped(&summary.skipped);
+
+    println!("Contigs: {}", summary.contigs);
+    for contig in summary.unplaced_contigs.iter() {
+        eprintln!(
+            "Warning: {} has constrained elements, but no length, so is skipped",
+            contig
+        );
+    }
+    if summary.missing_rates > 0 {
+        eprintln!(
+            "Warning: the rates file is {} lines shorter than the total length of the contigs",
+            summary.missing_rates
+        );
     }
+    println!("Scored positions: {}", summary.scored);
 }
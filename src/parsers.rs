@@ -1,7 +1,23 @@
-mod maf;
-mod taffy;
-mod vcf42;
+mod alignment;
+mod bgzf;
+mod gerp;
+mod input;
+pub mod maf;
+mod maf_index;
+mod maf_writer;
+mod taf_writer;
+pub mod taffy;
+mod track;
+pub mod vcf42;
 
+pub use alignment::*;
+pub use bgzf::*;
+pub use gerp::*;
+pub use input::*;
 pub use maf::*;
+pub use maf_index::*;
+pub use maf_writer::*;
+pub use taf_writer::*;
 pub use taffy::*;
+pub use track::*;
 pub use vcf42::*;
@@ -0,0 +1,431 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::BufRead;
+use std::sync::Arc;
+
+use super::{
+    open_input, AlignmentBlock, MafIndexedReader, MafLine, MafParser, MafReader, MafSequence,
+    TafAlignmentColumn, TafBlockIterator, TafParser, TaiIndex,
+};
+use crate::OxidMafError;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strand {
+    Plus,
+    Minus,
+}
+
+impl Display for Strand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strand::Plus => write!(f, "+"),
+            Strand::Minus => write!(f, "-"),
+        }
+    }
+}
+
+/// Where a position is, relative to an alignment block or column
+#[derive(Debug, Eq, PartialEq)]
+pub enum ContainsResult {
+    True,
+    Before,
+    After,
+    WrongChrom,
+}
+
+/// The base of one row of an [`AlignmentColumn`], and where it is in its source sequence
+///
+/// The names are shared by the columns of a MAF block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlignmentRow {
+    pub species: Arc<str>,
+    pub contig: Arc<str>,
+    pub strand: Strand,
+    /// Zero-based offset of the base, or for a gap, of the next base of the row. As for `start`
+    /// in MAF, minus strand offsets count from the end of the source sequence.
+    pub offset: u64,
+    /// Length of the source sequence, which is optional in TAF
+    pub src_size: Option<u64>,
+    /// As in the alignment text, `-` for a gap
+    pub base: u8,
+}
+
+impl AlignmentRow {
+    /// Zero-based forward strand position of the base, `None` for a gap, or a minus strand row
+    /// of unknown length
+    pub fn position(&self) -> Option<u64> {
+        if self.base == b'-' {
+            return None;
+        }
+        self.forward_offset()
+    }
+
+    // Forward strand position of the base, or the next base after a gap
+    fn forward_offset(&self) -> Option<u64> {
+        match self.strand {
+            Strand::Plus => Some(self.offset),
+            Strand::Minus => self
+                .src_size
+                .and_then(|len| len.checked_sub(self.offset + 1)),
+        }
+    }
+}
+
+/// One column of a MAF or TAF alignment, from [`AlignmentSource::next_column`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlignmentColumn {
+    /// One entry per row, the reference first. `None` for TAF rows whose coordinates are not
+    /// known, after seeking to a line that does not give them all.
+    pub rows: Vec<Option<AlignmentRow>>,
+}
+
+impl AlignmentColumn {
+    /// Zero-based forward strand position of the base of `row`, `None` for a gap or a row with
+    /// unknown coordinates
+    pub fn position(&self, row: usize) -> Option<u64> {
+        self.rows.get(row)?.as_ref()?.position()
+    }
+
+    /// The first row of `species` on `contig`
+    pub fn row_on(&self, species: &str, contig: &str) -> Option<usize> {
+        self.rows.iter().position(
+            |x| matches!(x, Some(row) if &*row.species == species && &*row.contig == contig),
+        )
+    }
+
+    /// The base (and row) of the first row of `species`
+    pub fn allele_for_species(&self, species: &str) -> Option<(usize, u8)> {
+        self.rows.iter().enumerate().find_map(|(i, x)| match x {
+            Some(row) if &*row.species == species => Some((i, row.base)),
+            _ => None,
+        })
+    }
+
+    /// Checks whether the base of the row of `species` on `contig` is at the zero-based forward
+    /// strand position `pos`, or whether `pos` is before or after it.
+    pub fn contains_pos(&self, species: &str, contig: &str, pos: u64) -> ContainsResult {
+        let row = match self.row_on(species, contig) {
+            Some(row) => &self.rows[row],
+            None => return ContainsResult::WrongChrom,
+        };
+        let row = row.as_ref().unwrap();
+
+        match row.position() {
+            Some(start) if pos == start => ContainsResult::True,
+            Some(start) if pos < start => ContainsResult::Before,
+            Some(_) => ContainsResult::After,
+            // A gap, compare with the next base of the row
+            None => match row.forward_offset() {
+                Some(next) if pos < next => ContainsResult::Before,
+                _ => ContainsResult::After,
+            },
+        }
+    }
+}
+
+impl From<TafAlignmentColumn> for AlignmentColumn {
+    fn from(column: TafAlignmentColumn) -> Self {
+        let rows = column
+            .coords
+            .into_iter()
+            .zip(column.column.alleles)
+            .map(|(coord, base)| {
+                coord.map(|coord| AlignmentRow {
+                    species: coord.species.into(),
+                    contig: coord.chrom.into(),
+                    strand: coord.strand,
+                    offset: coord.offset,
+                    src_size: coord.sequence_length,
+                    base: base as u8,
+                })
+            })
+            .collect();
+        AlignmentColumn { rows }
+    }
+}
+
+// The columns of the s lines of a MAF block
+fn maf_columns(block: Vec<MafLine>) -> VecDeque<AlignmentColumn> {
+    let block = match AlignmentBlock::from_lines(block) {
+        Some(block) => block,
+        None => return VecDeque::new(),
+    };
+
+    let rows: Vec<(&MafSequence, Arc<str>, Arc<str>)> = block
+        .lines
+        .iter()
+        .filter_map(|x| match x {
+            MafLine::SequenceLine(seq) => Some((
+                seq,
+                Arc::from(seq.species.as_str()),
+                Arc::from(seq.contig.as_str()),
+            )),
+            _ => None,
+        })
+        .collect();
+
+    block
+        .columns()
+        .map(|column| {
+            let rows = rows
+                .iter()
+                .zip(column.bases)
+                .zip(column.offsets)
+                .map(|(((seq, species, contig), base), offset)| {
+                    Some(AlignmentRow {
+                        species: species.clone(),
+                        contig: contig.clone(),
+                        strand: seq.strand,
+                        offset: seq.start + offset,
+                        src_size: Some(seq.src_size),
+                        base,
+                    })
+                })
+                .collect();
+            AlignmentColumn { rows }
+        })
+        .collect()
+}
+
+/// A MAF or TAF alignment, read as blocks (iterating) or as columns, see [`open_alignment`]
+///
+/// Blocks are MAF lines. TAF is regrouped into blocks as by [`TafBlockIterator`], each an `a`
+/// line followed by one `s` line per row. Blocks and columns are read from the same position, so
+/// columns read after a block continue from the next block.
+pub trait AlignmentSource: Iterator<Item = Result<Vec<MafLine>, OxidMafError>> {
+    /// Comment lines preceding the first block, see [`MafReader::header`]. For TAF, the `#taf`
+    /// header as a `##maf` line.
+    fn header(&self) -> &[MafLine];
+
+    fn next_column(&mut self) -> Option<Result<AlignmentColumn, OxidMafError>>;
+
+    /// Move to the first column of the row on `contig` at or before the zero-based forward strand
+    /// position `pos`, using the index of the file (`<file>.mafi` or `<file>.tai`). MAF files move
+    /// to the first block of `contig` ending after `pos`.
+    ///
+    /// Returns false if `contig` is not indexed (or has no block after `pos`), and an error if
+    /// the file is not indexed.
+    fn seek(&mut self, contig: &str, pos: u64) -> Result<bool, OxidMafError>;
+}
+
+/// Open a MAF or TAF file, or stdin when `path` is `-`, telling them apart by the `#taf` header.
+/// Compressed input is detected from its magic bytes.
+///
+/// `genome_names` split the srcs of MAF files and the sequence names of TAF indexes, see
+/// [`MafReader::with_genome_names`].
+pub fn open_alignment(
+    path: &str,
+    genome_names: &[String],
+) -> Result<Box<dyn AlignmentSource>, OxidMafError> {
+    let mut reader = open_input(path).map_err(|e| OxidMafError::io(path, e))?;
+    let is_taf = reader
+        .fill_buf()
+        .map_err(|e| OxidMafError::io(path, e))?
+        .starts_with(b"#taf");
+
+    // Files are reopened, so that they can be seeked
+    match (is_taf, path == "-") {
+        (true, true) => {
+            let parser = TafParser::from_reader(reader)?.with_file_name(path);
+            Ok(Box::new(TafSource::new(parser, path, genome_names)))
+        }
+        (true, false) => Ok(Box::new(TafSource::from_path(path, genome_names)?)),
+        (false, true) => {
+            let parser = MafReader::new(reader)
+                .with_file_name(path)
+                .with_genome_names(genome_names);
+            Ok(Box::new(MafSource::new(parser, path, genome_names)))
+        }
+        (false, false) => Ok(Box::new(MafSource::from_path(path, genome_names)?)),
+    }
+}
+
+/// A MAF file as an [`AlignmentSource`]
+pub struct MafSource {
+    input: MafInput,
+    path: String,
+    genome_names: Vec<String>,
+    // Columns of the last block read by next_column, not yet returned
+    columns: VecDeque<AlignmentColumn>,
+}
+
+// Read in order, until the first seek
+enum MafInput {
+    Stream(MafParser),
+    Indexed(Box<MafIndexedReader>),
+}
+
+impl MafSource {
+    /// Open a MAF file, see [`MafParser::from_path`]
+    pub fn from_path(path: &str, genome_names: &[String]) -> Result<Self, OxidMafError> {
+        let parser = MafParser::from_path(path)?.with_genome_names(genome_names);
+        Ok(MafSource::new(parser, path, genome_names))
+    }
+
+    fn new(parser: MafParser, path: &str, genome_names: &[String]) -> Self {
+        MafSource {
+            input: MafInput::Stream(parser),
+            path: path.to_string(),
+            genome_names: genome_names.to_vec(),
+            columns: VecDeque::new(),
+        }
+    }
+}
+
+impl Iterator for MafSource {
+    type Item = Result<Vec<MafLine>, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.columns.clear();
+        match &mut self.input {
+            MafInput::Stream(parser) => parser.next(),
+            MafInput::Indexed(reader) => reader.next_block(),
+        }
+    }
+}
+
+impl AlignmentSource for MafSource {
+    fn header(&self) -> &[MafLine] {
+        match &self.input {
+            MafInput::Stream(parser) => parser.header(),
+            MafInput::Indexed(reader) => reader.header(),
+        }
+    }
+
+    fn next_column(&mut self) -> Option<Result<AlignmentColumn, OxidMafError>> {
+        loop {
+            if let Some(column) = self.columns.pop_front() {
+                return Some(Ok(column));
+            }
+            match self.next()? {
+                Ok(block) => self.columns = maf_columns(block),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn seek(&mut self, contig: &str, pos: u64) -> Result<bool, OxidMafError> {
+        if let MafInput::Stream(_) = self.input {
+            let reader =
+                MafIndexedReader::from_path(&self.path)?.with_genome_names(&self.genome_names);
+            self.input = MafInput::Indexed(Box::new(reader));
+        }
+
+        self.columns.clear();
+        match &mut self.input {
+            MafInput::Indexed(reader) => reader.seek(contig, pos),
+            MafInput::Stream(_) => unreachable!(),
+        }
+    }
+}
+
+/// A TAF file as an [`AlignmentSource`]
+pub struct TafSource {
+    blocks: TafBlockIterator<TafParser>,
+    header: Vec<MafLine>,
+    path: String,
+    genome_names: Vec<String>,
+    // Read on the first seek
+    index: Option<TaiIndex>,
+}
+
+impl TafSource {
+    /// Open a TAF file, see [`TafParser::from_file`]. `genome_names` split the sequence names of
+    /// the file and its index, see [`TafParser::with_genome_names`].
+    pub fn from_path(path: &str, genome_names: &[String]) -> Result<Self, OxidMafError> {
+        Ok(TafSource::new(
+            TafParser::from_file(path)?,
+            path,
+            genome_names,
+        ))
+    }
+
+    fn new(parser: TafParser, path: &str, genome_names: &[String]) -> Self {
+        TafSource {
+            header: vec![parser.header.maf_header()],
+            blocks: TafBlockIterator::new(parser).with_genome_names(genome_names),
+            path: path.to_string(),
+            genome_names: genome_names.to_vec(),
+            index: None,
+        }
+    }
+}
+
+impl Iterator for TafSource {
+    type Item = Result<Vec<MafLine>, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.blocks.next()
+    }
+}
+
+impl AlignmentSource for TafSource {
+    fn header(&self) -> &[MafLine] {
+        &self.header
+    }
+
+    fn next_column(&mut self) -> Option<Result<AlignmentColumn, OxidMafError>> {
+        Some(self.blocks.next_column()?.map(AlignmentColumn::from))
+    }
+
+    fn seek(&mut self, contig: &str, pos: u64) -> Result<bool, OxidMafError> {
+        if self.index.is_none() {
+            let index_path = format!("{}.tai", self.path);
+            let index = TaiIndex::from_file(&index_path)?.with_genome_names(&self.genome_names);
+            self.index = Some(index);
+        }
+
+        let offset = match self.index.as_ref().unwrap().get_seek_info(contig, pos) {
+            Some((_, offset)) => offset,
+            None => return Ok(false),
+        };
+        self.blocks.parser_mut().seek_to(offset)?;
+        self.blocks.reset();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.maf");
+    const TAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.taf.gz");
+
+    // Species and forward strand position of each row of each column
+    fn rows(source: &mut dyn AlignmentSource) -> Vec<Vec<(String, Option<u64>)>> {
+        std::iter::from_fn(|| source.next_column())
+            .map(|column| {
+                let column = column.unwrap();
+                (0..column.rows.len())
+                    .map(|i| {
+                        let row = column.rows[i].as_ref().unwrap();
+                        (row.species.to_string(), column.position(i))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn maf_and_taf_columns_agree() {
+        let mut maf = open_alignment(MAF, &[]).unwrap();
+        let mut taf = open_alignment(TAF, &[]).unwrap();
+        assert_eq!(rows(maf.as_mut()), rows(taf.as_mut()));
+
+        // Including the offsets of gaps, and rows changing strand
+        let columns = |path: &str| -> Vec<AlignmentColumn> {
+            let mut source = open_alignment(path, &[]).unwrap();
+            std::iter::from_fn(|| source.next_column())
+                .map(|x| x.unwrap())
+                .collect()
+        };
+        let maf = columns(concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/rows.maf"));
+        let taf = columns(concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/rows.taf"));
+        assert_eq!(maf, taf);
+
+        // Anc0 has a gap in the third column, before its base at offset 7 from the end
+        let gap = maf[2].rows[1].as_ref().unwrap();
+        assert_eq!((gap.base, gap.offset, gap.position()), (b'-', 7, None));
+    }
+}
@@ -0,0 +1,267 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Crc;
+
+use std::io::{BufRead, Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Reads BGZF (bgzip) compressed data one block at a time, keeping track of virtual offsets
+///
+/// A virtual offset is the compressed offset of a block shifted left 16 bits, plus the offset of
+/// a byte within the uncompressed block, as used by htslib and taffy indexes.
+/// https://samtools.github.io/hts-specs/SAMv1.pdf (section 4.1)
+pub struct BgzfReader<R: Read> {
+    inner: R,
+    // Compressed offsets of the block in buf and of the block after it
+    block_offset: u64,
+    next_block_offset: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    cdata: Vec<u8>,
+}
+
+impl<R: Read> BgzfReader<R> {
+    /// `inner` should be positioned at the start of a block, normally the start of the file
+    pub fn new(inner: R) -> Self {
+        BgzfReader {
+            inner,
+            block_offset: 0,
+            next_block_offset: 0,
+            buf: Vec::new(),
+            pos: 0,
+            cdata: Vec::new(),
+        }
+    }
+
+    /// Virtual offset of the next byte to be read
+    pub fn virtual_offset(&self) -> u64 {
+        if self.pos < self.buf.len() {
+            (self.block_offset << 16) | self.pos as u64
+        } else {
+            self.next_block_offset << 16
+        }
+    }
+
+    // Read and decompress the next block into buf, returns false at EOF
+    fn read_block(&mut self) -> Result<bool, IoError> {
+        let mut header = [0u8; 12];
+        match self.inner.read(&mut header[..1])? {
+            0 => return Ok(false),
+            _ => self.inner.read_exact(&mut header[1..])?,
+        }
+
+        if header[..4] != [0x1f, 0x8b, 8, 4] {
+            return Err(IoError::new(ErrorKind::InvalidData, "Not a BGZF block"));
+        }
+
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0u8; xlen];
+        self.inner.read_exact(&mut extra)?;
+
+        // BSIZE (total block size - 1) is in the BC extra subfield
+        let mut bsize = None;
+        let mut i = 0;
+        while i + 4 <= xlen {
+            let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+            if extra[i] == b'B' && extra[i + 1] == b'C' && slen == 2 && i + 6 <= xlen {
+                bsize = Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize);
+            }
+            i += 4 + slen;
+        }
+        let bsize =
+            bsize.ok_or_else(|| IoError::new(ErrorKind::InvalidData, "Missing BGZF block size"))?;
+
+        // Compressed data, then CRC32 and ISIZE
+        let cdata_len = (bsize + 1)
+            .checked_sub(12 + xlen + 8)
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "Invalid BGZF block size"))?;
+        self.cdata.resize(cdata_len + 8, 0);
+        self.inner.read_exact(&mut self.cdata)?;
+
+        let trailer = &self.cdata[cdata_len..];
+        let crc32 = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let isize = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]) as usize;
+
+        self.buf.clear();
+        self.buf.reserve(isize);
+        DeflateDecoder::new(&self.cdata[..cdata_len]).read_to_end(&mut self.buf)?;
+
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+        if self.buf.len() != isize || crc.sum() != crc32 {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "BGZF block checksum mismatch",
+            ));
+        }
+
+        self.pos = 0;
+        self.block_offset = self.next_block_offset;
+        self.next_block_offset += bsize as u64 + 1;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    /// Seek to a virtual offset, as returned by [`BgzfReader::virtual_offset`]
+    pub fn seek_virtual(&mut self, virtual_offset: u64) -> Result<(), IoError> {
+        let block_offset = virtual_offset >> 16;
+        let pos = (virtual_offset & 0xffff) as usize;
+
+        // Often still within the current block
+        if self.buf.is_empty() || block_offset != self.block_offset {
+            self.inner.seek(SeekFrom::Start(block_offset))?;
+            self.next_block_offset = block_offset;
+            self.buf.clear();
+            self.read_block()?;
+        }
+
+        if pos > self.buf.len() {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "Virtual offset is past the end of the BGZF block",
+            ));
+        }
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, IoError> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for BgzfReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8], IoError> {
+        // Skip empty blocks, such as the EOF marker
+        while self.pos >= self.buf.len() {
+            if !self.read_block()? {
+                return Ok(&[]);
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
+// Uncompressed bytes per block, as bgzip uses, so a block still fits if it does not compress
+const BLOCK_SIZE: usize = 0xff00;
+
+// Empty block marking the end of the file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0,
+    0, 0,
+];
+
+/// Writes BGZF (bgzip) compressed data, keeping track of virtual offsets for indexing
+///
+/// [`BgzfWriter::finish`] writes the last block and the EOF marker. Dropping the writer does the
+/// same, ignoring errors.
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    // Compressed offset of the block being filled
+    block_offset: u64,
+    buf: Vec<u8>,
+    cdata: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BgzfWriter {
+            inner: Some(inner),
+            block_offset: 0,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            cdata: Vec::new(),
+        }
+    }
+
+    /// Virtual offset of the next byte to be written
+    pub fn virtual_offset(&self) -> u64 {
+        (self.block_offset << 16) | self.buf.len() as u64
+    }
+
+    /// Write the remaining data and the EOF marker, returning the inner writer
+    pub fn finish(mut self) -> Result<W, IoError> {
+        self.write_eof()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn write_eof(&mut self) -> Result<(), IoError> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&EOF_BLOCK)?;
+        inner.flush()
+    }
+
+    // Compress buf into a single block
+    fn write_block(&mut self) -> Result<(), IoError> {
+        self.cdata.clear();
+        let mut encoder = DeflateEncoder::new(&mut self.cdata, flate2::Compression::default());
+        encoder.write_all(&self.buf)?;
+        encoder.finish()?;
+
+        // Header, compressed data, CRC32 and ISIZE must total at most 64 KiB
+        if 18 + self.cdata.len() + 8 > 0x10000 {
+            self.cdata.clear();
+            let mut encoder = DeflateEncoder::new(&mut self.cdata, flate2::Compression::none());
+            encoder.write_all(&self.buf)?;
+            encoder.finish()?;
+        }
+
+        let block_size = 18 + self.cdata.len() + 8;
+        let bsize = ((block_size - 1) as u16).to_le_bytes();
+        let header = [
+            0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, bsize[0], bsize[1],
+        ];
+
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&header)?;
+        inner.write_all(&self.cdata)?;
+        inner.write_all(&crc.sum().to_le_bytes())?;
+        inner.write_all(&(self.buf.len() as u32).to_le_bytes())?;
+
+        self.block_offset += block_size as u64;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        let n = data.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Ends the current block early, as `bgzf_flush` does
+    fn flush(&mut self) -> Result<(), IoError> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.write_eof();
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use super::open_input;
+use crate::OxidMafError;
+
+/// Read contig names and lengths, in order, from a samtools faidx index (`.fai`)
+pub fn read_fai(path: &str) -> Result<Vec<(String, u64)>, OxidMafError> {
+    let file = File::open(path).map_err(|e| OxidMafError::io(path, e))?;
+    let mut contigs = Vec::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| OxidMafError::io(path, e))?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields = line.split('\t');
+        let name = fields.next().unwrap_or_default();
+        let length = fields.next().and_then(|x| x.parse::<u64>().ok());
+        match length {
+            Some(length) if !name.is_empty() => contigs.push((name.to_string(), length)),
+            _ => {
+                return Err(OxidMafError::malformed(
+                    path,
+                    i as u64 + 1,
+                    &line,
+                    "Expected a contig name and length",
+                ))
+            }
+        }
+    }
+
+    Ok(contigs)
+}
+
+/// One position of a GERP++ `.rates` file
+#[derive(Clone, Debug, PartialEq)]
+pub struct GerpRate {
+    /// Index of the contig in [`GerpRatesReader::contigs`]
+    pub contig: usize,
+    /// 1-based
+    pub pos: u64,
+    pub neutral_rate: f64,
+    /// Rejected substitutions score
+    pub rs_score: f64,
+}
+
+/// Reads a GERP++ `.rates` file (plain, gzip, bgzip or zstd compressed)
+///
+/// Rates files have no coordinates, only a line (neutral rate and RS score) for every position of
+/// a contig, so the contig lengths must be known. Genome-wide files are the files of each contig
+/// concatenated, in the order of `contigs`.
+pub struct GerpRatesReader {
+    reader: Box<dyn BufRead>,
+    contigs: Vec<(String, u64)>,
+    // Position of the last line read
+    contig: usize,
+    pos: u64,
+    // For error messages
+    file: String,
+    line_number: u64,
+    line: String,
+}
+
+impl GerpRatesReader {
+    pub fn from_file(file: &str, contigs: Vec<(String, u64)>) -> Result<Self, OxidMafError> {
+        let reader = open_input(file).map_err(|e| OxidMafError::io(file, e))?;
+
+        Ok(GerpRatesReader {
+            reader,
+            contigs,
+            contig: 0,
+            pos: 0,
+            file: file.to_string(),
+            line_number: 0,
+            line: String::new(),
+        })
+    }
+
+    pub fn contigs(&self) -> &[(String, u64)] {
+        &self.contigs
+    }
+
+    /// Number of lines expected for the contigs, less those read so far
+    pub fn remaining(&self) -> u64 {
+        let total: u64 = self.contigs.iter().map(|(_, length)| length).sum();
+        total.saturating_sub(self.line_number)
+    }
+}
+
+impl Iterator for GerpRatesReader {
+    type Item = Result<GerpRate, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.line.clear();
+        match self.reader.read_line(&mut self.line) {
+            Ok(0) => return None,
+            Ok(_) => self.line_number += 1,
+            Err(e) => return Some(Err(OxidMafError::io(&self.file, e))),
+        }
+        let line = self.line.trim_end_matches(['\n', '\r']);
+        let malformed =
+            |message: &str| OxidMafError::malformed(&self.file, self.line_number, line, message);
+
+        // Move to the next contig with any positions left
+        self.pos += 1;
+        while self.contig < self.contigs.len() && self.pos > self.contigs[self.contig].1 {
+            self.contig += 1;
+            self.pos = 1;
+        }
+        if self.contig == self.contigs.len() {
+            return Some(Err(malformed(
+                "More lines than the total length of the contigs",
+            )));
+        }
+
+        let mut fields = line.split_whitespace();
+        let mut number = || fields.next().and_then(|x| x.parse::<f64>().ok());
+        match (number(), number()) {
+            (Some(neutral_rate), Some(rs_score)) => Some(Ok(GerpRate {
+                contig: self.contig,
+                pos: self.pos,
+                neutral_rate,
+                rs_score,
+            })),
+            _ => Some(Err(malformed("Expected a neutral rate and RS score"))),
+        }
+    }
+}
+
+/// A constrained element from a GERP++ `.elems` file
+#[derive(Clone, Debug, PartialEq)]
+pub struct GerpElement {
+    pub contig: String,
+    /// 1-based and inclusive
+    pub start: u64,
+    pub end: u64,
+    pub rs_score: f64,
+    pub p_value: f64,
+    pub expected: f64,
+    pub observed: f64,
+    pub length: f64,
+}
+
+impl GerpElement {
+    /// Is the 1-based position `pos` within this element?
+    pub fn contains(&self, pos: u64) -> bool {
+        pos >= self.start && pos <= self.end
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return Err(format!(
+                "Expected 8 columns (contig, start, end, RS score, p-value, expected, observed and \
+                 length), found {}",
+                fields.len()
+            ));
+        }
+
+        let integer = |i: usize, name: &str| {
+            fields[i]
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid {} '{}': {}", name, fields[i], e))
+        };
+        let float = |i: usize, name: &str| {
+            fields[i]
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid {} '{}': {}", name, fields[i], e))
+        };
+
+        Ok(GerpElement {
+            contig: fields[0].to_string(),
+            start: integer(1, "start")?,
+            end: integer(2, "end")?,
+            rs_score: float(3, "RS score")?,
+            p_value: float(4, "p-value")?,
+            expected: float(5, "expected")?,
+            observed: float(6, "observed")?,
+            length: float(7, "length")?,
+        })
+    }
+}
+
+/// Reads a genome-wide GERP++ `.elems` file (plain, gzip, bgzip or zstd compressed), with columns
+/// contig, start, end, RS score, p-value, expected, observed and length
+pub struct GerpElementReader {
+    reader: Box<dyn BufRead>,
+    // For error messages
+    file: String,
+    line_number: u64,
+}
+
+impl GerpElementReader {
+    pub fn from_file(file: &str) -> Result<Self, OxidMafError> {
+        let reader = open_input(file).map_err(|e| OxidMafError::io(file, e))?;
+
+        Ok(GerpElementReader {
+            reader,
+            file: file.to_string(),
+            line_number: 0,
+        })
+    }
+}
+
+impl Iterator for GerpElementReader {
+    type Item = Result<GerpElement, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => self.line_number += 1,
+                Err(e) => return Some(Err(OxidMafError::io(&self.file, e))),
+            }
+
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+
+            let line = line.trim_end_matches(['\n', '\r']);
+            return Some(GerpElement::parse(line).map_err(|message| {
+                OxidMafError::malformed(&self.file, self.line_number, line, message)
+            }));
+        }
+    }
+}
+
+/// Constrained elements of each contig, sorted by start, for finding the element containing each
+/// position in a single sweep
+#[derive(Debug, Default)]
+pub struct GerpElements {
+    // Elements of each contig, and the first that may contain the next position
+    contigs: HashMap<String, (Vec<GerpElement>, usize)>,
+}
+
+impl GerpElements {
+    pub fn new(elements: impl IntoIterator<Item = GerpElement>) -> Self {
+        let mut contigs: HashMap<String, (Vec<GerpElement>, usize)> = HashMap::new();
+        for element in elements {
+            contigs
+                .entry(element.contig.clone())
+                .or_default()
+                .0
+                .push(element);
+        }
+        for (elements, _) in contigs.values_mut() {
+            elements.sort_by_key(|x| (x.start, x.end));
+        }
+
+        GerpElements { contigs }
+    }
+
+    /// The elements of `contig`, sorted by start
+    pub fn get(&self, contig: &str) -> &[GerpElement] {
+        match self.contigs.get(contig) {
+            Some((elements, _)) => elements,
+            None => &[],
+        }
+    }
+
+    /// Contigs with any elements, in no particular order
+    pub fn contigs(&self) -> impl Iterator<Item = &str> {
+        self.contigs.keys().map(|x| x.as_str())
+    }
+
+    /// The element containing the 1-based position `pos` of `contig`. Positions of each contig
+    /// must be looked up in increasing order.
+    pub fn find(&mut self, contig: &str, pos: u64) -> Option<&GerpElement> {
+        let (elements, next) = self.contigs.get_mut(contig)?;
+
+        // Elements are sorted by start, so if any contains pos, the first not ending before it
+        // does
+        while *next < elements.len() && elements[*next].end < pos {
+            *next += 1;
+        }
+        elements.get(*next).filter(|x| x.contains(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.rates");
+    const ELEMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.elems");
+    const FAI: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gerp.fai");
+
+    #[test]
+    fn rates_follow_contig_lengths() {
+        let contigs = read_fai(FAI).unwrap();
+        assert_eq!(
+            contigs,
+            vec![("chr1".to_string(), 6), ("chr2".to_string(), 4)]
+        );
+
+        let rates = GerpRatesReader::from_file(RATES, contigs).unwrap();
+        let positions: Vec<(usize, u64)> = rates
+            .map(|x| x.unwrap())
+            .map(|x| (x.contig, x.pos))
+            .collect();
+        assert_eq!(positions.len(), 10);
+        assert_eq!(positions[5], (0, 6));
+        assert_eq!(positions[6], (1, 1));
+
+        // One contig too short for the file
+        let mut rates = GerpRatesReader::from_file(RATES, vec![("chr1".to_string(), 9)]).unwrap();
+        assert!(rates.nth(9).unwrap().is_err());
+    }
+
+    #[test]
+    fn elements_sweep() {
+        let elements: Vec<GerpElement> = GerpElementReader::from_file(ELEMS)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut elements = GerpElements::new(elements);
+
+        let found: Vec<Option<u64>> = (1..=6)
+            .map(|pos| elements.find("chr1", pos).map(|x| x.start))
+            .collect();
+        assert_eq!(found, vec![None, Some(2), Some(2), None, Some(5), Some(5)]);
+        assert_eq!(elements.find("chr2", 2).map(|x| x.end), Some(3));
+        assert!(elements.find("chr3", 1).is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use flate2::bufread::MultiGzDecoder;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Seek, SeekFrom};
+
+use super::BgzfReader;
+use crate::OxidMafError;
+
+/// Compression formats recognised from the first bytes of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    /// Blocked gzip (bgzip), a series of gzip members with a `BC` extra subfield
+    Bgzf,
+    Zstd,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Sniff the compression format from the magic bytes, without consuming any input
+pub fn detect_compression<R: BufRead>(reader: &mut R) -> Result<Compression, IoError> {
+    let buf = reader.fill_buf()?;
+
+    if buf.starts_with(&ZSTD_MAGIC) {
+        return Ok(Compression::Zstd);
+    }
+
+    if buf.starts_with(&GZIP_MAGIC) {
+        // BGZF: FLG.FEXTRA set, and the first extra subfield is BC
+        // https://samtools.github.io/hts-specs/SAMv1.pdf (section 4.1)
+        if buf.len() >= 14 && buf[3] & 0x04 != 0 && buf[12] == b'B' && buf[13] == b'C' {
+            return Ok(Compression::Bgzf);
+        }
+        return Ok(Compression::Gzip);
+    }
+
+    Ok(Compression::None)
+}
+
+/// Wrap a reader, transparently decompressing gzip, bgzip or zstd input
+pub fn decompress<R: BufRead + 'static>(mut reader: R) -> Result<Box<dyn BufRead>, IoError> {
+    match detect_compression(&mut reader)? {
+        Compression::None => Ok(Box::new(reader)),
+        // BGZF is valid multi-member gzip
+        Compression::Gzip | Compression::Bgzf => {
+            Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+        }
+        Compression::Zstd => Ok(Box::new(BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(reader)?,
+        ))),
+    }
+}
+
+/// Open a file (or stdin, when the path is `-`) for reading, decompressing as needed
+pub fn open_input(path: &str) -> Result<Box<dyn BufRead>, IoError> {
+    if path == "-" {
+        decompress(std::io::stdin().lock())
+    } else {
+        let file = File::open(path)?;
+        decompress(BufReader::new(file))
+    }
+}
+
+/// Readers that can also seek, so they can be boxed
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A plain or bgzipped file that can report and seek to the offset of the next byte
+pub enum IndexedInput<R: Read + Seek> {
+    Plain { inner: BufReader<R>, offset: u64 },
+    Bgzf(BgzfReader<BufReader<R>>),
+}
+
+impl IndexedInput<File> {
+    pub fn open(path: &str) -> Result<Self, OxidMafError> {
+        let file = File::open(path).map_err(|e| OxidMafError::io(path, e))?;
+        let mut inner = BufReader::new(file);
+
+        match detect_compression(&mut inner).map_err(|e| OxidMafError::io(path, e))? {
+            Compression::None => Ok(IndexedInput::Plain { inner, offset: 0 }),
+            Compression::Bgzf => Ok(IndexedInput::Bgzf(BgzfReader::new(inner))),
+            Compression::Gzip | Compression::Zstd => {
+                let e = IoError::new(
+                    ErrorKind::InvalidInput,
+                    "Only plain text and bgzipped files can be indexed, recompress with bgzip",
+                );
+                Err(OxidMafError::io(path, e))
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> IndexedInput<R> {
+    /// Byte offset, or BGZF virtual offset, of the next byte to be read
+    pub fn tell(&self) -> u64 {
+        match self {
+            IndexedInput::Plain { offset, .. } => *offset,
+            IndexedInput::Bgzf(inner) => inner.virtual_offset(),
+        }
+    }
+
+    /// Seek to an offset returned by [`IndexedInput::tell`]
+    pub fn seek_to(&mut self, pos: u64) -> Result<(), IoError> {
+        match self {
+            IndexedInput::Plain { inner, offset } => {
+                inner.seek(SeekFrom::Start(pos))?;
+                *offset = pos;
+                Ok(())
+            }
+            IndexedInput::Bgzf(inner) => inner.seek_virtual(pos),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for IndexedInput<R> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, IoError> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> BufRead for IndexedInput<R> {
+    fn fill_buf(&mut self) -> Result<&[u8], IoError> {
+        match self {
+            IndexedInput::Plain { inner, .. } => inner.fill_buf(),
+            IndexedInput::Bgzf(inner) => inner.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            IndexedInput::Plain { inner, offset } => {
+                inner.consume(amt);
+                *offset += amt as u64;
+            }
+            IndexedInput::Bgzf(inner) => inner.consume(amt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same MAF, plain and compressed. The bgzipped copy has two data blocks.
+    const PLAIN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/indels.maf");
+    const GZIP: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/indels.maf.gz");
+    const BGZF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/indels.maf.bgz");
+    const ZSTD: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/indels.maf.zst");
+
+    #[test]
+    fn detect_and_decompress() {
+        let expected = std::fs::read_to_string(PLAIN).unwrap();
+
+        for (path, compression) in [
+            (PLAIN, Compression::None),
+            (GZIP, Compression::Gzip),
+            (BGZF, Compression::Bgzf),
+            (ZSTD, Compression::Zstd),
+        ] {
+            let mut reader = BufReader::new(File::open(path).unwrap());
+            assert_eq!(
+                detect_compression(&mut reader).unwrap(),
+                compression,
+                "{}",
+                path
+            );
+
+            let mut text = String::new();
+            open_input(path).unwrap().read_to_string(&mut text).unwrap();
+            assert_eq!(text, expected, "{}", path);
+        }
+
+        // Too short for any magic bytes
+        assert_eq!(
+            detect_compression(&mut &b"\x1f"[..]).unwrap(),
+            Compression::None
+        );
+        assert!(open_input(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_data/missing.maf"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn only_plain_and_bgzf_are_indexed() {
+        let expected = std::fs::read_to_string(PLAIN).unwrap();
+
+        for path in [PLAIN, BGZF] {
+            let mut input = IndexedInput::open(path).unwrap();
+            let mut text = String::new();
+            input.read_to_string(&mut text).unwrap();
+            assert_eq!(text, expected, "{}", path);
+        }
+        for path in [GZIP, ZSTD] {
+            let e = IndexedInput::open(path).err().unwrap();
+            assert!(e.to_string().contains("recompress with bgzip"), "{}", e);
+        }
+    }
+}
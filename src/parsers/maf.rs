@@ -1,98 +1,195 @@
 use std::fmt::{Debug, Display};
 use std::io::BufRead;
+use std::ops::Range;
 
-// Create an iterator from a bufreader
-pub fn maf_parser(file: std::fs::File) -> MafParser {
-    let reader = std::io::BufReader::new(file);
-    let lines = reader.lines();
+use super::{open_input, ContainsResult, Strand};
+use crate::OxidMafError;
 
-    MafParser {
-        lines,
-        current_block: Vec::new(),
-        block_start_line: None,
-    }
-}
+/// A MAF reader over an already opened (and decompressed) input
+pub type MafParser = MafReader<Box<dyn BufRead>>;
 
-pub struct MafParser {
-    lines: std::io::Lines<std::io::BufReader<std::fs::File>>,
+/// Iterates over the blocks of a MAF file, from any `BufRead`
+///
+/// Use [`MafReader::from_path`] to open a file (or `-` for stdin) with transparent gzip, bgzip
+/// and zstd decompression.
+///
+/// A malformed line yields an error for its block, and the rest of that block is skipped, so
+/// iteration can continue with the next block.
+pub struct MafReader<R: BufRead> {
+    reader: R,
+    line: String,
     current_block: Vec<MafLine>,
-    block_start_line: Option<MafLine>,
+    genome_names: Vec<String>,
+    // For error messages
+    file: String,
+    line_number: u64,
+    // Skipping the remainder of a malformed block
+    skipping: bool,
+    // Comment lines before the first block, set once the first block has been read
+    header: Option<Vec<MafLine>>,
+}
+
+impl MafParser {
+    /// Open a MAF file, or stdin when `path` is `-`. Compressed input is detected from its
+    /// magic bytes.
+    pub fn from_path(path: &str) -> Result<Self, OxidMafError> {
+        let reader = open_input(path).map_err(|e| OxidMafError::io(path, e))?;
+        Ok(MafReader::new(reader).with_file_name(path))
+    }
 }
 
-impl Iterator for MafParser {
-    type Item = Vec<MafLine>;
+impl<R: BufRead> Iterator for MafReader<R> {
+    type Item = Result<Vec<MafLine>, OxidMafError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(line) = self.lines.next() {
-            let line = line.unwrap();
+        loop {
+            let mut block = match self.read_block()? {
+                Ok(block) => block,
+                Err(e) => return Some(Err(e)),
+            };
+
+            // Leading comments of the file are the header (##maf version=1 ...)
+            if self.header.is_none() {
+                let header_len = block
+                    .iter()
+                    .take_while(|x| matches!(x, MafLine::Comment(_)))
+                    .count();
+                self.header = Some(block.drain(..header_len).collect());
+                if block.is_empty() {
+                    continue;
+                }
+            }
+
+            return Some(Ok(block));
+        }
+    }
+}
+
+impl<R: BufRead> MafReader<R> {
+    pub fn new(reader: R) -> Self {
+        MafReader {
+            reader,
+            line: String::new(),
+            current_block: Vec::new(),
+            genome_names: Vec::new(),
+            file: "<input>".to_string(),
+            line_number: 0,
+            skipping: false,
+            header: None,
+        }
+    }
+
+    /// Comment lines preceding the first block, including the `##maf version=1` line.
+    ///
+    /// These are not returned as part of the first block. Empty until the first block is read.
+    pub fn header(&self) -> &[MafLine] {
+        self.header.as_deref().unwrap_or(&[])
+    }
+
+    /// The underlying reader, e.g. to seek it. Call [`MafReader::reset`] after moving it.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Discard any partly read block, after the underlying reader has been moved to the start of
+    /// another block. Line numbers in subsequent errors count from this point.
+    pub fn reset(&mut self) {
+        self.current_block.clear();
+        self.skipping = false;
+        self.line_number = 0;
+    }
+
+    // Read lines up to the next blank line
+    fn read_block(&mut self) -> Option<Result<Vec<MafLine>, OxidMafError>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => break,
+                Ok(_) => self.line_number += 1,
+                Err(e) => return Some(Err(OxidMafError::io(&self.file, e))),
+            }
+            let line = self.line.trim_end_matches(['\n', '\r']);
 
             // Match on the first character
-            let x = parse_maf_line(&line);
+            let x = match parse_maf_line(line, &self.genome_names) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                // Only the first malformed line of a block is reported
+                Err(_) if self.skipping => continue,
+                Err(message) => {
+                    self.current_block.clear();
+                    self.skipping = true;
+                    return Some(Err(OxidMafError::malformed(
+                        &self.file,
+                        self.line_number,
+                        line,
+                        message,
+                    )));
+                }
+            };
 
             if let MafLine::BlankLine = x {
-                if self.current_block.len() > 0 {
+                self.skipping = false;
+                if !self.current_block.is_empty() {
                     let blank = Vec::with_capacity(self.current_block.len());
                     let block = std::mem::replace(&mut self.current_block, blank);
-                    return Some(block);
+                    return Some(Ok(block));
                 } else {
                     continue;
                 }
-            } else {
+            } else if !self.skipping {
                 self.current_block.push(x);
             }
         }
-        return None;
+
+        // Final block may not be followed by a blank line
+        if !self.current_block.is_empty() {
+            return Some(Ok(std::mem::take(&mut self.current_block)));
+        }
+        None
+    }
+
+    /// Name of the input, for error messages
+    pub fn with_file_name(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+
+    /// Known genome (species) names, for genomes whose names themselves contain dots.
+    ///
+    /// A src such as `Homo.sapiens.chr1` is split as species `Homo.sapiens` and contig `chr1`
+    /// when `Homo.sapiens` is given here. Srcs not matching any name are split on the first dot.
+    pub fn with_genome_names(mut self, genome_names: &[String]) -> Self {
+        self.genome_names = genome_names.to_vec();
+        self
     }
-}
 
-impl MafParser {
     // Iterate over the alignment blocks
-    pub fn alignment_blocks(&mut self) -> AlignmentBlockIterator {
+    pub fn alignment_blocks(&mut self) -> AlignmentBlockIterator<'_, R> {
         AlignmentBlockIterator { parser: self }
     }
 }
 
-pub struct AlignmentBlockIterator<'a> {
-    parser: &'a mut MafParser,
+pub struct AlignmentBlockIterator<'a, R: BufRead> {
+    parser: &'a mut MafReader<R>,
 }
 
-impl<'a> Iterator for AlignmentBlockIterator<'a> {
-    type Item = AlignmentBlock;
+impl<R: BufRead> Iterator for AlignmentBlockIterator<'_, R> {
+    type Item = Result<AlignmentBlock, OxidMafError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut block = AlignmentBlock::default();
-        let mut block_is_alignment = false;
-
-        while let Some(line) = self.parser.lines.next() {
-            let line = line.unwrap();
-
-            // Match on the first character
-            let x = parse_maf_line(&line);
-
-            match &x {
-                MafLine::AlignmentBlockLine(_) => {
-                    if block.lines.len() > 0 && block_is_alignment {
-                        return Some(block);
-                    }
-                }
-                MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text) => {
-                    block.species.push(species.clone());
-                    block_is_alignment = true;
-                    if block.lines.len() == 0 {
-                        block.seqid = seqid.clone();
-                        block.start = *start;
-                        block.len = *length;
-                    }
-                    block.lines.push(x);
-                }
-                _ => (), // Do nothing for comments and blank lines
-            }
+        for lines in self.parser.by_ref() {
+            let lines = match lines {
+                Ok(lines) => lines,
+                Err(e) => return Some(Err(e)),
+            };
 
-            if !block_is_alignment {
-                continue;
+            // Skip header and comment only chunks
+            if let Some(block) = AlignmentBlock::from_lines(lines) {
+                return Some(Ok(block));
             }
         }
-        return None;
+        None
     }
 }
 
@@ -100,11 +197,175 @@ impl<'a> Iterator for AlignmentBlockIterator<'a> {
 #[derive(Clone)]
 pub enum MafLine {
     Comment(String),
-    AlignmentBlockLine(String),
-    SequenceLine(String, String, u64, u64, Strand, u64, String),
+    AlignmentBlockLine(MafBlockHeader),
+    SequenceLine(MafSequence),
+    InfoLine(MafInfo),
+    EmptyLine(MafEmpty),
+    QualityLine(MafQuality),
     BlankLine,
 }
 
+/// The `a` line starting an alignment block, e.g. `a score=23262.0 pass=2`
+///
+/// Pairs are kept in file order, as written, so the line round-trips unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MafBlockHeader {
+    pub pairs: Vec<(String, String)>,
+}
+
+impl MafBlockHeader {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn score(&self) -> Option<f64> {
+        self.get("score").and_then(|x| x.parse().ok())
+    }
+
+    pub fn pass(&self) -> Option<u64> {
+        self.get("pass").and_then(|x| x.parse().ok())
+    }
+}
+
+/// An `i` line, describing what precedes and follows the previous `s` line in its genome
+///
+/// Status characters are `C` (contiguous), `I` (intervening bases), `N` (first in a new
+/// chromosome), `n` (first in a new bridged chromosome), `M` (missing data) and `T` (tandem).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MafInfo {
+    pub src: String,
+    pub species: String,
+    pub contig: String,
+    pub left_status: char,
+    pub left_count: u64,
+    pub right_status: char,
+    pub right_count: u64,
+}
+
+/// An `e` line, a species with no aligning sequence in this block, but bridged by the chain
+/// on either side
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MafEmpty {
+    pub src: String,
+    pub species: String,
+    pub contig: String,
+    pub start: u64,
+    pub size: u64,
+    pub strand: Strand,
+    pub src_size: u64,
+    pub status: char,
+}
+
+/// A `q` line, the per-column quality of the previous `s` line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MafQuality {
+    pub src: String,
+    pub species: String,
+    pub contig: String,
+    pub quality: String,
+}
+
+/// A single `s` line of an alignment block
+///
+/// `start` is zero-based and relative to `strand`: for minus strand rows it counts from the end
+/// of the source sequence, as in the MAF spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MafSequence {
+    /// The full source name as it appears in the file, e.g. `Kakapo.NC_044298.1_ctg1`
+    pub src: String,
+    pub species: String,
+    pub contig: String,
+    pub start: u64,
+    /// Number of (ungapped) bases in the aligning region
+    pub size: u64,
+    pub strand: Strand,
+    /// Length of the entire source sequence
+    pub src_size: u64,
+    /// Alignment text, including gaps (`-`)
+    pub text: String,
+}
+
+impl MafSequence {
+    /// End of the aligning region (exclusive), in the same coordinates as `start`
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Number of non-gap characters in `text`
+    pub fn ungapped_len(&self) -> u64 {
+        self.text.bytes().filter(|b| *b != b'-').count() as u64
+    }
+
+    /// Number of alignment columns
+    pub fn aligned_len(&self) -> usize {
+        self.text.len()
+    }
+
+    /// Position of the `offset`th base of this row (counting non-gap characters), zero-based on
+    /// the forward strand of the source sequence
+    pub fn forward_position(&self, offset: u64) -> u64 {
+        match self.strand {
+            Strand::Plus => self.start + offset,
+            Strand::Minus => self.src_size - 1 - (self.start + offset),
+        }
+    }
+
+    /// The aligning region, zero-based and half-open on the forward strand of the source sequence
+    pub fn forward_range(&self) -> Range<u64> {
+        match self.strand {
+            Strand::Plus => self.start..self.end(),
+            Strand::Minus => self.src_size - self.end()..self.src_size - self.start,
+        }
+    }
+
+    /// The alignment columns in `cols`, with `start` and `size` updated to the bases they cover
+    pub fn slice_columns(&self, cols: Range<usize>) -> MafSequence {
+        let before = self.text.as_bytes()[..cols.start]
+            .iter()
+            .filter(|b| **b != b'-')
+            .count() as u64;
+        let text = self.text[cols].to_string();
+        MafSequence {
+            src: self.src.clone(),
+            species: self.species.clone(),
+            contig: self.contig.clone(),
+            start: self.start + before,
+            size: text.bytes().filter(|b| *b != b'-').count() as u64,
+            strand: self.strand,
+            src_size: self.src_size,
+            text,
+        }
+    }
+
+    /// Keep only the alignment columns where `keep` is true, and update `start` and `size` to
+    /// match.
+    ///
+    /// `start` moves to the first base kept, but a row that loses bases after it no longer covers
+    /// a contiguous region.
+    pub fn retain_columns(&mut self, keep: &[bool]) {
+        debug_assert_eq!(keep.len(), self.text.len());
+        let before = self
+            .text
+            .bytes()
+            .zip(keep)
+            .take_while(|(base, keep)| !**keep || *base == b'-')
+            .filter(|(base, keep)| !**keep && *base != b'-')
+            .count() as u64;
+        self.start += before;
+        self.text = self
+            .text
+            .chars()
+            .zip(keep)
+            .filter(|(_, keep)| **keep)
+            .map(|(c, _)| c)
+            .collect();
+        self.size = self.ungapped_len();
+    }
+}
+
 impl Display for MafLine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -112,24 +373,44 @@ impl Display for MafLine {
                 write!(f, "#{}", x)
             }
             MafLine::AlignmentBlockLine(x) => {
-                write!(f, "a{}", x)
+                write!(f, "a")?;
+                for (key, value) in x.pairs.iter() {
+                    if value.is_empty() {
+                        write!(f, " {}", key)?;
+                    } else {
+                        write!(f, " {}={}", key, value)?;
+                    }
+                }
+                Ok(())
             }
-            MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text) => {
+            MafLine::SequenceLine(seq) => {
                 write!(
                     f,
-                    "s {} {} {} {} {} {} {}",
-                    species,
-                    seqid,
-                    start,
-                    length,
-                    match strand {
-                        Strand::Plus => "+",
-                        Strand::Minus => "-",
-                    },
-                    src_size,
-                    text
+                    "s {} {} {} {} {} {}",
+                    seq.src, seq.start, seq.size, seq.strand, seq.src_size, seq.text
                 )
             }
+            MafLine::InfoLine(info) => {
+                write!(
+                    f,
+                    "i {} {} {} {} {}",
+                    info.src,
+                    info.left_status,
+                    info.left_count,
+                    info.right_status,
+                    info.right_count
+                )
+            }
+            MafLine::EmptyLine(e) => {
+                write!(
+                    f,
+                    "e {} {} {} {} {} {}",
+                    e.src, e.start, e.size, e.strand, e.src_size, e.status
+                )
+            }
+            MafLine::QualityLine(q) => {
+                write!(f, "q {} {}", q.src, q.quality)
+            }
             MafLine::BlankLine => {
                 write!(f, "")
             }
@@ -144,23 +425,37 @@ impl Debug for MafLine {
                 write!(f, "Comment: {}", x)
             }
             MafLine::AlignmentBlockLine(x) => {
-                write!(f, "AlignmentBlockLine {}", x)
+                write!(f, "AlignmentBlockLine {:?}", x.pairs)
             }
-            MafLine::SequenceLine(species, seqid, start, length, strand, src_size, _text) => {
+            MafLine::SequenceLine(seq) => {
                 write!(
                     f,
                     "SequenceLine {} {} {} {} {} {}",
-                    species,
-                    seqid,
-                    start,
-                    length,
-                    match strand {
-                        Strand::Plus => "+",
-                        Strand::Minus => "-",
-                    },
-                    src_size,
+                    seq.species, seq.contig, seq.start, seq.size, seq.strand, seq.src_size,
+                )
+            }
+            MafLine::InfoLine(info) => {
+                write!(
+                    f,
+                    "InfoLine {} {} {} {} {} {}",
+                    info.species,
+                    info.contig,
+                    info.left_status,
+                    info.left_count,
+                    info.right_status,
+                    info.right_count
+                )
+            }
+            MafLine::EmptyLine(e) => {
+                write!(
+                    f,
+                    "EmptyLine {} {} {} {} {} {} {}",
+                    e.species, e.contig, e.start, e.size, e.strand, e.src_size, e.status
                 )
             }
+            MafLine::QualityLine(q) => {
+                write!(f, "QualityLine {} {}", q.species, q.contig)
+            }
             MafLine::BlankLine => {
                 write!(f, "BlankLine")
             }
@@ -170,10 +465,7 @@ impl Debug for MafLine {
 
 impl MafLine {
     pub fn is_seqline(&self) -> bool {
-        match self {
-            MafLine::SequenceLine(_, _, _, _, _, _, _) => true,
-            _ => false,
-        }
+        matches!(self, MafLine::SequenceLine(_))
     }
 
     pub fn fasta_out(&self) -> String {
@@ -184,23 +476,23 @@ impl MafLine {
             MafLine::AlignmentBlockLine(_x) => {
                 panic!("Cannot convert alignment block to fasta")
             }
-            MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text) => {
+            MafLine::SequenceLine(seq) => {
                 let mut out = String::new();
                 out.push_str(&format!(
                     ">{}:{}-{} {} {}",
-                    seqid,
-                    start,
-                    start + length,
-                    match strand {
-                        Strand::Plus => "+",
-                        Strand::Minus => "-",
-                    },
-                    src_size,
+                    seq.src,
+                    seq.start,
+                    seq.end(),
+                    seq.strand,
+                    seq.src_size,
                 ));
-                out.push_str("\n");
-                out.push_str(text);
-                out.push_str("\n");
-                return out;
+                out.push('\n');
+                out.push_str(&seq.text);
+                out.push('\n');
+                out
+            }
+            MafLine::InfoLine(_) | MafLine::EmptyLine(_) | MafLine::QualityLine(_) => {
+                panic!("Cannot convert i, e or q line to fasta")
             }
             MafLine::BlankLine => {
                 panic!("Cannot convert blank line to fasta")
@@ -209,60 +501,200 @@ impl MafLine {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum Strand {
-    Plus,
-    Minus,
-}
-
-impl Display for Strand {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Strand::Plus => write!(f, "+"),
-            Strand::Minus => write!(f, "-"),
-        }
+// Parse one line, without its line ending. Lines of other types are `None`, to be ignored as the
+// MAF spec asks, so only blank lines end a block.
+pub(super) fn parse_maf_line(
+    line: &str,
+    genome_names: &[String],
+) -> Result<Option<MafLine>, String> {
+    if line.trim().is_empty() {
+        return Ok(Some(MafLine::BlankLine));
     }
-}
 
-fn parse_maf_line(line: &str) -> MafLine {
     // Match on the first character
-    match line.chars().next() {
+    let line = match line.chars().next() {
         Some('#') => {
             // Remove first character
-            return MafLine::Comment(line[1..].to_string());
+            MafLine::Comment(line[1..].to_string())
         }
         Some('a') => {
-            return MafLine::AlignmentBlockLine(line[1..].to_string());
+            // Key=value pairs, e.g. a score=0.000000 pass=2
+            let pairs = line[1..]
+                .split_whitespace()
+                .map(|pair| match pair.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => (pair.to_string(), String::new()),
+                })
+                .collect();
+            MafLine::AlignmentBlockLine(MafBlockHeader { pairs })
         }
         Some('s') => {
-            // Split by tabs and convert to:
-            // String, u64, u64, Strand, u64, String
+            // s src start size strand srcSize text
             let mut split = line.split_whitespace();
             let _ = split.next(); // Remove first character
-            let seqid = split.next().unwrap().to_string();
-
-            // Seqid is in the format species.chromosome
-            let species = seqid.split('.').next().unwrap().to_string();
-            let seqid = seqid.split('.').nth(1).unwrap().to_string();
-
-            let start = split.next().unwrap().parse::<u64>().unwrap();
-            let length = split.next().unwrap().parse::<u64>().unwrap();
-            let strand = match split.next().unwrap() {
-                "+" => Strand::Plus,
-                "-" => Strand::Minus,
-                _ => panic!("Invalid strand"),
+            let src = next_field(&mut split, "src")?.to_string();
+            let (species, contig) = split_src(&src, genome_names);
+
+            let start = next_u64(&mut split, "start")?;
+            let size = next_u64(&mut split, "size")?;
+            let strand = parse_strand(next_field(&mut split, "strand")?)?;
+            let src_size = next_u64(&mut split, "srcSize")?;
+            check_range(start, size, src_size)?;
+            let text = next_field(&mut split, "text")?.to_string();
+
+            let seq = MafSequence {
+                src,
+                species,
+                contig,
+                start,
+                size,
+                strand,
+                src_size,
+                text,
             };
-            let src_size = split.next().unwrap().parse::<u64>().unwrap();
-            let text = split.next().unwrap().to_string();
 
-            return MafLine::SequenceLine(species, seqid, start, length, strand, src_size, text);
+            if seq.ungapped_len() != seq.size {
+                return Err(format!(
+                    "Size {} does not match the {} bases in the alignment text",
+                    seq.size,
+                    seq.ungapped_len()
+                ));
+            }
+
+            MafLine::SequenceLine(seq)
         }
-        None => {
-            return MafLine::BlankLine;
+        Some('i') => {
+            // i src leftStatus leftCount rightStatus rightCount
+            let mut split = line.split_whitespace();
+            let _ = split.next();
+            let src = next_field(&mut split, "src")?.to_string();
+            let (species, contig) = split_src(&src, genome_names);
+
+            let left_status = parse_status(next_field(&mut split, "leftStatus")?)?;
+            let left_count = next_u64(&mut split, "leftCount")?;
+            let right_status = parse_status(next_field(&mut split, "rightStatus")?)?;
+            let right_count = next_u64(&mut split, "rightCount")?;
+
+            MafLine::InfoLine(MafInfo {
+                src,
+                species,
+                contig,
+                left_status,
+                left_count,
+                right_status,
+                right_count,
+            })
         }
-        _ => {
-            return MafLine::BlankLine;
+        Some('e') => {
+            // e src start size strand srcSize status
+            let mut split = line.split_whitespace();
+            let _ = split.next();
+            let src = next_field(&mut split, "src")?.to_string();
+            let (species, contig) = split_src(&src, genome_names);
+
+            let start = next_u64(&mut split, "start")?;
+            let size = next_u64(&mut split, "size")?;
+            let strand = parse_strand(next_field(&mut split, "strand")?)?;
+            let src_size = next_u64(&mut split, "srcSize")?;
+            check_range(start, size, src_size)?;
+            let status = parse_status(next_field(&mut split, "status")?)?;
+
+            MafLine::EmptyLine(MafEmpty {
+                src,
+                species,
+                contig,
+                start,
+                size,
+                strand,
+                src_size,
+                status,
+            })
         }
+        Some('q') => {
+            // q src quality
+            let mut split = line.split_whitespace();
+            let _ = split.next();
+            let src = next_field(&mut split, "src")?.to_string();
+            let (species, contig) = split_src(&src, genome_names);
+            let quality = next_field(&mut split, "quality")?.to_string();
+
+            MafLine::QualityLine(MafQuality {
+                src,
+                species,
+                contig,
+                quality,
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(line))
+}
+
+fn next_field<'a>(
+    split: &mut std::str::SplitWhitespace<'a>,
+    name: &str,
+) -> Result<&'a str, String> {
+    split
+        .next()
+        .ok_or_else(|| format!("Missing {} field", name))
+}
+
+fn next_u64(split: &mut std::str::SplitWhitespace, name: &str) -> Result<u64, String> {
+    let field = next_field(split, name)?;
+    field
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid {} '{}': {}", name, field, e))
+}
+
+// The aligning region must lie within the source sequence, or minus strand positions, counted
+// back from srcSize, would underflow
+fn check_range(start: u64, size: u64, src_size: u64) -> Result<(), String> {
+    match start.checked_add(size) {
+        Some(end) if end <= src_size => Ok(()),
+        _ => Err(format!(
+            "Start {} and size {} run past the srcSize {}",
+            start, size, src_size
+        )),
+    }
+}
+
+// Src is in the format species.chromosome, where the chromosome may itself contain dots
+// (e.g. Kakapo.NC_044298.1_ctg1). Known genome names are matched first (longest wins) so that
+// species names containing dots are also supported. A src without any dot is used as both.
+pub(super) fn split_src(src: &str, genome_names: &[String]) -> (String, String) {
+    let known = genome_names
+        .iter()
+        .filter(|name| {
+            src.len() > name.len()
+                && src.starts_with(name.as_str())
+                && src[name.len()..].starts_with('.')
+        })
+        .max_by_key(|name| name.len());
+
+    if let Some(name) = known {
+        return (name.clone(), src[name.len() + 1..].to_string());
+    }
+
+    let mut parts = src.splitn(2, '.');
+    let species = parts.next().unwrap().to_string();
+    let contig = parts.next().unwrap_or(&species).to_string();
+    (species, contig)
+}
+
+fn parse_strand(strand: &str) -> Result<Strand, String> {
+    match strand {
+        "+" => Ok(Strand::Plus),
+        "-" => Ok(Strand::Minus),
+        _ => Err(format!("Invalid strand '{}'", strand)),
+    }
+}
+
+// A status character of an i or e line, see MafInfo
+fn parse_status(status: &str) -> Result<char, String> {
+    let mut chars = status.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if "CINnMT".contains(c) => Ok(c),
+        _ => Err(format!("Invalid status '{}'", status)),
     }
 }
 
@@ -277,6 +709,29 @@ pub struct AlignmentBlock {
 }
 
 impl AlignmentBlock {
+    /// The `s` lines of a block, as read by [`MafReader`], `None` if there are none
+    pub fn from_lines(lines: Vec<MafLine>) -> Option<AlignmentBlock> {
+        let mut block = AlignmentBlock::default();
+        for x in lines {
+            // Only sequence lines are kept
+            if let MafLine::SequenceLine(seq) = &x {
+                block.species.push(seq.species.clone());
+                if block.lines.is_empty() {
+                    block.seqid = seq.contig.clone();
+                    block.start = seq.start;
+                    block.len = seq.size;
+                }
+                block.lines.push(x);
+            }
+        }
+
+        if block.lines.is_empty() {
+            None
+        } else {
+            Some(block)
+        }
+    }
+
     pub fn add_line(&mut self, line: MafLine) {
         self.lines.push(line);
     }
@@ -288,21 +743,413 @@ impl AlignmentBlock {
 
         let end = self.start + self.len;
         if pos >= self.start && pos <= end {
-            return ContainsResult::True;
+            ContainsResult::True
         } else if pos < self.start {
-            return ContainsResult::Before;
+            ContainsResult::Before
         } else {
-            return ContainsResult::After;
+            ContainsResult::After
+        }
+    }
+
+    /// Find the columns where any row has a different base from the reference (the first row).
+    ///
+    /// Columns where the reference has a gap or an ambiguous base are skipped. Bases are upper
+    /// cased, and complemented when the reference row is on the minus strand, so that they are
+    /// given relative to the forward strand of the reference.
+    pub fn extract_snps(&self) -> Vec<SnpSite> {
+        let complement = match self.lines.first() {
+            Some(MafLine::SequenceLine(reference)) => reference.strand == Strand::Minus,
+            _ => return Vec::new(),
+        };
+
+        let mut sites = Vec::new();
+
+        for column in self.columns() {
+            let bases: Vec<Option<u8>> = column
+                .bases
+                .iter()
+                .map(|base| {
+                    let base = base.to_ascii_uppercase();
+                    let base = if complement {
+                        complement_base(base)
+                    } else {
+                        base
+                    };
+                    match base {
+                        b'A' | b'C' | b'G' | b'T' => Some(base),
+                        _ => None,
+                    }
+                })
+                .collect();
+
+            let ref_base = match bases[0] {
+                Some(ref_base) => ref_base,
+                None => continue,
+            };
+
+            if bases.iter().flatten().any(|x| *x != ref_base) {
+                sites.push(SnpSite {
+                    pos: column.positions[0].unwrap(),
+                    ref_base,
+                    bases,
+                    positions: column.positions,
+                });
+            }
+        }
+
+        sites
+    }
+
+    /// Iterate over the alignment columns, with the position of each row's base
+    pub fn columns(&self) -> BlockColumns<'_> {
+        let rows: Vec<&MafSequence> = self
+            .lines
+            .iter()
+            .filter_map(|x| match x {
+                MafLine::SequenceLine(seq) => Some(seq),
+                _ => None,
+            })
+            .collect();
+
+        BlockColumns {
+            len: rows.iter().map(|x| x.aligned_len()).max().unwrap_or(0),
+            offsets: vec![0; rows.len()],
+            rows,
+            col: 0,
+        }
+    }
+}
+
+/// The columns of an [`AlignmentBlock`], from [`AlignmentBlock::columns`]
+pub struct BlockColumns<'a> {
+    rows: Vec<&'a MafSequence>,
+    // Bases seen so far in each row
+    offsets: Vec<u64>,
+    col: usize,
+    len: usize,
+}
+
+/// One column of an [`AlignmentBlock`]
+///
+/// `bases`, `positions` and `offsets` have one entry per `s` line of the block, in block order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockColumn {
+    /// Zero-based index of the column in the block
+    pub index: usize,
+    /// Base of each row as in the alignment text, `-` for gaps
+    pub bases: Vec<u8>,
+    /// Zero-based forward strand position of each row's base in its source sequence, `None` for
+    /// gaps. Minus strand rows count down from `src_size - start - 1` (see
+    /// [`MafSequence::forward_position`]).
+    pub positions: Vec<Option<u64>>,
+    /// Number of bases of each row before this column, so that `start + offset` is the offset (as
+    /// `start`, from the end of minus strand rows) of the row's base, or for a gap, of its next base
+    pub offsets: Vec<u64>,
+}
+
+impl Iterator for BlockColumns<'_> {
+    type Item = BlockColumn;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.col == self.len {
+            return None;
         }
+
+        let mut bases = Vec::with_capacity(self.rows.len());
+        let mut positions = Vec::with_capacity(self.rows.len());
+        let offsets = self.offsets.clone();
+        for (row, offset) in self.rows.iter().zip(self.offsets.iter_mut()) {
+            let base = row.text.as_bytes().get(self.col).copied().unwrap_or(b'-');
+            bases.push(base);
+            if base == b'-' {
+                positions.push(None);
+            } else {
+                positions.push(Some(row.forward_position(*offset)));
+                *offset += 1;
+            }
+        }
+
+        let column = BlockColumn {
+            index: self.col,
+            bases,
+            positions,
+            offsets,
+        };
+        self.col += 1;
+        Some(column)
     }
 
-    pub fn extract_snps(&self) {}
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.col;
+        (remaining, Some(remaining))
+    }
+}
+
+/// A variable column of an alignment block, from [`AlignmentBlock::extract_snps`]
+///
+/// `bases` and `positions` have one entry per row of the block, in block order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnpSite {
+    /// Zero-based position on the forward strand of the reference sequence
+    pub pos: u64,
+    /// Upper case reference base
+    pub ref_base: u8,
+    /// Base of each row, `None` for gaps and ambiguous bases
+    pub bases: Vec<Option<u8>>,
+    /// Zero-based forward strand position of each row in its own source sequence, `None` for gaps
+    pub positions: Vec<Option<u64>>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum ContainsResult {
-    True,
-    Before,
-    After,
-    WrongChrom,
+fn complement_base(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        x => x,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.maf");
+
+    fn parse(line: &str) -> MafLine {
+        parse_maf_line(line, &[]).ok().unwrap().unwrap()
+    }
+
+    fn error(line: &str) -> String {
+        parse_maf_line(line, &[]).err().unwrap()
+    }
+
+    #[test]
+    fn info_empty_quality_lines() {
+        match parse("i panTro1.chr6 N 0 C 12") {
+            MafLine::InfoLine(x) => assert_eq!(
+                x,
+                MafInfo {
+                    src: "panTro1.chr6".to_string(),
+                    species: "panTro1".to_string(),
+                    contig: "chr6".to_string(),
+                    left_status: 'N',
+                    left_count: 0,
+                    right_status: 'C',
+                    right_count: 12,
+                }
+            ),
+            _ => panic!("Not an i line"),
+        }
+        match parse("e rn3.chr4 81444246 6 - 187371129 I") {
+            MafLine::EmptyLine(x) => assert_eq!(
+                x,
+                MafEmpty {
+                    src: "rn3.chr4".to_string(),
+                    species: "rn3".to_string(),
+                    contig: "chr4".to_string(),
+                    start: 81444246,
+                    size: 6,
+                    strand: Strand::Minus,
+                    src_size: 187371129,
+                    status: 'I',
+                }
+            ),
+            _ => panic!("Not an e line"),
+        }
+        match parse("q panTro1.chr6   99-9F") {
+            MafLine::QualityLine(x) => {
+                assert_eq!((x.species.as_str(), x.contig.as_str()), ("panTro1", "chr6"));
+                assert_eq!(x.quality, "99-9F");
+            }
+            _ => panic!("Not a q line"),
+        }
+    }
+
+    #[test]
+    fn unknown_lines_are_ignored() {
+        let maf = "##maf version=1\n\n\
+                   a score=1\n\
+                   s hg38.chr1 0 2 + 100 AC\n\
+                   x some future line type\n\
+                   s mm10.chr1 0 2 + 100 AC\n\
+                   \x20\t\n\
+                   a score=2\n\
+                   s hg38.chr1 2 2 + 100 GT\n";
+        assert!(matches!(
+            parse_maf_line("x some future line type", &[]),
+            Ok(None)
+        ));
+
+        // The unknown line is dropped without ending its block, a whitespace only line does
+        let blocks: Vec<Vec<MafLine>> = MafReader::new(maf.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let lines: Vec<Vec<String>> = blocks
+            .iter()
+            .map(|block| block.iter().map(|x| x.to_string()).collect())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                vec![
+                    "a score=1",
+                    "s hg38.chr1 0 2 + 100 AC",
+                    "s mm10.chr1 0 2 + 100 AC"
+                ],
+                vec!["a score=2", "s hg38.chr1 2 2 + 100 GT"],
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_block_is_skipped() {
+        let maf = "##maf version=1\n\n\
+                   a score=1\n\
+                   s hg38.chr1 0 2 + 100 AC\n\
+                   s mm10.chr1 x 2 + 100 AC\n\
+                   s rn6.chr1 y 2 + 100 AC\n\n\
+                   a score=2\n\
+                   s hg38.chr1 2 2 + 100 GT\n";
+        let mut results = MafReader::new(maf.as_bytes()).with_file_name("test.maf");
+
+        // The error names the file and line, the rest of its block is dropped
+        let e = results.next().unwrap().err().unwrap();
+        match &e {
+            OxidMafError::Malformed { file, line, .. } => {
+                assert_eq!((file.as_str(), *line), ("test.maf", 5))
+            }
+            e => panic!("{}", e),
+        }
+        assert!(
+            e.to_string().starts_with("test.maf:5: Invalid start 'x'"),
+            "{}",
+            e
+        );
+
+        let block = results.next().unwrap().ok().unwrap();
+        assert_eq!(block[0].to_string(), "a score=2");
+        assert!(results.next().is_none());
+        assert_eq!(results.header().len(), 1);
+    }
+
+    #[test]
+    fn dotted_srcs() {
+        let split = |src: &str, names: &[&str]| {
+            let names: Vec<String> = names.iter().map(|x| x.to_string()).collect();
+            split_src(src, &names)
+        };
+        let pair = |species: &str, contig: &str| (species.to_string(), contig.to_string());
+
+        // Contigs keep their dots
+        assert_eq!(
+            split("Kakapo.NC_044298.1_ctg1", &[]),
+            pair("Kakapo", "NC_044298.1_ctg1")
+        );
+        assert_eq!(
+            split("Kakapo.NC_044298.1_ctg1", &["Kakapo"]),
+            pair("Kakapo", "NC_044298.1_ctg1")
+        );
+
+        // Genome names with dots, the longest matching one winning
+        assert_eq!(
+            split("Homo.sapiens.chr1", &[]),
+            pair("Homo", "sapiens.chr1")
+        );
+        assert_eq!(
+            split("Homo.sapiens.NC_000001.11", &["Homo", "Homo.sapiens"]),
+            pair("Homo.sapiens", "NC_000001.11")
+        );
+
+        // Names only match up to a dot, and never the whole src
+        assert_eq!(
+            split("Kakapo.NC_044298.1_ctg1", &["Kaka"]),
+            pair("Kakapo", "NC_044298.1_ctg1")
+        );
+        assert_eq!(split("Kakapo", &["Kakapo"]), pair("Kakapo", "Kakapo"));
+
+        let names = vec!["Homo.sapiens".to_string()];
+        match parse_maf_line("s Homo.sapiens.NC_000001.11 0 2 + 100 AC", &names) {
+            Ok(Some(MafLine::SequenceLine(seq))) => {
+                assert_eq!(seq.src, "Homo.sapiens.NC_000001.11");
+                assert_eq!(
+                    (seq.species.as_str(), seq.contig.as_str()),
+                    ("Homo.sapiens", "NC_000001.11")
+                );
+            }
+            _ => panic!("Not an s line"),
+        }
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for (line, message) in [
+            ("i panTro1.chr6 N", "Missing leftCount field"),
+            ("i panTro1.chr6 N x C 0", "Invalid leftCount 'x'"),
+            ("i panTro1.chr6 N 0 CC 0", "Invalid status 'CC'"),
+            ("i panTro1.chr6 N 0 X 0", "Invalid status 'X'"),
+            ("i panTro1.chr6 N 0 C -1", "Invalid rightCount '-1'"),
+            ("e rn3.chr4 81444246 6 + 187371129", "Missing status field"),
+            ("e rn3.chr4 81444246 6 . 187371129 I", "Invalid strand '.'"),
+            ("e rn3.chr4 start 6 + 187371129 I", "Invalid start 'start'"),
+            ("q panTro1.chr6", "Missing quality field"),
+            (
+                "s hg38.chr1 0 3 + 100 AC-",
+                "Size 3 does not match the 2 bases",
+            ),
+            (
+                "s hg38.chr1 99 2 - 100 AC",
+                "Start 99 and size 2 run past the srcSize 100",
+            ),
+            (
+                "e rn3.chr4 10 6 - 12 I",
+                "Start 10 and size 6 run past the srcSize 12",
+            ),
+            (
+                "s hg38.chr1 18446744073709551615 2 + 100 AC",
+                "Start 18446744073709551615 and size 2 run past",
+            ),
+        ] {
+            let e = error(line);
+            assert!(e.starts_with(message), "{}: {}", line, e);
+        }
+    }
+
+    #[test]
+    fn minus_strand_ranges() {
+        // The last bases of the source sequence, counted from its end
+        let seq = match parse("s rn6.chr1 98 2 - 100 AC") {
+            MafLine::SequenceLine(seq) => seq,
+            _ => panic!("Not an s line"),
+        };
+        assert_eq!(seq.forward_range(), 0..2);
+        assert_eq!((seq.forward_position(0), seq.forward_position(1)), (1, 0));
+
+        // One base further is reported, rather than underflowing
+        let maf = "a\ns hg38.chr1 0 2 + 100 AC\ns rn6.chr1 99 2 - 100 AC\n";
+        let e = MafReader::new(maf.as_bytes()).next().unwrap().unwrap_err();
+        assert!(e.is_malformed());
+        assert!(e.to_string().contains(":3: Start 99 and size 2"), "{}", e);
+    }
+
+    #[test]
+    fn columns_skip_gaps() {
+        let mut parser = MafParser::from_path(MAF).unwrap();
+        let block = parser.alignment_blocks().next().unwrap().unwrap();
+
+        // rn6 is on the minus strand of a 300bp sequence, starting 10 bases from its end
+        let columns: Vec<(String, Vec<Option<u64>>)> = block
+            .columns()
+            .map(|x| (String::from_utf8(x.bases).unwrap(), x.positions))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("AAA".to_string(), vec![Some(100), Some(50), Some(289)]),
+                ("CC-".to_string(), vec![Some(101), Some(51), None]),
+                ("-TT".to_string(), vec![None, Some(52), Some(288)]),
+                ("GGG".to_string(), vec![Some(102), Some(53), Some(287)]),
+            ]
+        );
+    }
 }
@@ -0,0 +1,566 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error as IoError, ErrorKind, Write};
+use std::ops::Range;
+
+use super::maf::parse_maf_line;
+use super::{IndexedInput, MafLine, MafReader};
+use crate::OxidMafError;
+
+/// One alignment block in a [`MafIndex`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MafIndexEntry {
+    /// Species of the reference (first `s` line) of the block
+    pub species: String,
+    pub contig: String,
+    /// Aligning region of the reference, zero-based and half-open on the forward strand
+    pub start: u64,
+    pub end: u64,
+    /// Offset of the start of the block. For bgzipped files, this is the BGZF virtual offset.
+    pub offset: u64,
+}
+
+/// Index of the blocks of a MAF file by reference coordinate, stored as `<file>.mafi`
+///
+/// The index file starts with a `##mafi version=1 compression=<none|bgzf>` line, followed by one
+/// tab separated line per block: species, contig, start, end and offset.
+///
+/// Plain text and bgzipped MAF files can be indexed. gzip and zstd compressed files cannot be
+/// seeked, and should be recompressed with `bgzip`.
+#[derive(Debug, Default)]
+pub struct MafIndex {
+    pub bgzf: bool,
+    // Entries of each reference species and contig, sorted by start
+    entries: HashMap<(String, String), Vec<MafIndexEntry>>,
+    // The greatest end of the entries up to and including each one, set by sort, as blocks may
+    // overlap
+    max_ends: HashMap<(String, String), Vec<u64>>,
+    // Reference species of the first block, set by sort
+    reference: Option<String>,
+}
+
+impl MafIndex {
+    /// Scan a MAF file, recording the reference region and offset of every block
+    pub fn build(path: &str, genome_names: &[String]) -> Result<Self, OxidMafError> {
+        let mut input = IndexedInput::open(path)?;
+
+        let mut index = MafIndex {
+            bgzf: matches!(input, IndexedInput::Bgzf(_)),
+            ..Default::default()
+        };
+
+        let mut line = String::new();
+        let mut line_number = 0;
+        // Offset of the first line of the current block, and whether its reference is known
+        let mut block_offset = None;
+        let mut has_reference = false;
+
+        loop {
+            let offset = input.tell();
+            line.clear();
+            match input.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => line_number += 1,
+                Err(e) => return Err(OxidMafError::io(path, e)),
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.trim().is_empty() {
+                block_offset = None;
+                has_reference = false;
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let block_offset = *block_offset.get_or_insert(offset);
+
+            if !has_reference && line.starts_with('s') {
+                let seq = match parse_maf_line(line, genome_names) {
+                    Ok(Some(MafLine::SequenceLine(seq))) => seq,
+                    Ok(_) => unreachable!(),
+                    Err(message) => {
+                        return Err(OxidMafError::malformed(path, line_number, line, message))
+                    }
+                };
+
+                let forward = seq.forward_range();
+                index.push(MafIndexEntry {
+                    species: seq.species,
+                    contig: seq.contig,
+                    start: forward.start,
+                    end: forward.end,
+                    offset: block_offset,
+                });
+                has_reference = true;
+            }
+        }
+
+        index.sort();
+        Ok(index)
+    }
+
+    /// Load an index written by [`MafIndex::write`]
+    pub fn from_file(path: &str) -> Result<Self, OxidMafError> {
+        let file = File::open(path).map_err(|e| OxidMafError::io(path, e))?;
+        let mut index = MafIndex::default();
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| OxidMafError::io(path, e))?;
+            let malformed =
+                |message: String| OxidMafError::malformed(path, i as u64 + 1, &line, message);
+
+            if i == 0 {
+                if !line.starts_with("##mafi") {
+                    return Err(malformed("Missing ##mafi header".to_string()));
+                }
+                index.bgzf = line.split_whitespace().any(|x| x == "compression=bgzf");
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 5 {
+                return Err(malformed("Expected 5 tab separated columns".to_string()));
+            }
+            let number = |x: &str| x.parse::<u64>().map_err(|e| malformed(e.to_string()));
+
+            index.push(MafIndexEntry {
+                species: fields[0].to_string(),
+                contig: fields[1].to_string(),
+                start: number(fields[2])?,
+                end: number(fields[3])?,
+                offset: number(fields[4])?,
+            });
+        }
+
+        index.sort();
+        Ok(index)
+    }
+
+    /// Write the index to `path`, normally `<maf>.mafi`
+    pub fn write(&self, path: &str) -> Result<(), OxidMafError> {
+        let file = File::create(path).map_err(|e| OxidMafError::io(path, e))?;
+        let mut out = BufWriter::new(file);
+
+        let mut write = || -> Result<(), IoError> {
+            let compression = if self.bgzf { "bgzf" } else { "none" };
+            writeln!(out, "##mafi version=1 compression={}", compression)?;
+
+            // In file order
+            let mut entries: Vec<&MafIndexEntry> = self.entries.values().flatten().collect();
+            entries.sort_by_key(|x| x.offset);
+            for x in entries {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}",
+                    x.species, x.contig, x.start, x.end, x.offset
+                )?;
+            }
+            out.flush()
+        };
+
+        write().map_err(|e| OxidMafError::io(path, e))
+    }
+
+    /// Blocks whose reference is `contig` of `species` and overlaps `range` (zero-based,
+    /// half-open), in order of reference start
+    pub fn overlapping(
+        &self,
+        species: &str,
+        contig: &str,
+        range: Range<u64>,
+    ) -> Vec<&MafIndexEntry> {
+        let key = (species.to_string(), contig.to_string());
+        let (entries, max_ends) = match (self.entries.get(&key), self.max_ends.get(&key)) {
+            (Some(entries), Some(max_ends)) => (entries, max_ends),
+            _ => return Vec::new(),
+        };
+
+        // No entry before the first whose running max end is past the start can overlap
+        let first = max_ends.partition_point(|x| *x <= range.start);
+        let last = entries.partition_point(|x| x.start < range.end);
+        entries[first..last.max(first)]
+            .iter()
+            .filter(|x| x.end > range.start)
+            .collect()
+    }
+
+    /// True if `species` is the reference of any indexed block
+    pub fn has_species(&self, species: &str) -> bool {
+        self.entries.keys().any(|(x, _)| x == species)
+    }
+
+    /// The reference species of the first block in the file
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    fn push(&mut self, entry: MafIndexEntry) {
+        self.entries
+            .entry((entry.species.clone(), entry.contig.clone()))
+            .or_default()
+            .push(entry);
+    }
+
+    fn sort(&mut self) {
+        self.max_ends.clear();
+        for (key, entries) in self.entries.iter_mut() {
+            entries.sort_by_key(|x| (x.start, x.offset));
+            let max_ends = entries
+                .iter()
+                .scan(0, |max, x| {
+                    *max = x.end.max(*max);
+                    Some(*max)
+                })
+                .collect();
+            self.max_ends.insert(key.clone(), max_ends);
+        }
+        self.reference = self
+            .entries
+            .values()
+            .flatten()
+            .min_by_key(|x| x.offset)
+            .map(|x| x.species.clone());
+    }
+}
+
+/// Random access to the blocks of an indexed MAF file by reference coordinate
+///
+/// ```ignore
+/// let mut reader = MafIndexedReader::from_path("cactus.maf.gz")?;
+/// for block in reader.fetch("hg38", "chr1", 10_000..20_000)? {
+///     ...
+/// }
+/// ```
+pub struct MafIndexedReader {
+    index: MafIndex,
+    reader: MafReader<IndexedInput<File>>,
+    file: String,
+}
+
+impl MafIndexedReader {
+    /// Open a MAF file with its index at `<path>.mafi`
+    pub fn from_path(path: &str) -> Result<Self, OxidMafError> {
+        let index = MafIndex::from_file(&format!("{}.mafi", path))?;
+        MafIndexedReader::with_index(path, index)
+    }
+
+    pub fn with_index(path: &str, index: MafIndex) -> Result<Self, OxidMafError> {
+        let input = IndexedInput::open(path)?;
+        if index.bgzf != matches!(input, IndexedInput::Bgzf(_)) {
+            let e = IoError::new(
+                ErrorKind::InvalidData,
+                "Index compression does not match the file, rebuild the index",
+            );
+            return Err(OxidMafError::io(path, e));
+        }
+
+        let mut reader = MafReader::new(input).with_file_name(path);
+
+        // Read the first block, for the header
+        if let Some(Err(e)) = reader.next() {
+            return Err(e);
+        }
+
+        Ok(MafIndexedReader {
+            index,
+            reader,
+            file: path.to_string(),
+        })
+    }
+
+    /// See [`MafReader::with_genome_names`]
+    pub fn with_genome_names(mut self, genome_names: &[String]) -> Self {
+        self.reader = self.reader.with_genome_names(genome_names);
+        self
+    }
+
+    pub fn index(&self) -> &MafIndex {
+        &self.index
+    }
+
+    /// Comment lines preceding the first block, see [`MafReader::header`]
+    pub fn header(&self) -> &[MafLine] {
+        self.reader.header()
+    }
+
+    /// Read the blocks whose reference is `contig` of `species` and overlaps `range` (zero-based,
+    /// half-open), in order of reference start
+    pub fn fetch(
+        &mut self,
+        species: &str,
+        contig: &str,
+        range: Range<u64>,
+    ) -> Result<Vec<Vec<MafLine>>, OxidMafError> {
+        let offsets: Vec<u64> = self
+            .index
+            .overlapping(species, contig, range)
+            .iter()
+            .map(|x| x.offset)
+            .collect();
+
+        let mut blocks = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            self.seek_to(offset)?;
+
+            match self.reader.next() {
+                Some(Ok(block)) => blocks.push(block),
+                Some(Err(e)) => return Err(e),
+                None => {
+                    let e = IoError::new(
+                        ErrorKind::UnexpectedEof,
+                        "Index points past the end of the file, rebuild the index",
+                    );
+                    return Err(OxidMafError::io(&self.file, e));
+                }
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /// Move to the first block on `contig` whose reference ends after `pos` (zero-based), so that
+    /// [`MafIndexedReader::next_block`] continues from it in file order. False if there is none.
+    ///
+    /// Only blocks whose reference is the species of the first block, see [`MafIndex::reference`],
+    /// are sought, as blocks of other species may have contigs of the same name.
+    pub fn seek(&mut self, contig: &str, pos: u64) -> Result<bool, OxidMafError> {
+        let species = match self.index.reference() {
+            Some(species) => species,
+            None => return Ok(false),
+        };
+        let offset = match self
+            .index
+            .overlapping(species, contig, pos..u64::MAX)
+            .first()
+        {
+            Some(entry) => entry.offset,
+            None => return Ok(false),
+        };
+        self.seek_to(offset)?;
+        Ok(true)
+    }
+
+    /// The block after the last one read, fetched or seeked to
+    pub fn next_block(&mut self) -> Option<Result<Vec<MafLine>, OxidMafError>> {
+        self.reader.next()
+    }
+
+    fn seek_to(&mut self, offset: u64) -> Result<(), OxidMafError> {
+        self.reader
+            .get_mut()
+            .seek_to(offset)
+            .map_err(|e| OxidMafError::io(&self.file, e))?;
+        self.reader.reset();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::BgzfWriter;
+
+    const HEADER: &str = "##maf version=1\n\n";
+
+    // hg38 blocks on chr1 every 10 bases from 0 to 60, with a block of mm10 on its own chr1 after
+    // the third. The hg38 blocks also have a row on mm10's chr1.
+    fn blocks() -> Vec<String> {
+        let mut blocks = Vec::new();
+        for i in 0..6 {
+            blocks.push(format!(
+                "a score=0\ns hg38.chr1 {} 10 + 100 ACGTACGTAC\ns mm10.chr1 {} 10 - 100 ACGTACGTAC\n\n",
+                10 * i,
+                10 * i
+            ));
+            if i == 2 {
+                blocks.push(
+                    "a score=0\ns mm10.chr1 80 10 + 100 ACGTACGTAC\ns hg38.chr2 0 10 + 50 ACGTACGTAC\n\n"
+                        .to_string(),
+                );
+            }
+        }
+        blocks
+    }
+
+    // src and start of the reference of each block
+    fn starts(blocks: Vec<Vec<MafLine>>) -> Vec<(String, u64)> {
+        blocks
+            .iter()
+            .map(|block| {
+                block
+                    .iter()
+                    .find_map(|x| match x {
+                        MafLine::SequenceLine(seq) => Some((seq.src.clone(), seq.start)),
+                        _ => None,
+                    })
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn start(src: &str, start: u64) -> (String, u64) {
+        (src.to_string(), start)
+    }
+
+    // Index `path`, check the index reads back the same and fetch and seek blocks through it
+    fn round_trip(path: &str) -> MafIndex {
+        let index = MafIndex::build(path, &[]).unwrap();
+        let mafi = format!("{}.mafi", path);
+        index.write(&mafi).unwrap();
+        let loaded = MafIndex::from_file(&mafi).unwrap();
+
+        let entries = |x: &MafIndex| -> Vec<MafIndexEntry> {
+            let mut entries: Vec<MafIndexEntry> = x.entries.values().flatten().cloned().collect();
+            entries.sort_by_key(|x| x.offset);
+            entries
+        };
+        assert_eq!(entries(&loaded), entries(&index));
+        assert_eq!(entries(&index).len(), 7);
+        assert_eq!(loaded.bgzf, index.bgzf);
+        assert_eq!(loaded.reference(), Some("hg38"));
+
+        let mut reader = MafIndexedReader::from_path(path).unwrap();
+        let blocks = reader.fetch("hg38", "chr1", 25..35).unwrap();
+        assert_eq!(
+            starts(blocks),
+            vec![start("hg38.chr1", 20), start("hg38.chr1", 30)]
+        );
+
+        // Only blocks with mm10 as the reference
+        let blocks = reader.fetch("mm10", "chr1", 0..100).unwrap();
+        assert_eq!(starts(blocks), vec![start("mm10.chr1", 80)]);
+        assert!(reader.fetch("hg38", "chr2", 0..100).unwrap().is_empty());
+
+        // Seeking on hg38, the reference of the first block, then reading on in file order
+        assert!(reader.seek("chr1", 25).unwrap());
+        let blocks = (0..3)
+            .map(|_| reader.next_block().unwrap().unwrap())
+            .collect();
+        assert_eq!(
+            starts(blocks),
+            vec![
+                start("hg38.chr1", 20),
+                start("mm10.chr1", 80),
+                start("hg38.chr1", 30)
+            ]
+        );
+        assert!(!reader.seek("chr1", 60).unwrap());
+        assert!(!reader.seek("chr2", 0).unwrap());
+
+        index
+    }
+
+    #[test]
+    fn plain() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_mafi_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plain.maf");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, format!("{}{}", HEADER, blocks().concat())).unwrap();
+
+        let index = round_trip(path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!index.bgzf);
+        let first = index.overlapping("hg38", "chr1", 0..1);
+        assert_eq!(first[0].offset, HEADER.len() as u64);
+    }
+
+    #[test]
+    fn overlapping_blocks() {
+        let mut index = MafIndex::default();
+        for (i, (start, end)) in [(30, 40), (0, 100), (10, 20), (50, 55)].iter().enumerate() {
+            index.push(MafIndexEntry {
+                species: "hg38".to_string(),
+                contig: "chr1".to_string(),
+                start: *start,
+                end: *end,
+                offset: i as u64,
+            });
+        }
+        index.sort();
+
+        let ranges = |range: Range<u64>| -> Vec<(u64, u64)> {
+            index
+                .overlapping("hg38", "chr1", range)
+                .iter()
+                .map(|x| (x.start, x.end))
+                .collect()
+        };
+        // The long block is found past the end of the short ones it contains
+        assert_eq!(ranges(60..70), vec![(0, 100)]);
+        assert_eq!(ranges(15..35), vec![(0, 100), (10, 20), (30, 40)]);
+        assert_eq!(ranges(54..200), vec![(0, 100), (50, 55)]);
+        assert_eq!(ranges(100..200), vec![]);
+        assert!(index.overlapping("hg38", "chr2", 0..100).is_empty());
+    }
+
+    #[test]
+    fn bgzf() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_mafi_bgzf_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bgzf.maf.gz");
+        let path = path.to_str().unwrap();
+
+        // Every other block starts a BGZF block, the others start within one
+        let mut out = BgzfWriter::new(File::create(path).unwrap());
+        out.write_all(HEADER.as_bytes()).unwrap();
+        for (i, block) in blocks().iter().enumerate() {
+            if i % 2 == 0 {
+                out.flush().unwrap();
+            }
+            out.write_all(block.as_bytes()).unwrap();
+        }
+        out.finish().unwrap();
+
+        let index = round_trip(path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(index.bgzf);
+        let offsets: Vec<u64> = index
+            .overlapping("hg38", "chr1", 0..100)
+            .iter()
+            .map(|x| x.offset)
+            .collect();
+        assert!(offsets.iter().any(|x| x & 0xffff == 0 && *x > 0));
+        assert!(offsets.iter().any(|x| x & 0xffff != 0 && x >> 16 > 0));
+    }
+
+    #[test]
+    fn malformed_index() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_mafi_bad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bad.maf.mafi");
+        let path = path.to_str().unwrap();
+
+        std::fs::write(
+            path,
+            "##mafi version=1 compression=none\nhg38\tchr1\t0\t10\n",
+        )
+        .unwrap();
+        let short = MafIndex::from_file(path).unwrap_err();
+        std::fs::write(path, "hg38\tchr1\t0\t10\t0\n").unwrap();
+        let header = MafIndex::from_file(path).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match short {
+            OxidMafError::Malformed { line, message, .. } => {
+                assert_eq!(
+                    (line, message.as_str()),
+                    (2, "Expected 5 tab separated columns")
+                )
+            }
+            e => panic!("{}", e),
+        }
+        assert!(
+            header.to_string().contains("Missing ##mafi header"),
+            "{}",
+            header
+        );
+    }
+}
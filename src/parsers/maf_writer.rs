@@ -0,0 +1,243 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{BufWriter, Error as IoError, Write};
+
+use super::MafLine;
+
+/// Writes MAF blocks, padding rows into aligned columns as the UCSC tools do
+///
+/// ```text
+/// a score=23262.0 pass=2
+/// s hg18.chr7    27578828 22 + 158545518 AAA-GGGAATGTTAACCAAATGA
+/// s panTro1.chr6 28741140 22 + 161576975 AAA-GGGAATGTTAACCAAATGA
+/// q panTro1.chr6                         99999999999999999999999
+/// i panTro1.chr6 N 0 C 0
+/// e rn3.chr4     81444246  6 + 187371129 I
+/// ```
+///
+/// Each block is formatted in memory and written with a single call to the buffered output.
+pub struct MafWriter<W: Write> {
+    inner: BufWriter<W>,
+    buf: String,
+}
+
+impl<W: Write> MafWriter<W> {
+    pub fn new(inner: W) -> Self {
+        MafWriter {
+            inner: BufWriter::new(inner),
+            buf: String::new(),
+        }
+    }
+
+    /// Write the header comment lines, followed by a blank line.
+    ///
+    /// A `##maf version=1` line is written first when the header does not contain one.
+    pub fn write_header(&mut self, header: &[MafLine]) -> Result<(), IoError> {
+        let has_version = header
+            .iter()
+            .any(|x| matches!(x, MafLine::Comment(c) if c.starts_with("#maf")));
+        if !has_version {
+            writeln!(self.inner, "##maf version=1")?;
+        }
+
+        for line in header {
+            if let MafLine::Comment(_) = line {
+                writeln!(self.inner, "{}", line)?;
+            }
+        }
+        writeln!(self.inner)
+    }
+
+    /// Write an alignment block, followed by a blank line.
+    ///
+    /// An `a` line is added if the block does not start with one.
+    pub fn write_block(&mut self, block: &[MafLine]) -> Result<(), IoError> {
+        self.buf.clear();
+        format_block(&mut self.buf, block);
+        self.inner.write_all(self.buf.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+
+// Column widths of a block, so that rows line up
+#[derive(Default)]
+struct Widths {
+    src: usize,
+    start: usize,
+    size: usize,
+    src_size: usize,
+}
+
+fn digits(x: u64) -> usize {
+    x.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+fn format_block(out: &mut String, block: &[MafLine]) {
+    let mut w = Widths::default();
+    for line in block {
+        match line {
+            MafLine::SequenceLine(seq) => {
+                w.src = w.src.max(seq.src.len());
+                w.start = w.start.max(digits(seq.start));
+                w.size = w.size.max(digits(seq.size));
+                w.src_size = w.src_size.max(digits(seq.src_size));
+            }
+            MafLine::EmptyLine(e) => {
+                w.src = w.src.max(e.src.len());
+                w.start = w.start.max(digits(e.start));
+                w.size = w.size.max(digits(e.size));
+                w.src_size = w.src_size.max(digits(e.src_size));
+            }
+            MafLine::InfoLine(i) => w.src = w.src.max(i.src.len()),
+            MafLine::QualityLine(q) => w.src = w.src.max(q.src.len()),
+            _ => (),
+        }
+    }
+
+    let mut has_a_line = false;
+    for line in block {
+        // Rows need an a line before them
+        if !has_a_line && !matches!(line, MafLine::Comment(_) | MafLine::BlankLine) {
+            if !matches!(line, MafLine::AlignmentBlockLine(_)) {
+                out.push_str("a\n");
+            }
+            has_a_line = true;
+        }
+
+        // Writing to a String does not fail
+        match line {
+            MafLine::Comment(_) | MafLine::AlignmentBlockLine(_) => {
+                writeln!(out, "{}", line).unwrap();
+            }
+            MafLine::SequenceLine(seq) => {
+                writeln!(
+                    out,
+                    "s {:<src$} {:>start$} {:>size$} {} {:>src_size$} {}",
+                    seq.src,
+                    seq.start,
+                    seq.size,
+                    seq.strand,
+                    seq.src_size,
+                    seq.text,
+                    src = w.src,
+                    start = w.start,
+                    size = w.size,
+                    src_size = w.src_size,
+                )
+                .unwrap();
+            }
+            MafLine::QualityLine(q) => {
+                // Align the quality values with the sequence text above
+                let width = w.src + w.start + w.size + w.src_size + 5;
+                writeln!(out, "q {:<width$} {}", q.src, q.quality, width = width).unwrap();
+            }
+            MafLine::InfoLine(i) => {
+                writeln!(
+                    out,
+                    "i {:<src$} {} {} {} {}",
+                    i.src,
+                    i.left_status,
+                    i.left_count,
+                    i.right_status,
+                    i.right_count,
+                    src = w.src,
+                )
+                .unwrap();
+            }
+            MafLine::EmptyLine(e) => {
+                writeln!(
+                    out,
+                    "e {:<src$} {:>start$} {:>size$} {} {:>src_size$} {}",
+                    e.src,
+                    e.start,
+                    e.size,
+                    e.strand,
+                    e.src_size,
+                    e.status,
+                    src = w.src,
+                    start = w.start,
+                    size = w.size,
+                    src_size = w.src_size,
+                )
+                .unwrap();
+            }
+            MafLine::BlankLine => (),
+        }
+    }
+
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::MafReader;
+
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/ref_indels.maf");
+
+    // Each line of each block, as parsed
+    fn lines(blocks: &[Vec<MafLine>]) -> Vec<Vec<String>> {
+        blocks
+            .iter()
+            .map(|x| x.iter().map(|x| x.to_string()).collect())
+            .collect()
+    }
+
+    fn write(header: &[MafLine], blocks: &[Vec<MafLine>]) -> String {
+        let mut out = Vec::new();
+        let mut writer = MafWriter::new(&mut out);
+        writer.write_header(header).unwrap();
+        for block in blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut reader = MafReader::new(std::io::BufReader::new(std::fs::File::open(MAF).unwrap()));
+        let blocks: Vec<Vec<MafLine>> = reader.by_ref().map(|x| x.unwrap()).collect();
+        let written = write(reader.header(), &blocks);
+
+        let mut reread = MafReader::new(written.as_bytes());
+        let reread_blocks: Vec<Vec<MafLine>> = reread.by_ref().map(|x| x.unwrap()).collect();
+        assert_eq!(lines(&reread_blocks), lines(&blocks));
+        assert_eq!(reread.header().len(), 1);
+
+        // s, q, i and e lines all survive
+        let kinds: String = lines(&reread_blocks)[0]
+            .iter()
+            .map(|x| x.chars().next().unwrap())
+            .collect();
+        assert_eq!(kinds, "assqisqie");
+    }
+
+    #[test]
+    fn aligned_columns() {
+        let block = "a score=23262.0 pass=2\n\
+                     s hg18.chr7 27578828 22 + 158545518 AAA-GGGAATGTTAACCAAATGA\n\
+                     s panTro1.chr6 28741140 22 + 161576975 AAA-GGGAATGTTAACCAAATGA\n\
+                     q panTro1.chr6 99999999999999999999999\n\
+                     i panTro1.chr6 N 0 C 0\n\
+                     e rn3.chr4 81444246 6 + 187371129 I\n";
+        let blocks: Vec<Vec<MafLine>> = MafReader::new(block.as_bytes())
+            .map(|x| x.unwrap())
+            .collect();
+
+        // No ##maf line in the input
+        assert_eq!(
+            write(&[], &blocks),
+            "##maf version=1\n\n\
+             a score=23262.0 pass=2\n\
+             s hg18.chr7    27578828 22 + 158545518 AAA-GGGAATGTTAACCAAATGA\n\
+             s panTro1.chr6 28741140 22 + 161576975 AAA-GGGAATGTTAACCAAATGA\n\
+             q panTro1.chr6                         99999999999999999999999\n\
+             i panTro1.chr6 N 0 C 0\n\
+             e rn3.chr4     81444246  6 + 187371129 I\n\n"
+        );
+    }
+}
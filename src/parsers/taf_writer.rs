@@ -0,0 +1,409 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{BufWriter, Error as IoError, ErrorKind, Write};
+
+use super::Strand;
+use super::{BgzfWriter, MafLine, MafSequence, TafHeader, TaiIndex};
+
+/// Writes MAF blocks as TAF, one line per alignment column
+///
+/// ```text
+/// #taf version:1 run_length_encode_bases:0
+/// AAA ; i 0 hg38.chr1 100 + 1000 i 1 mm10.chr2 50 + 500 i 2 rn6.chr3 10 - 300
+/// CC-
+/// TTT ; d 1 i 1 panTro6.chr1 20 + 800
+/// CCC ; g 1 3 s 2 rn6.chr3 100 - 300
+/// ```
+///
+/// Coordinates are only written when the rows change between blocks. Rows with the same src in
+/// consecutive blocks are kept, and a row that skips ahead on the same strand is given a `g` op
+/// rather than new coordinates. MAF does not carry the skipped bases, so `G` ops are not written.
+/// `i`, `e` and `q` lines are dropped.
+///
+/// The coordinates of every row are repeated at the first column of each new reference sequence
+/// and every [`TafWriter::with_repeat_coordinates`] columns, as reading can only start from these
+/// lines. With [`TafWriter::with_index`], a [`TaiIndex`] of these lines is built as the file is
+/// written.
+pub struct TafWriter<W: Write> {
+    inner: TafOutput<W>,
+    run_length_encode: bool,
+    repeat_coordinates: usize,
+    // Index and block size, when indexing
+    index: Option<(TaiIndex, u64)>,
+    // Rows of the last column, with the position of their next base
+    rows: Vec<TafRow>,
+    columns_since_coordinates: usize,
+    line: String,
+}
+
+impl<W: Write> TafWriter<W> {
+    /// Write plain text TAF
+    pub fn new(inner: W) -> Self {
+        TafWriter::with_output(TafOutput::Plain {
+            inner: BufWriter::new(inner),
+            offset: 0,
+        })
+    }
+
+    /// Write bgzipped TAF
+    pub fn bgzf(inner: W) -> Self {
+        TafWriter::with_output(TafOutput::Bgzf(BgzfWriter::new(inner)))
+    }
+
+    fn with_output(inner: TafOutput<W>) -> Self {
+        TafWriter {
+            inner,
+            run_length_encode: false,
+            repeat_coordinates: 10000,
+            index: None,
+            rows: Vec::new(),
+            columns_since_coordinates: 0,
+            line: String::new(),
+        }
+    }
+
+    /// Run length encode the bases of each column, e.g. `A 3 C 2` for `AAACC`
+    pub fn with_run_length_encoding(mut self, run_length_encode: bool) -> Self {
+        self.run_length_encode = run_length_encode;
+        self
+    }
+
+    /// Repeat the coordinates of every row at least every `columns` columns, 0 to only write them
+    /// when the rows change. Defaults to 10000, as taffy does.
+    pub fn with_repeat_coordinates(mut self, columns: usize) -> Self {
+        self.repeat_coordinates = columns;
+        self
+    }
+
+    /// Build a [`TaiIndex`] while writing, as [`TaiIndex::build`] would with `block_size`
+    pub fn with_index(mut self, block_size: u64) -> Self {
+        self.index = Some((TaiIndex::default(), block_size));
+        self
+    }
+
+    /// Write the `#taf` header line.
+    ///
+    /// `version` and `run_length_encode_bases` are set by the writer, other tags are copied in
+    /// sorted order.
+    pub fn write_header(&mut self, header: &TafHeader) -> Result<(), IoError> {
+        self.line.clear();
+        self.line
+            .push_str("#taf version:1 run_length_encode_bases:");
+        self.line
+            .push_str(if self.run_length_encode { "1" } else { "0" });
+
+        let mut tags: Vec<(&String, &String)> = header
+            .tags
+            .iter()
+            .filter(|(key, _)| *key != "version" && *key != "run_length_encode_bases")
+            .collect();
+        tags.sort();
+        for (key, value) in tags {
+            write!(self.line, " {}:{}", key, value).unwrap();
+        }
+
+        self.line.push('\n');
+        self.inner.write_all(self.line.as_bytes())
+    }
+
+    /// Write the columns of an alignment block
+    pub fn write_block(&mut self, block: &[MafLine]) -> Result<(), IoError> {
+        let seqs: Vec<&MafSequence> = block
+            .iter()
+            .filter_map(|line| match line {
+                MafLine::SequenceLine(seq) => Some(seq),
+                _ => None,
+            })
+            .collect();
+
+        let columns = match seqs.first() {
+            Some(seq) => seq.text.len(),
+            None => return Ok(()),
+        };
+        if seqs.iter().any(|seq| seq.text.len() != columns) {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("Rows of the block at {} differ in length", seqs[0].src),
+            ));
+        }
+        if columns == 0 {
+            return Ok(());
+        }
+
+        let links = link_rows(&self.rows, &seqs);
+        let all_coordinates =
+            self.rows.is_empty() || self.rows[0].src != seqs[0].src || self.repeat_due();
+
+        // Deleted rows, numbered as the rows before them are deleted
+        let mut ops = String::new();
+        let mut linked = vec![false; self.rows.len()];
+        for link in links.iter().flatten() {
+            linked[*link] = true;
+        }
+        let mut row = 0;
+        for linked in linked {
+            if linked {
+                row += 1;
+            } else {
+                write!(ops, " d {}", row).unwrap();
+            }
+        }
+
+        // Then inserted rows, and rows that do not continue from the previous block
+        for (row, (seq, link)) in seqs.iter().zip(links.iter()).enumerate() {
+            let previous = link.map(|x| &self.rows[x]);
+            match previous {
+                None => write_coordinate(&mut ops, 'i', row, &TafRow::from(*seq)),
+                Some(_) if all_coordinates => {
+                    write_coordinate(&mut ops, 's', row, &TafRow::from(*seq))
+                }
+                Some(previous)
+                    if previous.strand == seq.strand && previous.position == seq.start => {}
+                Some(previous)
+                    if previous.strand == seq.strand && previous.position < seq.start =>
+                {
+                    write!(ops, " g {} {}", row, seq.start - previous.position).unwrap()
+                }
+                Some(_) => write_coordinate(&mut ops, 's', row, &TafRow::from(*seq)),
+            }
+        }
+
+        self.rows = seqs.iter().map(|seq| TafRow::from(*seq)).collect();
+        let texts: Vec<&[u8]> = seqs.iter().map(|seq| seq.text.as_bytes()).collect();
+
+        for column in 0..columns {
+            let all_coordinates = if column == 0 {
+                all_coordinates
+            } else {
+                ops.clear();
+                self.repeat_due()
+            };
+            if all_coordinates && column > 0 {
+                for (row, x) in self.rows.iter().enumerate() {
+                    write_coordinate(&mut ops, 's', row, x);
+                }
+            }
+
+            if all_coordinates {
+                self.columns_since_coordinates = 0;
+                if let Some((index, block_size)) = self.index.as_mut() {
+                    let reference = &self.rows[0];
+                    index.add_line(
+                        &reference.src,
+                        reference.position,
+                        self.inner.tell(),
+                        *block_size,
+                    );
+                }
+            }
+            self.columns_since_coordinates += 1;
+
+            self.line.clear();
+            let bases = texts.iter().map(|text| text[column]);
+            if self.run_length_encode {
+                write_run_length(&mut self.line, bases);
+            } else {
+                self.line.extend(bases.map(char::from));
+            }
+            if !ops.is_empty() {
+                self.line.push_str(" ;");
+                self.line.push_str(&ops);
+            }
+            self.line.push('\n');
+            self.inner.write_all(self.line.as_bytes())?;
+
+            for (x, text) in self.rows.iter_mut().zip(texts.iter()) {
+                if text[column] != b'-' {
+                    x.position += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush the output, and for bgzipped output write the EOF marker. Returns the index, if one
+    /// was requested with [`TafWriter::with_index`].
+    pub fn finish(self) -> Result<Option<TaiIndex>, IoError> {
+        self.inner.finish()?;
+        Ok(self.index.map(|(index, _)| index))
+    }
+
+    fn repeat_due(&self) -> bool {
+        self.repeat_coordinates > 0 && self.columns_since_coordinates >= self.repeat_coordinates
+    }
+}
+
+// A row of the alignment, with the position of its next base
+struct TafRow {
+    src: String,
+    strand: Strand,
+    position: u64,
+    src_size: u64,
+}
+
+impl From<&MafSequence> for TafRow {
+    fn from(seq: &MafSequence) -> Self {
+        TafRow {
+            src: seq.src.clone(),
+            strand: seq.strand,
+            position: seq.start,
+            src_size: seq.src_size,
+        }
+    }
+}
+
+fn write_coordinate(out: &mut String, op: char, row: usize, x: &TafRow) {
+    write!(
+        out,
+        " {} {} {} {} {} {}",
+        op, row, x.src, x.position, x.strand, x.src_size
+    )
+    .unwrap();
+}
+
+fn write_run_length(out: &mut String, bases: impl Iterator<Item = u8>) {
+    let mut run: Option<(u8, usize)> = None;
+    for base in bases {
+        run = match run {
+            Some((x, count)) if x == base => Some((x, count + 1)),
+            Some((x, count)) => {
+                write!(out, "{} {} ", x as char, count).unwrap();
+                Some((base, 1))
+            }
+            None => Some((base, 1)),
+        };
+    }
+    if let Some((x, count)) = run {
+        write!(out, "{} {}", x as char, count).unwrap();
+    }
+}
+
+// For each row of the block, the row of the previous column it continues, if any.
+//
+// Rows are linked by src, keeping their order (the longest common subsequence), so that as few
+// rows as possible are deleted and inserted.
+fn link_rows(previous: &[TafRow], rows: &[&MafSequence]) -> Vec<Option<usize>> {
+    // Usually the rows are unchanged
+    if previous.len() == rows.len() && previous.iter().zip(rows).all(|(p, r)| p.src == r.src) {
+        return (0..rows.len()).map(Some).collect();
+    }
+
+    let (n, m) = (previous.len(), rows.len());
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    let mut lengths = vec![0usize; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[at(i, j)] = if previous[i].src == rows[j].src {
+                lengths[at(i + 1, j + 1)] + 1
+            } else {
+                lengths[at(i + 1, j)].max(lengths[at(i, j + 1)])
+            };
+        }
+    }
+
+    let mut links = vec![None; m];
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if previous[i].src == rows[j].src {
+            links[j] = Some(i);
+            i += 1;
+            j += 1;
+        } else if lengths[at(i + 1, j)] >= lengths[at(i, j + 1)] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    links
+}
+
+// Plain output counts bytes written, BGZF output gives virtual offsets
+enum TafOutput<W: Write> {
+    Plain { inner: BufWriter<W>, offset: u64 },
+    Bgzf(BgzfWriter<W>),
+}
+
+impl<W: Write> TafOutput<W> {
+    fn tell(&self) -> u64 {
+        match self {
+            TafOutput::Plain { offset, .. } => *offset,
+            TafOutput::Bgzf(inner) => inner.virtual_offset(),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        match self {
+            TafOutput::Plain { inner, offset } => {
+                inner.write_all(buf)?;
+                *offset += buf.len() as u64;
+                Ok(())
+            }
+            TafOutput::Bgzf(inner) => inner.write_all(buf),
+        }
+    }
+
+    fn finish(self) -> Result<(), IoError> {
+        match self {
+            TafOutput::Plain { mut inner, .. } => inner.flush(),
+            TafOutput::Bgzf(inner) => inner.finish().map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{MafParser, TafAlignmentColumn, TafAlignmentIterator, TafParser};
+    use std::io::Cursor;
+
+    const MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.maf");
+    const TAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.taf.gz");
+
+    fn rows(column: &TafAlignmentColumn) -> Vec<(char, Option<&str>, Option<u64>)> {
+        (0..column.column.alleles.len())
+            .map(|row| {
+                let species = column.species_map[row].as_deref();
+                (column.column.alleles[row], species, column.position(row))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn maf_round_trips_through_taf() {
+        let mut buf = Vec::new();
+        let mut writer = TafWriter::bgzf(&mut buf)
+            .with_run_length_encoding(true)
+            .with_repeat_coordinates(2)
+            .with_index(1);
+        writer.write_header(&TafHeader::default()).unwrap();
+        for block in MafParser::from_path(MAF).unwrap() {
+            writer.write_block(&block.unwrap()).unwrap();
+        }
+        let index = writer.finish().unwrap().unwrap();
+
+        let mut parser = TafParser::new(Cursor::new(buf.clone())).unwrap();
+        assert!(parser.run_length_encode);
+        let written: Vec<TafAlignmentColumn> = TafAlignmentIterator::new(&mut parser)
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let mut expected = TafParser::from_file(TAF).unwrap();
+        let expected: Vec<TafAlignmentColumn> = TafAlignmentIterator::new(&mut expected)
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(written.len(), expected.len());
+        for (written, expected) in written.iter().zip(expected.iter()) {
+            assert_eq!(rows(written), rows(expected));
+        }
+
+        // The index built while writing matches one built from the output
+        let mut parser = TafParser::new(Cursor::new(buf)).unwrap();
+        let built = TaiIndex::build(&mut parser, 1).unwrap();
+        for pos in 100..110 {
+            let seek_info = index.get_seek_info("chr1", pos);
+            assert_eq!(seek_info, built.get_seek_info("chr1", pos));
+            assert!(seek_info.is_some());
+        }
+    }
+}
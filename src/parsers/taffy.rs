@@ -1,81 +1,230 @@
-use flate2::bufread::GzDecoder;
-
+use std::borrow::BorrowMut;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Error as IoError, Lines, Read, Seek, SeekFrom};
+use std::io::{BufRead, BufReader, BufWriter, Error as IoError, ErrorKind, Read, Seek, Write};
 
-use super::ContainsResult;
+use super::maf::split_src;
+use super::{
+    decompress, detect_compression, BgzfReader, Compression, ContainsResult, IndexedInput,
+    MafBlockHeader, MafLine, MafSequence, ReadSeek, Strand,
+};
+use crate::OxidMafError;
 
 /// Represents one index entry for a block.
 #[derive(Debug)]
 pub struct TaiEntry {
-    /// The uncompressed block start coordinate (second column)
+    /// The reference start coordinate of the indexed line
     block_start: u64,
-    /// The file offset (third column). For bgzipped files, this is the compressed offset.
+    /// The file offset. For bgzipped files, this is the BGZF virtual offset.
     offset: u64,
 }
 
-/// The TAI index, mapping a contig name to a vector of index entries.
+// The entries of one reference sequence, in file order
 #[derive(Debug)]
+struct TaiSequence {
+    /// Full sequence name, as in the TAF file (e.g. `hg38.chr1`)
+    name: String,
+    entries: Vec<TaiEntry>,
+}
+
+/// The TAI index, mapping a contig name to a vector of index entries.
+#[derive(Debug, Default)]
 pub struct TaiIndex {
-    entries: HashMap<String, Vec<TaiEntry>>,
+    sequences: Vec<TaiSequence>,
+    // Contig (the sequence name after the genome name) to position in sequences
+    contigs: HashMap<String, usize>,
+    genome_names: Vec<String>,
 }
 
 impl TaiIndex {
     /// Load a TAI index from the given file path.
     ///
-    /// The file is expected to be tab-delimited with three columns.
-    /// The first column holds the contig name or "*" to indicate the same contig as the previous line.
-    /// The second column is the block start (uncompressed coordinate).
-    /// The third column is the file offset (for bgzipped files, the compressed offset).
-    pub fn from_file(path: &str) -> Result<Self, std::io::Error> {
-        let file = File::open(path)?;
+    /// The file is expected to be tab-delimited with three columns, as written by `taffy index`.
+    /// The first column holds the sequence name or "*" to indicate the same sequence as the previous line.
+    /// The second column is the reference position, and the third the file offset (for bgzipped
+    /// files, the BGZF virtual offset). On "*" lines, both are relative to the previous line.
+    pub fn from_file(path: &str) -> Result<Self, OxidMafError> {
+        let file = File::open(path).map_err(|e| OxidMafError::io(path, e))?;
         let reader = BufReader::new(file);
-        let mut entries: HashMap<String, Vec<TaiEntry>> = HashMap::new();
-        let mut current_contig = String::new();
+        let mut index = TaiIndex::default();
 
-        for line in reader.lines() {
-            let line = line?;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| OxidMafError::io(path, e))?;
             if line.trim().is_empty() {
                 continue;
             }
+            let malformed =
+                |message: &str| OxidMafError::malformed(path, i as u64 + 1, &line, message);
 
             let fields: Vec<&str> = line.split('\t').collect();
-            if fields.len() < 3 {
-                continue;
+            if fields.len() != 3 {
+                return Err(malformed(
+                    "Expected a sequence name (or *), position and offset",
+                ));
             }
 
-            // Update the current contig if the first field is not "*"
-            if fields[0] != "*" {
-                // Split after the first dot to get the contig name.
-                let parts: Vec<&str> = fields[0].splitn(2, '.').collect();
-                if parts.len() > 1 {
-                    current_contig = parts[1].to_string();
-                } else {
-                    current_contig = fields[0].to_string();
+            // Parse the block start and offset
+            let mut block_start = fields[1]
+                .parse::<u64>()
+                .map_err(|e| malformed(&format!("Invalid position '{}': {}", fields[1], e)))?;
+            let mut offset = fields[2]
+                .parse::<u64>()
+                .map_err(|e| malformed(&format!("Invalid offset '{}': {}", fields[2], e)))?;
+
+            if fields[0] == "*" {
+                match index.sequences.last().and_then(|x| x.entries.last()) {
+                    Some(previous) => {
+                        block_start += previous.block_start;
+                        offset += previous.offset;
+                    }
+                    None => return Err(malformed("* before the first sequence name")),
                 }
+            } else {
+                index.push_sequence(fields[0]);
             }
 
-            // Parse the block start and offset
-            let block_start = fields[1].parse::<u64>().unwrap_or(0);
-            let offset = fields[2].parse::<u64>().unwrap_or(0);
-            let entry = TaiEntry {
+            index.sequences.last_mut().unwrap().entries.push(TaiEntry {
                 block_start,
                 offset,
+            });
+        }
+        Ok(index)
+    }
+
+    /// Split sequence names into genome and contig using known genome names, for genomes whose
+    /// names contain dots (see [`crate::parsers::MafReader::with_genome_names`])
+    pub fn with_genome_names(mut self, genome_names: &[String]) -> Self {
+        self.genome_names = genome_names.to_vec();
+        self.contigs.clear();
+        for (i, sequence) in self.sequences.iter().enumerate() {
+            let (_, contig) = split_src(&sequence.name, &self.genome_names);
+            self.contigs.insert(contig, i);
+        }
+        self
+    }
+
+    /// Index a plain text or bgzipped TAF file, adding an entry at the first line of each reference sequence
+    /// and then roughly every `block_size` reference bases.
+    ///
+    /// Entries are only added at lines giving the coordinates of every row (the first line, and
+    /// lines where taffy repeats the coordinates), as reading can only start from these.
+    pub fn build(parser: &mut TafParser, block_size: u64) -> Result<Self, OxidMafError> {
+        if !parser.is_seekable() {
+            let e = IoError::new(
+                ErrorKind::InvalidInput,
+                "Only plain text and bgzipped files can be indexed, recompress with bgzip",
+            );
+            return Err(OxidMafError::io(&parser.file, e));
+        }
+
+        let mut index = TaiIndex::default();
+
+        while let Some(column) = parser.next() {
+            let column = column?;
+
+            let mut rows = HashSet::new();
+            let mut reference = None;
+            for op in column.coordinates.iter() {
+                if let CoordinateOp::Insertion {
+                    row,
+                    coord: Some(coord),
+                }
+                | CoordinateOp::Substitution {
+                    row,
+                    coord: Some(coord),
+                } = op
+                {
+                    rows.insert(*row);
+                    if *row == 0 {
+                        reference = Some(coord);
+                    }
+                }
+            }
+
+            let reference = match reference {
+                Some(reference) if (0..column.alleles.len()).all(|x| rows.contains(&x)) => {
+                    reference
+                }
+                _ => continue,
             };
+            index.add_line(
+                &reference.name,
+                reference.offset,
+                parser.line_offset(),
+                block_size,
+            );
+        }
 
-            entries
-                .entry(current_contig.clone())
-                .or_insert_with(Vec::new)
-                .push(entry);
+        Ok(index)
+    }
+
+    // Add an entry for a line giving the coordinates of every row, if it starts a new sequence or
+    // is at least block_size bases past the previous entry
+    pub(super) fn add_line(&mut self, name: &str, block_start: u64, offset: u64, block_size: u64) {
+        let new_sequence = match self.sequences.last() {
+            Some(sequence) => sequence.name != name,
+            None => true,
+        };
+        if new_sequence {
+            self.push_sequence(name);
+        } else {
+            let previous = self.sequences.last().unwrap().entries.last().unwrap();
+            if block_start < previous.block_start + block_size {
+                return;
+            }
         }
-        Ok(TaiIndex { entries })
+
+        self.sequences.last_mut().unwrap().entries.push(TaiEntry {
+            block_start,
+            offset,
+        });
+    }
+
+    /// Write the index in the format read by [`TaiIndex::from_file`] and `taffy`
+    pub fn write(&self, path: &str) -> Result<(), OxidMafError> {
+        let file = File::create(path).map_err(|e| OxidMafError::io(path, e))?;
+        let mut out = BufWriter::new(file);
+
+        let mut write = || -> Result<(), IoError> {
+            for sequence in self.sequences.iter() {
+                let mut previous: Option<&TaiEntry> = None;
+                for entry in sequence.entries.iter() {
+                    match previous {
+                        Some(previous) => writeln!(
+                            out,
+                            "*\t{}\t{}",
+                            entry.block_start - previous.block_start,
+                            entry.offset - previous.offset
+                        )?,
+                        None => writeln!(
+                            out,
+                            "{}\t{}\t{}",
+                            sequence.name, entry.block_start, entry.offset
+                        )?,
+                    }
+                    previous = Some(entry);
+                }
+            }
+            out.flush()
+        };
+
+        write().map_err(|e| OxidMafError::io(path, e))
+    }
+
+    fn push_sequence(&mut self, name: &str) {
+        let (_, contig) = split_src(name, &self.genome_names);
+        self.contigs.insert(contig, self.sequences.len());
+        self.sequences.push(TaiSequence {
+            name: name.to_string(),
+            entries: Vec::new(),
+        });
     }
 
     /// Given a contig and a target uncompressed coordinate, find the block entry with the highest block_start that is <= target.
     /// Returns a tuple: (block_start, file_offset).
     pub fn get_seek_info(&self, contig: &str, pos: u64) -> Option<(u64, u64)> {
-        self.entries.get(contig).and_then(|entries| {
+        self.contigs.get(contig).and_then(|idx| {
+            let entries = &self.sequences[*idx].entries;
             // Since entries are inserted in order, we can iterate until block_start exceeds the target.
             let mut candidate = None;
             for entry in entries {
@@ -90,11 +239,29 @@ impl TaiIndex {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct TafHeader {
     pub tags: HashMap<String, String>,
 }
 
+impl TafHeader {
+    /// The `##maf` line with the same tags, other than `version` and `run_length_encode_bases`
+    pub fn maf_header(&self) -> MafLine {
+        let mut tags: Vec<(&String, &String)> = self
+            .tags
+            .iter()
+            .filter(|(key, _)| *key != "version" && *key != "run_length_encode_bases")
+            .collect();
+        tags.sort();
+
+        let mut line = "#maf version=1".to_string();
+        for (key, value) in tags {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        MafLine::Comment(line)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TafColumn {
     pub raw_bases: String,
@@ -105,6 +272,8 @@ pub struct TafColumn {
 
 #[derive(Debug, Clone)]
 pub struct Coordinate {
+    /// Sequence name as given in the file, `species.chrom`
+    pub name: String,
     pub species: String,
     pub chrom: String,
     pub offset: u64,
@@ -135,115 +304,214 @@ pub enum CoordinateOp {
     },
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Strand {
-    Plus,
-    Minus,
-}
-
 // ---
-// Now, the parser is generic over any type that implements BufRead.
+// The parser reads plain text, gzip, BGZF or zstd compressed TAF.
 
+/// Read a TAF file column by column.
+///
+/// Plain text and bgzipped input can be seeked with [`TafParser::seek_to`], using offsets from a
+/// [`TaiIndex`]. gzip and zstd compressed input, and stdin, can only be read in order.
 pub struct TafParser {
     pub header: TafHeader,
     pub run_length_encode: bool,
-    inner: BufReader<File>,
-    inner_buf: BufReader<Cursor<Vec<u8>>>,
+    inner: TafInput,
     line: String,
+    // Offset of the line of the last column
+    line_offset: u64,
+    // Splits sequence names into species and chrom
+    genome_names: Vec<String>,
+    // For error messages
+    file: String,
+    line_number: u64,
 }
 
 impl TafParser {
-    /// Create a new parser from any reader that implements BufRead.
-    pub fn from_file(path: &str) -> Result<Self, IoError> {
-        let reader = File::open(path).unwrap();
-        let mut reader = BufReader::new(reader);
+    /// Open a TAF file (or stdin, when the path is `-`) and read its header.
+    pub fn from_file(path: &str) -> Result<Self, OxidMafError> {
+        if path == "-" {
+            let stdin = BufReader::new(std::io::stdin());
+            let inner = decompress(stdin).map_err(|e| OxidMafError::io(path, e))?;
+            return TafParser::open(TafInput::Stream { inner, offset: 0 }, path);
+        }
 
-        let mut inner_buf = Vec::new();
-        // Decode the first Gz Block
-        let mut gz_decoder = GzDecoder::new(reader);
-        gz_decoder
-            .read_to_end(&mut inner_buf)
-            .map_err(|e| IoError::new(std::io::ErrorKind::Other, e))?;
+        let file = File::open(path).map_err(|e| OxidMafError::io(path, e))?;
+        let inner = TafInput::new(file).map_err(|e| OxidMafError::io(path, e))?;
+        TafParser::open(inner, path)
+    }
 
-        // Create a new reader from the decompressed data
-        let mut reader = gz_decoder.into_inner();
+    /// Read TAF from a seekable reader, such as a file or an in memory buffer
+    pub fn new<R: Read + Seek + 'static>(reader: R) -> Result<Self, OxidMafError> {
+        let inner = TafInput::new(reader).map_err(|e| OxidMafError::io("<input>", e))?;
+        TafParser::open(inner, "<input>")
+    }
 
-        let mut inner_buf = BufReader::new(std::io::Cursor::new(inner_buf));
+    /// Read TAF from a reader that cannot seek, such as a pipe. [`TafParser::seek_to`] will fail.
+    pub fn from_reader<R: Read + 'static>(reader: R) -> Result<Self, OxidMafError> {
+        let inner =
+            decompress(BufReader::new(reader)).map_err(|e| OxidMafError::io("<input>", e))?;
+        TafParser::open(TafInput::Stream { inner, offset: 0 }, "<input>")
+    }
 
+    fn open(mut inner: TafInput, file: &str) -> Result<Self, OxidMafError> {
         // The header must be the first line.
-        // Get the first line
         let mut header_line = String::new();
-        inner_buf.read_line(&mut header_line)?;
+        inner
+            .read_line(&mut header_line)
+            .map_err(|e| OxidMafError::io(file, e))?;
         if !header_line.starts_with("#taf") {
-            return Err(IoError::new(
-                std::io::ErrorKind::InvalidData,
+            return Err(OxidMafError::malformed(
+                file,
+                1,
+                header_line.trim_end(),
                 "Missing #taf header",
             ));
         }
         let header = parse_taf_header(&header_line);
-        let run_length_encode = match header.tags.get("run_length_encode_bases") {
-            Some(val) if val == "1" => true,
-            _ => false,
-        };
+        let run_length_encode =
+            matches!(header.tags.get("run_length_encode_bases"), Some(val) if val == "1");
         Ok(TafParser {
             header,
             run_length_encode,
-            inner: reader,
+            inner,
             line: String::new(),
-            inner_buf,
+            line_offset: 0,
+            genome_names: Vec::new(),
+            file: file.to_string(),
+            line_number: 1,
         })
     }
 
-    pub fn seek_to(&mut self, pos: u64, block_offset: u64) {
-        // Seek to the specified position in the file.
-        self.inner.seek(SeekFrom::Start(pos)).unwrap();
-
-        // Decode the next block
-        let mut gz_decoder = GzDecoder::new(&mut self.inner);
-        let mut inner_buf = Vec::new();
-        gz_decoder
-            .read_to_end(&mut inner_buf)
-            .expect("Failed to read gzipped data");
-        // Create a new reader from the decompressed data
-        self.inner_buf = BufReader::new(Cursor::new(inner_buf));
-        self.inner_buf
-            .seek(SeekFrom::Start(block_offset))
-            .expect("Failed to seek to block offset");
-    }
-
-    fn read_next_block(&mut self) -> Result<(), IoError> {
-        // Read the next block of data from the inner buffer.
-        let mut gz_decoder = GzDecoder::new(&mut self.inner);
-        let mut inner_buf = Vec::new();
-        gz_decoder
-            .read_to_end(&mut inner_buf)
-            .map_err(|e| IoError::new(std::io::ErrorKind::Other, e))?;
-        // Create a new reader from the decompressed data
-        self.inner_buf = BufReader::new(Cursor::new(inner_buf));
+    /// File name used in error messages
+    pub fn with_file_name(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+
+    /// Genome names used to split sequence names, which may contain dots, into species and chrom
+    /// (see [`crate::parsers::MafReader::with_genome_names`])
+    pub fn with_genome_names(mut self, genome_names: &[String]) -> Self {
+        self.genome_names = genome_names.to_vec();
+        self
+    }
+
+    /// True if the input is plain text or bgzipped, so it can be indexed and seeked
+    pub fn is_seekable(&self) -> bool {
+        matches!(self.inner, TafInput::Seekable(_))
+    }
+
+    /// Seek to an offset stored in a [`TaiIndex`]. For bgzipped files, this is the BGZF virtual
+    /// offset.
+    ///
+    /// Line numbers in subsequent errors count from this point.
+    pub fn seek_to(&mut self, offset: u64) -> Result<(), OxidMafError> {
+        let result = match &mut self.inner {
+            TafInput::Seekable(inner) => inner.seek_to(offset),
+            TafInput::Stream { .. } => Err(IoError::new(
+                ErrorKind::Unsupported,
+                "Cannot seek in gzip or zstd compressed TAF, or stdin. Recompress with bgzip",
+            )),
+        };
+        result.map_err(|e| OxidMafError::io(&self.file, e))?;
+        self.line_number = 0;
         Ok(())
     }
+
+    /// Offset of the line of the last column returned, for indexing
+    pub fn line_offset(&self) -> u64 {
+        self.line_offset
+    }
+}
+
+// Plain text and BGZF can be seeked, anything else is decompressed as a stream
+enum TafInput {
+    Seekable(IndexedInput<Box<dyn ReadSeek>>),
+    Stream {
+        inner: Box<dyn BufRead>,
+        offset: u64,
+    },
+}
+
+impl TafInput {
+    fn new<R: Read + Seek + 'static>(reader: R) -> Result<Self, IoError> {
+        let reader: Box<dyn ReadSeek> = Box::new(reader);
+        let mut reader = BufReader::new(reader);
+
+        match detect_compression(&mut reader)? {
+            Compression::None => Ok(TafInput::Seekable(IndexedInput::Plain {
+                inner: reader,
+                offset: 0,
+            })),
+            Compression::Bgzf => Ok(TafInput::Seekable(IndexedInput::Bgzf(BgzfReader::new(
+                reader,
+            )))),
+            Compression::Gzip | Compression::Zstd => Ok(TafInput::Stream {
+                inner: decompress(reader)?,
+                offset: 0,
+            }),
+        }
+    }
+
+    // Offset of the next byte, uncompressed for streams
+    fn tell(&self) -> u64 {
+        match self {
+            TafInput::Seekable(inner) => inner.tell(),
+            TafInput::Stream { offset, .. } => *offset,
+        }
+    }
+}
+
+impl Read for TafInput {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, IoError> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for TafInput {
+    fn fill_buf(&mut self) -> Result<&[u8], IoError> {
+        match self {
+            TafInput::Seekable(inner) => inner.fill_buf(),
+            TafInput::Stream { inner, .. } => inner.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            TafInput::Seekable(inner) => inner.consume(amt),
+            TafInput::Stream { inner, offset } => {
+                inner.consume(amt);
+                *offset += amt as u64;
+            }
+        }
+    }
 }
 
 impl Iterator for TafParser {
-    type Item = Result<TafColumn, String>;
+    type Item = Result<TafColumn, OxidMafError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Read lines until we find a valid TAF column or EOF.
         loop {
             self.line.clear();
-            if self.inner_buf.read_line(&mut self.line).is_err() {
-                // Try to read the next block
-                match self.read_next_block() {
-                    Ok(_) => continue,
-                    Err(e) => return Some(Err(e.to_string())),
-                }
+            self.line_offset = self.inner.tell();
+            match self.inner.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => self.line_number += 1,
+                Err(e) => return Some(Err(OxidMafError::io(&self.file, e))),
             }
 
             let line = self.line.trim();
             if line.is_empty() || (line.starts_with('#') && !line.starts_with("#taf")) {
                 continue;
             }
-            return Some(parse_taf_column(line, self.run_length_encode));
+            return Some(
+                parse_taf_column(line, self.run_length_encode, &self.genome_names).map_err(
+                    |message| OxidMafError::malformed(&self.file, self.line_number, line, message),
+                ),
+            );
         }
     }
 }
@@ -264,7 +532,11 @@ fn parse_taf_header(line: &str) -> TafHeader {
     TafHeader { tags }
 }
 
-fn parse_taf_column(line: &str, run_length: bool) -> Result<TafColumn, String> {
+fn parse_taf_column(
+    line: &str,
+    run_length: bool,
+    genome_names: &[String],
+) -> Result<TafColumn, String> {
     let tokens: Vec<&str> = line.split_whitespace().collect();
     if tokens.is_empty() {
         return Err("Empty column line".to_string());
@@ -298,9 +570,8 @@ fn parse_taf_column(line: &str, run_length: bool) -> Result<TafColumn, String> {
                 coord_tokens.push(tokens[i]);
                 i += 1;
             }
-        } else if token.starts_with('@') {
+        } else if let Some(tag_str) = token.strip_prefix('@') {
             // Remove the leading '@'
-            let tag_str = &token[1..];
             tag_tokens.push(tag_str);
             i += 1;
             while i < tokens.len() {
@@ -311,7 +582,7 @@ fn parse_taf_column(line: &str, run_length: bool) -> Result<TafColumn, String> {
             i += 1;
         }
     }
-    let coordinates = parse_coordinate_ops(&coord_tokens)?;
+    let coordinates = parse_coordinate_ops(&coord_tokens, genome_names)?;
     let tags = parse_tags(&tag_tokens);
     Ok(TafColumn {
         raw_bases,
@@ -351,7 +622,10 @@ fn decode_bases(s: &str, run_length: bool) -> Result<Vec<char>, String> {
     }
 }
 
-fn parse_coordinate_ops(tokens: &[&str]) -> Result<Vec<CoordinateOp>, String> {
+fn parse_coordinate_ops(
+    tokens: &[&str],
+    genome_names: &[String],
+) -> Result<Vec<CoordinateOp>, String> {
     let mut ops = Vec::new();
     let mut i = 0;
     while i < tokens.len() {
@@ -366,13 +640,10 @@ fn parse_coordinate_ops(tokens: &[&str]) -> Result<Vec<CoordinateOp>, String> {
                     .parse::<usize>()
                     .map_err(|e| format!("Parsing row: {}", e))?;
                 i += 1;
-                // Instead of taking the token as the full sequence name,
-                // split it into species and chromosome.
-                let full_seq_name = tokens[i].to_string();
+                // Split the sequence name into species and chromosome, as MAF srcs
+                let name = tokens[i].to_string();
                 i += 1;
-                let mut parts = full_seq_name.splitn(2, '.');
-                let species = parts.next().unwrap().to_string();
-                let chrom = parts.next().unwrap_or("").to_string();
+                let (species, chrom) = split_src(&name, genome_names);
                 let offset = tokens[i]
                     .parse::<u64>()
                     .map_err(|e| format!("Parsing offset: {}", e))?;
@@ -391,6 +662,7 @@ fn parse_coordinate_ops(tokens: &[&str]) -> Result<Vec<CoordinateOp>, String> {
                     }
                 }
                 let coord = Coordinate {
+                    name,
                     species,
                     chrom,
                     offset,
@@ -465,11 +737,18 @@ fn parse_tags(tokens: &[&str]) -> HashMap<String, String> {
 
 /// An alignment iterator that wraps the TafParser and maintains two mappings:
 /// one for species names and one for current coordinates (updated by coordinate ops).
-pub struct TafAlignmentIterator<'taf> {
-    parser: &'taf mut TafParser,
+///
+/// Coordinate ops are applied in order, before the bases of their column, following the TAF
+/// spec: `i` inserts a row (shifting later rows down), `d` removes a row, `s` replaces the
+/// coordinates of a row, and `g` / `G` skip unaligned bases. Each row then advances by one for
+/// every non-gap base.
+///
+/// The parser can be borrowed (`&mut TafParser`) or owned.
+pub struct TafAlignmentIterator<P: BorrowMut<TafParser>> {
+    parser: P,
     /// Mapping from row index to species name (if known).
     species_map: Vec<Option<String>>,
-    /// Mapping from row index to the current coordinate for that row.
+    /// Mapping from row index to the coordinate of the next base in that row.
     current_coords: Vec<Option<Coordinate>>,
     col_index: usize,
 }
@@ -481,15 +760,19 @@ pub struct TafAlignmentColumn {
     pub column: TafColumn,
     /// The current mapping from row indices to species.
     pub species_map: Vec<Option<String>>,
-    /// The current mapping from row indices to coordinates.
+    /// The current mapping from row indices to coordinates. The offset is the position of the
+    /// row's base in this column, or for a gap, of the next base in that row.
     pub coords: Vec<Option<Coordinate>>,
     /// Column index (0-based) in the alignment.
     pub col_index: usize,
 }
 
-impl<'taf> TafAlignmentIterator<'taf> {
+impl<P: BorrowMut<TafParser>> TafAlignmentIterator<P> {
     /// Create a new alignment iterator from the given parser.
-    pub fn new(parser: &'taf mut TafParser) -> Self {
+    ///
+    /// After seeking, the parser should be at a line giving the coordinates of every row (as
+    /// indexed in a [`TaiIndex`]); rows are unknown until they are given.
+    pub fn new(parser: P) -> Self {
         TafAlignmentIterator {
             parser,
             species_map: Vec::new(),
@@ -497,85 +780,313 @@ impl<'taf> TafAlignmentIterator<'taf> {
             col_index: 0,
         }
     }
+
+    /// The underlying parser, e.g. to seek it. Call [`TafAlignmentIterator::reset`] after moving
+    /// it.
+    pub fn parser_mut(&mut self) -> &mut TafParser {
+        self.parser.borrow_mut()
+    }
+
+    /// Split sequence names with these genome names, see [`TafParser::with_genome_names`]
+    pub fn with_genome_names(mut self, genome_names: &[String]) -> Self {
+        self.parser.borrow_mut().genome_names = genome_names.to_vec();
+        self
+    }
+
+    /// Forget the rows, after the parser has been seeked
+    pub fn reset(&mut self) {
+        self.species_map.clear();
+        self.current_coords.clear();
+    }
+
+    fn apply_op(&mut self, op: &CoordinateOp) -> Result<(), String> {
+        let rows = self.current_coords.len();
+        let out_of_range = |row: usize| format!("Row {} out of range ({} rows)", row, rows);
+
+        match op {
+            CoordinateOp::Insertion { row, coord } => {
+                if *row > rows {
+                    return Err(out_of_range(*row));
+                }
+                self.species_map
+                    .insert(*row, coord.as_ref().map(|c| c.species.clone()));
+                self.current_coords.insert(*row, coord.clone());
+            }
+            CoordinateOp::Deletion { row } => {
+                if *row >= rows {
+                    return Err(out_of_range(*row));
+                }
+                self.species_map.remove(*row);
+                self.current_coords.remove(*row);
+            }
+            CoordinateOp::Substitution { row, coord } => {
+                if *row >= rows {
+                    return Err(out_of_range(*row));
+                }
+                self.species_map[*row] = coord.as_ref().map(|c| c.species.clone());
+                self.current_coords[*row] = coord.clone();
+            }
+            CoordinateOp::Gap { row, gap_length } => {
+                if *row >= rows {
+                    return Err(out_of_range(*row));
+                }
+                if let Some(c) = &mut self.current_coords[*row] {
+                    c.offset += *gap_length as u64;
+                }
+            }
+            CoordinateOp::GapString { row, gap_string } => {
+                if *row >= rows {
+                    return Err(out_of_range(*row));
+                }
+                if let Some(c) = &mut self.current_coords[*row] {
+                    c.offset += gap_string.len() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-impl<'taf> Iterator for TafAlignmentIterator<'taf> {
-    type Item = Result<TafAlignmentColumn, String>;
+impl<P: BorrowMut<TafParser>> Iterator for TafAlignmentIterator<P> {
+    type Item = Result<TafAlignmentColumn, OxidMafError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.parser.next() {
-            Some(Ok(mut col)) => {
-                // Ensure our mappings have as many entries as there are rows.
-                if self.species_map.len() < col.alleles.len() {
-                    self.species_map.resize(col.alleles.len(), None);
-                    self.current_coords.resize(col.alleles.len(), None);
+        let col = match self.parser.borrow_mut().next()? {
+            Ok(col) => col,
+            Err(e) => return Some(Err(e)),
+        };
+
+        // Rows are unknown after seeking to a line that does not give all of them
+        if self.current_coords.is_empty() {
+            let mut rows = col.alleles.len() as i64;
+            for op in &col.coordinates {
+                match op {
+                    CoordinateOp::Insertion { .. } => rows -= 1,
+                    CoordinateOp::Deletion { .. } => rows += 1,
+                    _ => (),
                 }
-                // Track which rows are updated in this column.
-                let mut updated_rows: HashSet<usize> = HashSet::new();
-
-                // Update mappings based on coordinate operations.
-                for op in &col.coordinates {
-                    match op {
-                        CoordinateOp::Insertion { row, coord }
-                        | CoordinateOp::Substitution { row, coord } => {
-                            if let Some(c) = coord {
-                                if *row < self.species_map.len() {
-                                    self.species_map[*row] = Some(c.species.clone());
-                                    self.current_coords[*row] = Some(c.clone());
-                                    updated_rows.insert(*row);
-                                }
-                            }
-                        }
-                        CoordinateOp::Gap { row, gap_length } => {
-                            if *row < self.current_coords.len() {
-                                if let Some(ref mut c) = self.current_coords[*row] {
-                                    c.offset += *gap_length as u64;
-                                }
-                                updated_rows.insert(*row); // treat gap as an update
-                            }
-                        }
-                        CoordinateOp::GapString { row, .. } => {
-                            // You can update similarly if needed.
-                            updated_rows.insert(*row);
-                        }
-                        _ => {}
-                    }
+            }
+            self.species_map.resize(rows.max(0) as usize, None);
+            self.current_coords.resize(rows.max(0) as usize, None);
+        }
+
+        let mut result = Ok(());
+        for op in &col.coordinates {
+            result = self.apply_op(op);
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() && self.current_coords.len() != col.alleles.len() {
+            result = Err(format!(
+                "Column has {} bases, but there are {} rows",
+                col.alleles.len(),
+                self.current_coords.len()
+            ));
+        }
+        if let Err(message) = result {
+            // Start again from the next line giving every row
+            self.reset();
+            let parser: &TafParser = self.parser.borrow();
+            return Some(Err(OxidMafError::malformed(
+                &parser.file,
+                parser.line_number,
+                parser.line.trim(),
+                message,
+            )));
+        }
+
+        let coords = self.current_coords.clone();
+
+        // Each row moves on to its next base, gaps do not consume any
+        for (coord, allele) in self.current_coords.iter_mut().zip(col.alleles.iter()) {
+            if let Some(coord) = coord {
+                if *allele != '-' {
+                    coord.offset += 1;
                 }
-                // For any row that was not updated in this column, advance its coordinate by 1 (if present).
-                for i in 0..self.current_coords.len() {
-                    if !updated_rows.contains(&i) {
-                        if let Some(coord) = &mut self.current_coords[i] {
-                            coord.offset += 1;
-                        }
-                    }
+            }
+        }
+
+        let result = TafAlignmentColumn {
+            column: col,
+            species_map: self.species_map.clone(),
+            coords,
+            col_index: self.col_index,
+        };
+        self.col_index += 1;
+        Some(Ok(result))
+    }
+}
+
+/// Regroups the columns of a [`TafAlignmentIterator`] into MAF alignment blocks.
+///
+/// A new block is started wherever the coordinate ops change the rows, or a row does not continue
+/// from its previous base (after a `g` or `G` op, or an `s` op to another position). Coordinate
+/// ops that only repeat the current coordinates do not start a new block. Each block is an `a`
+/// line followed by one `s` line per row.
+///
+/// Columns where a row is unknown, after a malformed line or seeking to a line that does not give
+/// every row, are skipped until a line gives them all.
+pub struct TafBlockIterator<P: BorrowMut<TafParser>> {
+    columns: TafAlignmentIterator<P>,
+    // First column of the next block, read while finishing the previous one
+    pending: Option<Result<TafAlignmentColumn, OxidMafError>>,
+}
+
+impl<P: BorrowMut<TafParser>> TafBlockIterator<P> {
+    pub fn new(parser: P) -> Self {
+        TafBlockIterator {
+            columns: TafAlignmentIterator::new(parser),
+            pending: None,
+        }
+    }
+
+    /// The underlying parser, e.g. to seek it. Call [`TafBlockIterator::reset`] after moving it.
+    pub fn parser_mut(&mut self) -> &mut TafParser {
+        self.columns.parser_mut()
+    }
+
+    /// Split sequence names with these genome names, see [`TafParser::with_genome_names`]
+    pub fn with_genome_names(mut self, genome_names: &[String]) -> Self {
+        self.columns = self.columns.with_genome_names(genome_names);
+        self
+    }
+
+    /// Forget the rows and any column read ahead, after the parser has been seeked
+    pub fn reset(&mut self) {
+        self.columns.reset();
+        self.pending = None;
+    }
+
+    /// The next column, including one read ahead while finishing the last block, for reading
+    /// columns and blocks from the same parser
+    pub fn next_column(&mut self) -> Option<Result<TafAlignmentColumn, OxidMafError>> {
+        self.pending.take().or_else(|| self.columns.next())
+    }
+
+    // Rows of a new block, starting with this column
+    fn start_block(&self, column: &TafAlignmentColumn) -> Result<Vec<MafSequence>, OxidMafError> {
+        let mut rows = Vec::with_capacity(column.coords.len());
+        for coord in column.coords.iter() {
+            // Checked by the caller
+            let coord = coord.as_ref().unwrap();
+            let src_size = coord.sequence_length.ok_or_else(|| {
+                let parser: &TafParser = self.columns.parser.borrow();
+                OxidMafError::malformed(
+                    &parser.file,
+                    parser.line_number,
+                    parser.line.trim(),
+                    format!("Missing sequence length for {}", coord.name),
+                )
+            })?;
+            rows.push(MafSequence {
+                src: coord.name.clone(),
+                species: coord.species.clone(),
+                contig: coord.chrom.clone(),
+                start: coord.offset,
+                size: 0,
+                strand: coord.strand,
+                src_size,
+                text: String::new(),
+            });
+        }
+        Ok(rows)
+    }
+}
+
+// True if every row of the column is the next base of the same row of the block
+fn continues_block(rows: &[MafSequence], column: &TafAlignmentColumn) -> bool {
+    rows.len() == column.coords.len()
+        && rows.iter().zip(column.coords.iter()).all(|(seq, coord)| {
+            coord.as_ref().is_some_and(|coord| {
+                coord.species == seq.species
+                    && coord.chrom == seq.contig
+                    && coord.strand == seq.strand
+                    && coord.offset == seq.end()
+            })
+        })
+}
+
+fn push_column(rows: &mut [MafSequence], column: &TafAlignmentColumn) {
+    for (seq, allele) in rows.iter_mut().zip(column.column.alleles.iter()) {
+        seq.text.push(*allele);
+        if *allele != '-' {
+            seq.size += 1;
+        }
+    }
+}
+
+impl<P: BorrowMut<TafParser>> Iterator for TafBlockIterator<P> {
+    type Item = Result<Vec<MafLine>, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = loop {
+            let column = match self.next_column()? {
+                Ok(column) => column,
+                Err(e) => return Some(Err(e)),
+            };
+            if column.coords.iter().all(|x| x.is_some()) {
+                break column;
+            }
+        };
+
+        let mut rows = match self.start_block(&first) {
+            Ok(rows) => rows,
+            Err(e) => return Some(Err(e)),
+        };
+        push_column(&mut rows, &first);
+
+        loop {
+            match self.columns.next() {
+                Some(Ok(column)) if continues_block(&rows, &column) => {
+                    push_column(&mut rows, &column)
                 }
-                let result = TafAlignmentColumn {
-                    column: col,
-                    species_map: self.species_map.clone(),
-                    coords: self.current_coords.clone(),
-                    col_index: self.col_index,
-                };
-                self.col_index += 1;
-                Some(Ok(result))
+                // Returned once this block is done
+                Some(column) => {
+                    self.pending = Some(column);
+                    break;
+                }
+                None => break,
             }
-            Some(Err(e)) => Some(Err(e)),
-            None => None,
+        }
+
+        let mut block = Vec::with_capacity(rows.len() + 1);
+        block.push(MafLine::AlignmentBlockLine(MafBlockHeader::default()));
+        block.extend(rows.into_iter().map(MafLine::SequenceLine));
+        Some(Ok(block))
+    }
+}
+
+impl Coordinate {
+    /// The offset on the forward strand. Minus strand offsets count from the end of the
+    /// sequence, so need `sequence_length`.
+    pub fn forward_offset(&self) -> Option<u64> {
+        match self.strand {
+            Strand::Plus => Some(self.offset),
+            Strand::Minus => self
+                .sequence_length
+                .and_then(|len| len.checked_sub(self.offset + 1)),
         }
     }
 }
 
 impl TafAlignmentColumn {
+    /// Zero-based forward strand position of the base of `row` in this column, or `None` for a
+    /// gap or a row with unknown coordinates.
+    pub fn position(&self, row: usize) -> Option<u64> {
+        match self.column.alleles.get(row) {
+            Some('-') | None => None,
+            Some(_) => self.coords.get(row)?.as_ref()?.forward_offset(),
+        }
+    }
+
     /// Returns true if the reference coordinate (at ref_index) matches the given position.
     /// Here we assume:
     ///   - TAF coordinates are 0-based.
     ///   - VCF positions are 1-based.
     pub fn ref_matches_pos(&self, ref_index: usize, pos: u64) -> bool {
-        if let Some(Some(coord)) = self.coords.get(ref_index) {
-            // Compare the coordinate offset to (pos - 1)
-            coord.offset == pos - 1
-        } else {
-            false
-        }
+        // Compare the forward strand position to (pos - 1)
+        pos > 0 && self.position(ref_index) == Some(pos - 1)
     }
 
     /// Given a species name, return the allele (and its row) present in this column.
@@ -592,36 +1103,410 @@ impl TafAlignmentColumn {
 
     /// Return a coordinate for the given chromosome if present.
     pub fn ref_coord(&self, chrom: &str) -> Option<&Coordinate> {
-        self.coords.iter().find_map(|c| {
-            if let Some(coord) = c {
-                if coord.chrom == chrom {
-                    Some(coord)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        })
+        self.coords
+            .iter()
+            .find_map(|c| c.as_ref().filter(|coord| coord.chrom == chrom))
     }
 
-    /// Checks if the reference coordinate for `chrom` contains `pos`.
-    /// If the coordinate has a known length, we use that; otherwise we assume length 1.
+    /// Checks whether the base of the row on `chrom` is at the zero-based forward strand
+    /// position `pos`, or whether `pos` is before or after it.
     pub fn contains_pos(&self, chrom: &str, pos: u64) -> ContainsResult {
-        if let Some(coord) = self.ref_coord(chrom) {
-            let len = coord.sequence_length.unwrap_or(1);
-            let start = coord.offset;
-            let end = start + len;
-            if pos < start {
-                ContainsResult::Before
-            } else if pos >= end {
-                ContainsResult::After
-            } else {
-                ContainsResult::True
+        let row = self.coords.iter().position(|c| match c {
+            Some(coord) => coord.chrom == chrom,
+            None => false,
+        });
+
+        let row = match row {
+            Some(row) => row,
+            None => return ContainsResult::WrongChrom,
+        };
+
+        match self.position(row) {
+            Some(start) if pos == start => ContainsResult::True,
+            Some(start) if pos < start => ContainsResult::Before,
+            Some(_) => ContainsResult::After,
+            // A gap, compare with the next base of the row
+            None => match self.coords[row].as_ref().and_then(|c| c.forward_offset()) {
+                Some(next) if pos < next => ContainsResult::Before,
+                _ => ContainsResult::After,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test_data/coordinates.taf is test_data/coordinates.maf in TAF, with rows deleted, inserted,
+    // substituted and skipping unaligned bases with g and G ops
+    const TAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.taf.gz");
+    const PLAIN_TAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.taf");
+    // test_data/rows.taf is test_data/rows.maf in TAF, with minus strand rows, rows removed and
+    // added between blocks, a row changing strand and rows skipping unaligned bases.
+    // test_data/taffy_fixtures.sh regenerates it with taffy.
+    const ROWS_TAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/rows.taf");
+    const ROWS_MAF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/rows.maf");
+
+    fn columns() -> Vec<TafAlignmentColumn> {
+        let mut parser = TafParser::from_file(TAF).unwrap();
+        TafAlignmentIterator::new(&mut parser)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn rows(column: &TafAlignmentColumn) -> Vec<(&str, Option<u64>)> {
+        (0..column.column.alleles.len())
+            .map(|row| {
+                let species = column.species_map[row].as_deref().unwrap();
+                (species, column.position(row))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn coordinates_follow_ops() {
+        // rn6 is on the minus strand of a 300bp sequence, so forward positions count down
+        let expected = vec![
+            vec![("hg38", Some(100)), ("mm10", Some(50)), ("rn6", Some(289))],
+            vec![("hg38", Some(101)), ("mm10", Some(51)), ("rn6", None)],
+            vec![("hg38", None), ("mm10", Some(52)), ("rn6", Some(288))],
+            vec![("hg38", Some(102)), ("mm10", Some(53)), ("rn6", Some(287))],
+            // d 1 i 1 panTro6.chr1 20 + 800
+            vec![
+                ("hg38", Some(103)),
+                ("panTro6", Some(20)),
+                ("rn6", Some(286)),
+            ],
+            vec![
+                ("hg38", Some(104)),
+                ("panTro6", Some(21)),
+                ("rn6", Some(285)),
+            ],
+            // g 1 3 s 2 rn6.chr3 100 - 300
+            vec![
+                ("hg38", Some(105)),
+                ("panTro6", Some(25)),
+                ("rn6", Some(199)),
+            ],
+            vec![("hg38", Some(106)), ("panTro6", Some(26)), ("rn6", None)],
+            // G 1 GT d 2
+            vec![("hg38", Some(107)), ("panTro6", Some(29))],
+        ];
+
+        let columns = columns();
+        assert_eq!(columns.len(), expected.len());
+        for (column, expected) in columns.iter().zip(expected) {
+            assert_eq!(rows(column), expected, "column {}", column.col_index);
+        }
+    }
+
+    #[test]
+    fn rows_follow_maf() {
+        use crate::parsers::{AlignmentBlock, MafParser};
+
+        // Species, base and forward strand position of each row of each column of the MAF
+        let mut expected = Vec::new();
+        for block in MafParser::from_path(ROWS_MAF).unwrap() {
+            let block = match AlignmentBlock::from_lines(block.unwrap()) {
+                Some(block) => block,
+                None => continue,
+            };
+            for column in block.columns() {
+                let rows: Vec<(String, char, Option<u64>)> = (0..block.species.len())
+                    .map(|i| {
+                        (
+                            block.species[i].clone(),
+                            column.bases[i] as char,
+                            column.positions[i],
+                        )
+                    })
+                    .collect();
+                expected.push(rows);
             }
-        } else {
+        }
+        assert_eq!(expected.len(), 11);
+
+        let mut parser = TafParser::from_file(ROWS_TAF).unwrap();
+        let columns: Vec<Vec<(String, char, Option<u64>)>> = TafAlignmentIterator::new(&mut parser)
+            .map(|column| {
+                let column = column.unwrap();
+                (0..column.column.alleles.len())
+                    .map(|i| {
+                        (
+                            column.coords[i].as_ref().unwrap().species.clone(),
+                            column.column.alleles[i],
+                            column.position(i),
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(columns, expected);
+
+        // Anc0 is on the minus strand of a 50bp sequence, then skips 4 bases (g 1 4), then
+        // changes strand (s 1 ...)
+        let anc0 = |col: usize| {
+            let (_, _, pos) = columns[col].iter().find(|x| x.0 == "Anc0").unwrap();
+            *pos
+        };
+        assert_eq!(anc0(0), Some(44));
+        assert_eq!(anc0(2), None);
+        assert_eq!(anc0(4), Some(41));
+        assert_eq!(anc0(7), Some(34));
+        assert_eq!(anc0(9), Some(3));
+
+        // mm10 is removed (d 2), then added again as the last row (i 2)
+        let species =
+            |col: usize| -> Vec<&str> { columns[col].iter().map(|x| x.0.as_str()).collect() };
+        assert_eq!(species(3), vec!["hg38", "Anc0", "mm10"]);
+        assert_eq!(species(4), vec!["hg38", "panTro6", "Anc0"]);
+        assert_eq!(species(7), vec!["hg38", "Anc0", "mm10"]);
+        assert_eq!(columns[3][2].2, Some(56));
+        assert_eq!(columns[8][2].2, Some(39));
+        assert_eq!(columns[10][2].2, Some(37));
+
+        // The reference skips 11 bases (g 0 11)
+        assert_eq!(columns[8][0].2, Some(18));
+        assert_eq!(columns[9][0].2, Some(30));
+    }
+
+    #[test]
+    fn gaps_keep_next_offset() {
+        let columns = columns();
+
+        // hg38 has a gap in column 2, its next base is 102
+        assert_eq!(columns[2].coords[0].as_ref().unwrap().offset, 102);
+        assert_eq!(columns[3].coords[0].as_ref().unwrap().offset, 102);
+
+        // Minus strand offsets are as in the file
+        assert_eq!(columns[6].coords[2].as_ref().unwrap().offset, 100);
+        assert_eq!(columns[7].coords[2].as_ref().unwrap().offset, 101);
+    }
+
+    #[test]
+    fn allele_for_species_after_row_changes() {
+        let columns = columns();
+
+        assert_eq!(columns[3].allele_for_species("mm10"), Some((1, 'G')));
+        assert_eq!(columns[4].allele_for_species("mm10"), None);
+        assert_eq!(columns[4].allele_for_species("panTro6"), Some((1, 'T')));
+        assert_eq!(columns[5].allele_for_species("rn6"), Some((2, 'G')));
+        assert_eq!(columns[8].allele_for_species("rn6"), None);
+    }
+
+    #[test]
+    fn ref_matches_pos_skips_gaps() {
+        let columns = columns();
+
+        // VCF positions are 1-based
+        assert!(columns[0].ref_matches_pos(0, 101));
+        assert!(!columns[2].ref_matches_pos(0, 103));
+        assert!(columns[3].ref_matches_pos(0, 103));
+        assert!(columns[8].ref_matches_pos(0, 108));
+
+        let matching: Vec<usize> = columns
+            .iter()
+            .filter(|x| x.ref_matches_pos(2, 289))
+            .map(|x| x.col_index)
+            .collect();
+        assert_eq!(matching, vec![2]);
+    }
+
+    #[test]
+    fn contains_pos() {
+        let columns = columns();
+
+        assert_eq!(columns[3].contains_pos("chr1", 102), ContainsResult::True);
+        assert_eq!(columns[3].contains_pos("chr1", 101), ContainsResult::Before);
+        assert_eq!(columns[3].contains_pos("chr1", 103), ContainsResult::After);
+        assert_eq!(columns[2].contains_pos("chr1", 102), ContainsResult::After);
+        assert_eq!(
+            columns[3].contains_pos("chrX", 102),
             ContainsResult::WrongChrom
+        );
+    }
+
+    #[test]
+    fn index_and_seek() {
+        let mut parser = TafParser::from_file(TAF).unwrap();
+        let index = TaiIndex::build(&mut parser, 1).unwrap();
+
+        // Only the first line gives the coordinates of every row
+        let (block_start, offset) = index.get_seek_info("chr1", 105).unwrap();
+        assert_eq!(block_start, 100);
+        assert_eq!(index.get_seek_info("chr1", 99), None);
+
+        parser.seek_to(offset).unwrap();
+        let seeked: Vec<TafAlignmentColumn> = TafAlignmentIterator::new(&mut parser)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let columns = columns();
+        assert_eq!(seeked.len(), columns.len());
+        for (seeked, column) in seeked.iter().zip(columns.iter()) {
+            assert_eq!(rows(seeked), rows(column));
         }
     }
-}
 
+    #[test]
+    fn index_matches_taffy() {
+        // Coordinates of every row are repeated on some lines, see test_data/taffy_fixtures.sh
+        let taf = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/index.taf");
+        let tai = format!("{}.tai", taf);
+
+        let mut parser = TafParser::from_file(taf).unwrap();
+        let index = TaiIndex::build(&mut parser, 10000).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("oxid_maf_tai_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let built = dir.join("built.tai");
+        let built = built.to_str().unwrap();
+        let rewritten = dir.join("rewritten.tai");
+        let rewritten = rewritten.to_str().unwrap();
+        index.write(built).unwrap();
+        TaiIndex::from_file(&tai).unwrap().write(rewritten).unwrap();
+
+        let expected = std::fs::read_to_string(&tai).unwrap();
+        assert_eq!(std::fs::read_to_string(built).unwrap(), expected);
+        assert_eq!(std::fs::read_to_string(rewritten).unwrap(), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The line at 36000 does not give rn6, so reading cannot start there
+        assert_eq!(index.get_seek_info("chr1", 40000), Some((25000, 214)));
+        assert_eq!(index.get_seek_info("chr2", 20000), Some((20000, 437)));
+    }
+
+    #[test]
+    fn index_errors_and_genome_names() {
+        let dir = std::env::temp_dir().join(format!("oxid_maf_tai_names_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.tai");
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "Homo.sapiens.chr1\t10\t41\n*\t5\t20\n").unwrap();
+        let index = TaiIndex::from_file(path).unwrap();
+        assert_eq!(index.get_seek_info("sapiens.chr1", 20), Some((15, 61)));
+        let index = index.with_genome_names(&["Homo.sapiens".to_string()]);
+        assert_eq!(index.get_seek_info("chr1", 20), Some((15, 61)));
+        assert_eq!(index.get_seek_info("sapiens.chr1", 20), None);
+
+        for (tai, line) in [
+            ("hg38.chr1\t10\t41\n*\tx\t20\n", 2),
+            ("hg38.chr1\t10\n", 1),
+            ("*\t10\t41\n", 1),
+        ] {
+            std::fs::write(path, tai).unwrap();
+            match TaiIndex::from_file(path).unwrap_err() {
+                OxidMafError::Malformed { line: x, .. } => assert_eq!(x, line, "{}", tai),
+                e => panic!("{}", e),
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dotted_sequence_names() {
+        // As dotted_srcs in the MAF parser
+        const DOTTED: &str = "#taf version:1\n\
+            AA ; i 0 Homo.sapiens.NC_000001.11 0 + 100 i 1 Kakapo.NC_044298.1_ctg1 5 + 50\n\
+            CC\n";
+        let names = vec!["Homo.sapiens".to_string()];
+        let open = |names: &[String]| {
+            let parser = TafParser::new(std::io::Cursor::new(DOTTED)).unwrap();
+            TafBlockIterator::new(parser).with_genome_names(names)
+        };
+        let rows = |block: Vec<MafLine>| -> Vec<(String, String, String)> {
+            block
+                .into_iter()
+                .filter_map(|line| match line {
+                    MafLine::SequenceLine(seq) => Some((seq.src, seq.species, seq.contig)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let row = |src: &str, species: &str, contig: &str| {
+            (src.to_string(), species.to_string(), contig.to_string())
+        };
+
+        let blocks: Vec<_> = open(&names).collect::<Result<_, _>>().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            rows(blocks[0].clone()),
+            vec![
+                row("Homo.sapiens.NC_000001.11", "Homo.sapiens", "NC_000001.11"),
+                row("Kakapo.NC_044298.1_ctg1", "Kakapo", "NC_044298.1_ctg1"),
+            ]
+        );
+
+        let blocks: Vec<_> = open(&[]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            rows(blocks[0].clone())[0],
+            row("Homo.sapiens.NC_000001.11", "Homo", "sapiens.NC_000001.11")
+        );
+
+        // Columns report the same species and contig
+        let parser = TafParser::new(std::io::Cursor::new(DOTTED))
+            .unwrap()
+            .with_genome_names(&names);
+        let column = TafAlignmentIterator::new(parser).next().unwrap().unwrap();
+        assert_eq!(column.allele_for_species("Homo.sapiens"), Some((0, 'A')));
+        assert!(column.ref_coord("NC_000001.11").is_some());
+    }
+
+    #[test]
+    fn plain_and_gzip_input() {
+        use flate2::write::GzEncoder;
+        use std::io::{Cursor, Write};
+
+        let collect = |mut parser: TafParser| -> Vec<TafAlignmentColumn> {
+            TafAlignmentIterator::new(&mut parser)
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let columns = columns();
+
+        let plain = TafParser::from_file(PLAIN_TAF).unwrap();
+        assert!(plain.is_seekable());
+        let plain = collect(plain);
+
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&std::fs::read(PLAIN_TAF).unwrap()).unwrap();
+        let mut gzip = TafParser::new(Cursor::new(gzip.finish().unwrap())).unwrap();
+        assert!(!gzip.is_seekable());
+        assert!(gzip.seek_to(0).is_err());
+        let gzip = collect(gzip);
+
+        assert_eq!(plain.len(), columns.len());
+        assert_eq!(gzip.len(), columns.len());
+        for ((plain, gzip), column) in plain.iter().zip(gzip.iter()).zip(columns.iter()) {
+            assert_eq!(rows(plain), rows(column));
+            assert_eq!(rows(gzip), rows(column));
+        }
+    }
+
+    #[test]
+    fn blocks_match_maf() {
+        let sequences = |block: Vec<MafLine>| -> Vec<MafSequence> {
+            block
+                .into_iter()
+                .filter_map(|line| match line {
+                    MafLine::SequenceLine(seq) => Some(seq),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let maf = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/coordinates.maf");
+        let expected: Vec<Vec<MafSequence>> = crate::parsers::MafParser::from_path(maf)
+            .unwrap()
+            .map(|block| sequences(block.unwrap()))
+            .collect();
+
+        let mut parser = TafParser::from_file(TAF).unwrap();
+        let blocks: Vec<Vec<MafSequence>> = TafBlockIterator::new(&mut parser)
+            .map(|block| sequences(block.unwrap()))
+            .collect();
+
+        assert_eq!(blocks, expected);
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::BufRead;
+
+use super::open_input;
+use crate::OxidMafError;
+
+/// A score over a region of a contig, from a bedGraph or wig file
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackInterval {
+    pub contig: String,
+    /// Zero-based and half-open
+    pub start: u64,
+    pub end: u64,
+    pub value: f64,
+}
+
+// The current wig section
+enum WigStep {
+    None,
+    Variable {
+        contig: String,
+        span: u64,
+    },
+    Fixed {
+        contig: String,
+        // Start of the next line
+        start: u64,
+        step: u64,
+        span: u64,
+    },
+}
+
+/// Reads a bedGraph or wig (`variableStep` and `fixedStep`) file of scores, such as phyloP or
+/// phastCons, as [`TrackInterval`]s in file order. Files can be plain, gzip, bgzip or zstd
+/// compressed.
+pub struct TrackReader {
+    reader: Box<dyn BufRead>,
+    step: WigStep,
+    // For error messages
+    file: String,
+    line_number: u64,
+}
+
+impl TrackReader {
+    pub fn from_file(file: &str) -> Result<Self, OxidMafError> {
+        let reader = open_input(file).map_err(|e| OxidMafError::io(file, e))?;
+
+        Ok(TrackReader {
+            reader,
+            step: WigStep::None,
+            file: file.to_string(),
+            line_number: 0,
+        })
+    }
+
+    // Start a wig section from its declaration line
+    fn declaration(&mut self, line: &str) -> Result<(), String> {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next().unwrap_or_default();
+
+        let mut contig = None;
+        let mut start = None;
+        let mut step = None;
+        let mut span = 1;
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, found '{}'", field))?;
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|e| format!("Invalid {} '{}': {}", key, value, e))
+            };
+            match key {
+                "chrom" => contig = Some(value.to_string()),
+                "start" => start = Some(number()?),
+                "step" => step = Some(number()?),
+                "span" => span = number()?,
+                _ => {}
+            }
+        }
+        let contig = contig.ok_or("Missing chrom")?;
+
+        self.step = match kind {
+            "variableStep" => WigStep::Variable { contig, span },
+            _ => WigStep::Fixed {
+                contig,
+                // 1-based in the file
+                start: start.ok_or("Missing start")?.saturating_sub(1),
+                step: step.ok_or("Missing step")?,
+                span,
+            },
+        };
+        Ok(())
+    }
+
+    // Parse a data line of the current section, or a bedGraph line
+    fn interval(&mut self, line: &str) -> Result<TrackInterval, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let integer = |i: usize| {
+            fields[i]
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid position '{}': {}", fields[i], e))
+        };
+        let value = |i: usize| {
+            fields[i]
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid value '{}': {}", fields[i], e))
+        };
+
+        match &mut self.step {
+            WigStep::Variable { contig, span } if fields.len() == 2 => {
+                let start = integer(0)?.saturating_sub(1);
+                Ok(TrackInterval {
+                    contig: contig.clone(),
+                    start,
+                    end: start + *span,
+                    value: value(1)?,
+                })
+            }
+            WigStep::Fixed {
+                contig,
+                start,
+                step,
+                span,
+            } if fields.len() == 1 => {
+                let interval = TrackInterval {
+                    contig: contig.clone(),
+                    start: *start,
+                    end: *start + *span,
+                    value: value(0)?,
+                };
+                *start += *step;
+                Ok(interval)
+            }
+            _ if fields.len() == 4 => Ok(TrackInterval {
+                contig: fields[0].to_string(),
+                start: integer(1)?,
+                end: integer(2)?,
+                value: value(3)?,
+            }),
+            _ => Err("Expected a bedGraph line, or wig data after a declaration".to_string()),
+        }
+    }
+}
+
+impl Iterator for TrackReader {
+    type Item = Result<TrackInterval, OxidMafError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => self.line_number += 1,
+                Err(e) => return Some(Err(OxidMafError::io(&self.file, e))),
+            }
+
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+                || line.trim().is_empty()
+            {
+                continue;
+            }
+
+            let result = if line.starts_with("variableStep") || line.starts_with("fixedStep") {
+                match self.declaration(line) {
+                    Ok(()) => continue,
+                    Err(message) => Err(message),
+                }
+            } else {
+                self.interval(line)
+            };
+
+            return Some(result.map_err(|message| {
+                OxidMafError::malformed(&self.file, self.line_number, line, message)
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/conservation.wig");
+
+    #[test]
+    fn wig_steps() {
+        let intervals: Vec<(String, u64, u64, f64)> = TrackReader::from_file(WIG)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .map(|x| (x.contig, x.start, x.end, x.value))
+            .collect();
+
+        let chr1 = |start, value| ("chr1".to_string(), start, start + 1, value);
+        assert_eq!(
+            intervals,
+            vec![
+                ("chr2".to_string(), 0, 2, 0.5),
+                ("chr2".to_string(), 2, 4, -1.25),
+                chr1(2, 2.5),
+                chr1(3, 0.75),
+                chr1(4, 1.0),
+            ]
+        );
+    }
+}
@@ -1,8 +1,8 @@
-use flate2::read::MultiGzDecoder;
+use std::fmt::{Display, Write as FmtWrite};
+use std::io::{BufRead, BufWriter, Error as IoError, Write};
 
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use super::open_input;
+use crate::OxidMafError;
 
 /*
 ##fileformat=VCFv4.2
@@ -19,206 +19,827 @@ ptg000001l      10248   ptg000001l_10248_A_C    A       C       .       PASS
 ptg000001l      13673   ptg000001l_13673_T_C    T       C       .       PASS    .       GT      0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|1     0|0     0|0     0|1     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0  0|0      0|0     1|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0  0|0      0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0  0|0      0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|1     0|0     1|0     0|0     0|0     0|0     0|0     0|0     0|0     1|0     0|0     1|0     0|0     0|0     0|0     0|0     0|0     0|1     0|0     0|0     0|1     1|0     0|0     0|0  0|0      0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|1     0|0     0|0     0|0  0|0      0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|1     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0  0|0      0|0     0|1     0|0     0|0     0|0     1|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     1|0     0|0     0|0     0|0     0|0     0|0     0|1  0|0      0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     0|0     1|0     0|0     1|0     0|0     0|0     1|0     0|0     0|0     0|0     0|0     0|0
 */
 
+/// Reads a VCF 4.2 file (plain, gzip, bgzip or zstd compressed), header first, then one
+/// [`Record`] at a time from [`VcfParser::records`]
 pub struct VcfParser {
-    version: String,
-    info: HashMap<String, String>,
-    format: HashMap<String, String>,
-    samples: Vec<String>,
+    header: VcfHeader,
     reader: Box<dyn BufRead>,
+    // For error messages
+    file: String,
+    line_number: u64,
 }
 
 impl VcfParser {
-    pub fn from_file(file: &str) -> Self {
-        // Is the file compressed? Does it end with .gz?
-        let mut reader: Box<dyn BufRead> = if file.ends_with(".gz") {
-            let file = File::open(file).expect("Error opening VCF file");
-            let decoder = MultiGzDecoder::new(file);
-            Box::new(BufReader::new(decoder))
-        } else {
-            let file = File::open(file).expect("Error opening VCF file");
-            Box::new(BufReader::new(file))
-        };
+    /// Open a VCF file, or stdin when the path is `-`, and read its header
+    pub fn from_file(file: &str) -> Result<Self, OxidMafError> {
+        let mut reader = open_input(file).map_err(|e| OxidMafError::io(file, e))?;
 
-        let (version, info, format, samples) = Self::parse_header(&mut reader);
+        let mut line_number = 0;
+        let header = Self::parse_header(&mut reader, file, &mut line_number)?;
 
-        Self {
-            version,
-            info,
-            format,
-            samples,
+        Ok(Self {
+            header,
             reader,
-        }
+            file: file.to_string(),
+            line_number,
+        })
     }
 
     fn parse_header(
         reader: &mut Box<dyn BufRead>,
-    ) -> (
-        String,
-        HashMap<String, String>,
-        HashMap<String, String>,
-        Vec<String>,
-    ) {
-        let mut version = String::new();
-        let mut info = HashMap::new();
-        let mut format = HashMap::new();
-        let samples;
+        file: &str,
+        line_number: &mut u64,
+    ) -> Result<VcfHeader, OxidMafError> {
+        let mut header = VcfHeader::default();
 
         let mut line = String::new();
         loop {
             line.clear();
-            reader
+            let read = reader
                 .read_line(&mut line)
-                .expect("Error reading VCF header");
-
-            if line.starts_with("##fileformat=") {
-                version = line
-                    .split('=')
-                    .nth(1)
-                    .expect("Error parsing VCF version")
-                    .trim()
-                    .to_string();
-            } else if line.starts_with("##INFO") {
-                let id = line
-                    .split(',')
-                    .nth(0)
-                    .expect("Error parsing INFO ID")
-                    .split('=')
-                    .nth(1)
-                    .expect("Error parsing INFO ID")
-                    .to_string();
-                let description = line
-                    .split(',')
-                    .nth(3)
-                    .expect("Error parsing INFO description")
-                    .split('=')
-                    .nth(1)
-                    .expect("Error parsing INFO description")
-                    .to_string();
-                info.insert(id, description);
-            } else if line.starts_with("##FORMAT") {
-                let id = line
-                    .split(',')
-                    .nth(0)
-                    .expect("Error parsing FORMAT ID")
-                    .split('=')
-                    .nth(1)
-                    .expect("Error parsing FORMAT ID")
-                    .to_string();
-                let description = line
-                    .split(',')
-                    .nth(3)
-                    .expect("Error parsing FORMAT description")
-                    .split('=')
-                    .nth(1)
-                    .expect("Error parsing FORMAT description")
-                    .to_string();
-                format.insert(id, description);
+                .map_err(|e| OxidMafError::io(file, e))?;
+            *line_number += 1;
+
+            if read == 0 {
+                return Err(OxidMafError::malformed(
+                    file,
+                    *line_number,
+                    "",
+                    "Reached end of file before the #CHROM header line",
+                ));
+            }
+
+            let line = line.trim_end_matches(['\n', '\r']);
+            let malformed =
+                |message: &str| OxidMafError::malformed(file, *line_number, line, message);
+
+            if let Some(version) = line.strip_prefix("##fileformat=") {
+                header.version = version.trim().to_string();
+            } else if let Some(meta) = line.strip_prefix("##") {
+                let meta = VcfMeta::parse(meta).map_err(|e| malformed(&e))?;
+                header.meta.push(meta);
             } else if line.starts_with("#CHROM") {
-                samples = line
+                header.samples = line
                     .split('\t')
                     .skip(9)
                     .map(|x| x.trim().to_string())
                     .collect();
                 break;
+            } else {
+                return Err(malformed(
+                    "Expected a ## meta line or the #CHROM header line",
+                ));
             }
         }
 
-        (version, info, format, samples)
+        Ok(header)
+    }
+
+    pub fn header(&self) -> &VcfHeader {
+        &self.header
     }
 
     // Return a records iterator (need to borrow?)
-    pub fn records(&mut self) -> VcfRecords {
+    pub fn records(&mut self) -> VcfRecords<'_> {
         VcfRecords { parser: self }
     }
 }
 
+/// The header of a VCF file: `##` meta lines in file order, and the sample names of the `#CHROM`
+/// line
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VcfHeader {
+    /// From `##fileformat=`, e.g. `VCFv4.2`
+    pub version: String,
+    /// Every other `##` line, in file order
+    pub meta: Vec<VcfMeta>,
+    pub samples: Vec<String>,
+}
+
+impl VcfHeader {
+    pub fn info(&self, id: &str) -> Option<&VcfFieldDefinition> {
+        self.meta.iter().find_map(|x| match x {
+            VcfMeta::Info(x) if x.id == id => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn format(&self, id: &str) -> Option<&VcfFieldDefinition> {
+        self.meta.iter().find_map(|x| match x {
+            VcfMeta::Format(x) if x.id == id => Some(x),
+            _ => None,
+        })
+    }
+
+    /// `##contig` IDs and lengths, in file order
+    pub fn contigs(&self) -> impl Iterator<Item = (&str, Option<u64>)> {
+        self.meta.iter().filter_map(|x| match x {
+            VcfMeta::Contig { id, length, .. } => Some((id.as_str(), *length)),
+            _ => None,
+        })
+    }
+
+    /// Add an `##INFO` definition after the existing ones, replacing any with the same ID
+    pub fn add_info(&mut self, definition: VcfFieldDefinition) {
+        if let Some(x) = self.meta.iter_mut().find_map(|x| match x {
+            VcfMeta::Info(x) if x.id == definition.id => Some(x),
+            _ => None,
+        }) {
+            *x = definition;
+            return;
+        }
+
+        let position = self
+            .meta
+            .iter()
+            .rposition(|x| matches!(x, VcfMeta::Info(_)))
+            .map(|x| x + 1)
+            .unwrap_or(self.meta.len());
+        self.meta.insert(position, VcfMeta::Info(definition));
+    }
+}
+
+/// A `##` meta line of a VCF header (without the leading `##`)
+#[derive(Clone, Debug, PartialEq)]
+pub enum VcfMeta {
+    Info(VcfFieldDefinition),
+    Format(VcfFieldDefinition),
+    /// Keys other than ID and Description, such as Source, are kept in `extra`. Description is
+    /// optional, and only written when given.
+    Filter {
+        id: String,
+        description: Option<String>,
+        extra: Vec<(String, String)>,
+    },
+    /// Keys other than ID and length, such as assembly or md5, are kept in `extra`
+    Contig {
+        id: String,
+        length: Option<u64>,
+        extra: Vec<(String, String)>,
+    },
+    /// Any other line, kept verbatim, e.g. `source=beagle.29Oct24.c8e.jar`
+    Other(String),
+}
+
+impl VcfMeta {
+    fn parse(line: &str) -> Result<Self, String> {
+        let (key, value) = match line.split_once('=') {
+            Some(x) => x,
+            None => return Ok(VcfMeta::Other(line.to_string())),
+        };
+        if !matches!(key, "INFO" | "FORMAT" | "FILTER" | "contig") {
+            return Ok(VcfMeta::Other(line.to_string()));
+        }
+
+        let mut fields = parse_structured(value)
+            .ok_or_else(|| format!("Error parsing {} line, expected <ID=...>", key))?;
+        let mut take = |name: &str| {
+            fields
+                .iter()
+                .position(|(k, _)| k == name)
+                .map(|i| fields.remove(i).1)
+        };
+
+        let id = take("ID").ok_or_else(|| format!("Missing ID in {} line", key))?;
+
+        match key {
+            "INFO" | "FORMAT" => {
+                let number = take("Number").ok_or_else(|| format!("Missing Number for {}", id))?;
+                let ty = take("Type").ok_or_else(|| format!("Missing Type for {}", id))?;
+                let ty = VcfType::parse(&ty).ok_or_else(|| format!("Unknown Type {}", ty))?;
+                let description = take("Description").map(|x| unquote(&x)).unwrap_or_default();
+                let definition = VcfFieldDefinition {
+                    id,
+                    number,
+                    ty,
+                    description,
+                    extra: fields,
+                };
+                if key == "INFO" {
+                    Ok(VcfMeta::Info(definition))
+                } else {
+                    Ok(VcfMeta::Format(definition))
+                }
+            }
+            "FILTER" => {
+                let description = take("Description").map(|x| unquote(&x));
+                Ok(VcfMeta::Filter {
+                    id,
+                    description,
+                    extra: fields,
+                })
+            }
+            _ => {
+                let length = match take("length") {
+                    Some(x) => Some(
+                        x.parse()
+                            .map_err(|e| format!("Error parsing contig length '{}': {}", x, e))?,
+                    ),
+                    None => None,
+                };
+                Ok(VcfMeta::Contig {
+                    id,
+                    length,
+                    extra: fields,
+                })
+            }
+        }
+    }
+}
+
+impl Display for VcfMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VcfMeta::Info(x) => write!(f, "INFO={}", x),
+            VcfMeta::Format(x) => write!(f, "FORMAT={}", x),
+            VcfMeta::Filter {
+                id,
+                description,
+                extra,
+            } => {
+                write!(f, "FILTER=<ID={}", id)?;
+                if let Some(description) = description {
+                    write!(f, ",Description=\"{}\"", description)?;
+                }
+                for (key, value) in extra {
+                    write!(f, ",{}={}", key, value)?;
+                }
+                write!(f, ">")
+            }
+            VcfMeta::Contig { id, length, extra } => {
+                write!(f, "contig=<ID={}", id)?;
+                if let Some(length) = length {
+                    write!(f, ",length={}", length)?;
+                }
+                for (key, value) in extra {
+                    write!(f, ",{}={}", key, value)?;
+                }
+                write!(f, ">")
+            }
+            VcfMeta::Other(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// An `##INFO` or `##FORMAT` definition
+#[derive(Clone, Debug, PartialEq)]
+pub struct VcfFieldDefinition {
+    pub id: String,
+    /// A count, or `A`, `R`, `G` or `.`
+    pub number: String,
+    pub ty: VcfType,
+    pub description: String,
+    /// Other keys, such as Source or Version, with their values as written (quotes included)
+    pub extra: Vec<(String, String)>,
+}
+
+impl Display for VcfFieldDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<ID={},Number={},Type={},Description=\"{}\"",
+            self.id, self.number, self.ty, self.description
+        )?;
+        for (key, value) in self.extra.iter() {
+            write!(f, ",{}={}", key, value)?;
+        }
+        write!(f, ">")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcfType {
+    Integer,
+    Float,
+    Flag,
+    Character,
+    String,
+}
+
+impl VcfType {
+    fn parse(x: &str) -> Option<Self> {
+        match x {
+            "Integer" => Some(VcfType::Integer),
+            "Float" => Some(VcfType::Float),
+            "Flag" => Some(VcfType::Flag),
+            "Character" => Some(VcfType::Character),
+            "String" => Some(VcfType::String),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VcfType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VcfType::Integer => write!(f, "Integer"),
+            VcfType::Float => write!(f, "Float"),
+            VcfType::Flag => write!(f, "Flag"),
+            VcfType::Character => write!(f, "Character"),
+            VcfType::String => write!(f, "String"),
+        }
+    }
+}
+
+/// A typed INFO or FORMAT value. Missing values (`.`) in a list are `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VcfValue {
+    Flag,
+    Integer(Vec<Option<i64>>),
+    Float(Vec<Option<f64>>),
+    Character(Vec<Option<char>>),
+    String(Vec<Option<String>>),
+}
+
+impl VcfValue {
+    /// Parse a comma separated value as `ty`
+    pub fn parse(ty: VcfType, value: &str) -> Result<Self, String> {
+        fn list<T>(
+            value: &str,
+            parse: impl Fn(&str) -> Result<T, String>,
+        ) -> Result<Vec<Option<T>>, String> {
+            value
+                .split(',')
+                .map(|x| {
+                    if x == "." {
+                        Ok(None)
+                    } else {
+                        parse(x).map(Some)
+                    }
+                })
+                .collect()
+        }
+
+        match ty {
+            VcfType::Flag => Ok(VcfValue::Flag),
+            VcfType::Integer => list(value, |x| {
+                x.parse()
+                    .map_err(|e| format!("Error parsing Integer '{}': {}", x, e))
+            })
+            .map(VcfValue::Integer),
+            VcfType::Float => list(value, |x| {
+                x.parse()
+                    .map_err(|e| format!("Error parsing Float '{}': {}", x, e))
+            })
+            .map(VcfValue::Float),
+            VcfType::Character => list(value, |x| {
+                let mut chars = x.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(c),
+                    _ => Err(format!("Expected a single Character, found '{}'", x)),
+                }
+            })
+            .map(VcfValue::Character),
+            VcfType::String => list(value, |x| Ok(x.to_string())).map(VcfValue::String),
+        }
+    }
+}
+
+/// A GT value, e.g. `0|1`, `1/1`, `0|1/2` or `.`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Genotype {
+    /// Allele indexes (0 for REF, 1 for the first ALT), `None` when missing
+    pub alleles: Vec<Option<usize>>,
+    /// One per allele, true when it is phased with the allele before it (separated by `|`). The
+    /// first allele has no separator, and is true.
+    pub phased: Vec<bool>,
+}
+
+impl Genotype {
+    pub fn parse(x: &str) -> Result<Self, String> {
+        let alleles = x
+            .split(['/', '|'])
+            .map(|allele| match allele {
+                "." => Ok(None),
+                allele => allele
+                    .parse()
+                    .map(Some)
+                    .map_err(|e| format!("Error parsing genotype '{}': {}", x, e)),
+            })
+            .collect::<Result<_, _>>()?;
+        let phased = std::iter::once(true)
+            .chain(x.matches(['/', '|']).map(|x| x == "|"))
+            .collect();
+
+        Ok(Genotype { alleles, phased })
+    }
+
+    /// True when every allele is separated by `|`. Haploid calls count as phased.
+    pub fn is_phased(&self) -> bool {
+        self.phased.iter().all(|x| *x)
+    }
+}
+
+impl Display for Genotype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, allele) in self.alleles.iter().enumerate() {
+            if i > 0 {
+                let phased = self.phased.get(i).copied().unwrap_or(false);
+                write!(f, "{}", if phased { '|' } else { '/' })?;
+            }
+            match allele {
+                Some(allele) => write!(f, "{}", allele)?,
+                None => write!(f, ".")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Key and value pairs of <ID=AF,Number=A,Description="a, b">, values as written
+fn parse_structured(value: &str) -> Option<Vec<(String, String)>> {
+    let value = value.strip_prefix('<')?.strip_suffix('>')?;
+
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars().chain(std::iter::once(',')) {
+        match c {
+            ',' if !quoted => {
+                let (key, value) = field.split_once('=')?;
+                fields.push((key.to_string(), value.to_string()));
+                field.clear();
+                continue;
+            }
+            '"' if !escaped => quoted = !quoted,
+            _ => (),
+        }
+        escaped = c == '\\' && !escaped;
+        field.push(c);
+    }
+
+    Some(fields)
+}
+
+fn unquote(x: &str) -> String {
+    x.strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .unwrap_or(x)
+        .to_string()
+}
+
 pub struct VcfRecords<'a> {
     parser: &'a mut VcfParser,
 }
 
-impl<'a> Iterator for VcfRecords<'a> {
-    type Item = Record;
+impl Iterator for VcfRecords<'_> {
+    type Item = Result<Record, OxidMafError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut line = String::new();
 
         loop {
             line.clear();
-            self.parser
-                .reader
-                .read_line(&mut line)
-                .expect("Error reading VCF record");
+            if let Err(e) = self.parser.reader.read_line(&mut line) {
+                return Some(Err(OxidMafError::io(&self.parser.file, e)));
+            }
+            self.parser.line_number += 1;
 
             if line.is_empty() {
                 return None;
             }
 
-            if line.starts_with("#") {
+            if line.starts_with("#") || line.trim().is_empty() {
                 continue;
             }
 
-            let fields: Vec<&str> = line.split('\t').collect();
+            let line = line.trim_end_matches(['\n', '\r']);
+            return Some(Record::parse(line).map_err(|message| {
+                OxidMafError::malformed(&self.parser.file, self.parser.line_number, line, message)
+            }));
+        }
+    }
+}
+
+/// A VCF record. FORMAT keys, and the values of each sample, are kept in file order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub chrom: String,
+    /// 1-based
+    pub pos: u64,
+    pub id: String,
+    pub ref_: String,
+    /// Empty when ALT is `.`
+    pub alt: Vec<String>,
+    pub qual: String,
+    pub filter: String,
+    /// INFO keys and values in file order, flags have an empty value
+    pub info: Vec<(String, String)>,
+    pub format: Vec<String>,
+    /// Values of each sample, in the order of `format`. Trailing values may be missing.
+    pub samples: Vec<Vec<String>>,
+}
+
+impl Record {
+    fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
 
-            let chrom = fields[0].to_string();
-            let pos = fields[1].parse().expect("Error parsing VCF position");
-            let id = fields[2].to_string();
-            let ref_ = fields[3].to_string();
-            let alt = fields[4].to_string();
-            let qual = fields[5].to_string();
-            let filter = fields[6].to_string();
+        if fields.len() < 8 {
+            return Err(format!(
+                "Expected at least 8 columns, found {}",
+                fields.len()
+            ));
+        }
 
-            let mut info = HashMap::new();
-            for field in fields[7].split(';') {
-                let parts: Vec<&str> = field.split('=').collect();
-                if parts.len() == 2 {
-                    info.insert(parts[0].to_string(), parts[1].to_string());
-                } else {
-                    info.insert(parts[0].to_string(), "".to_string());
-                }
+        let pos = fields[1]
+            .parse()
+            .map_err(|e| format!("Error parsing VCF position '{}': {}", fields[1], e))?;
+
+        let alt = match fields[4] {
+            "." => Vec::new(),
+            alt => alt.split(',').map(|x| x.to_string()).collect(),
+        };
+
+        let info = match fields[7] {
+            "." => Vec::new(),
+            info => info
+                .split(';')
+                .map(|x| match x.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => (x.to_string(), String::new()),
+                })
+                .collect(),
+        };
+
+        let format = match fields.get(8) {
+            Some(format) => format.split(':').map(|x| x.to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        let samples = fields
+            .iter()
+            .skip(9)
+            .map(|sample| sample.split(':').map(|x| x.to_string()).collect())
+            .collect();
+
+        Ok(Record {
+            chrom: fields[0].to_string(),
+            pos,
+            id: fields[2].to_string(),
+            ref_: fields[3].to_string(),
+            alt,
+            qual: fields[5].to_string(),
+            filter: fields[6].to_string(),
+            info,
+            format,
+            samples,
+        })
+    }
+
+    /// Value of an INFO key, empty for flags
+    pub fn info(&self, key: &str) -> Option<&str> {
+        self.info
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of an INFO key, typed by its `##INFO` definition (String if not defined)
+    pub fn info_value(&self, key: &str, header: &VcfHeader) -> Option<Result<VcfValue, String>> {
+        let value = self.info(key)?;
+        let ty = header.info(key).map(|x| x.ty).unwrap_or(VcfType::String);
+        Some(VcfValue::parse(ty, value))
+    }
+
+    /// Set an INFO key, replacing its value or adding it at the end. Use an empty value for flags.
+    pub fn set_info(&mut self, key: &str, value: &str) {
+        match self.info.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.info.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Value of a FORMAT key for a sample, `None` if the key is absent or the value was dropped
+    pub fn sample_value(&self, sample: usize, key: &str) -> Option<&str> {
+        let i = self.format.iter().position(|x| x == key)?;
+        self.samples.get(sample)?.get(i).map(|x| x.as_str())
+    }
+
+    /// The GT of a sample
+    pub fn genotype(&self, sample: usize) -> Option<Result<Genotype, String>> {
+        self.sample_value(sample, "GT").map(Genotype::parse)
+    }
+
+    /// Replace the GT of a sample. GT must already be a FORMAT key.
+    pub fn set_genotype(&mut self, sample: usize, genotype: &Genotype) {
+        let i = match self.format.iter().position(|x| x == "GT") {
+            Some(i) => i,
+            None => return,
+        };
+        if let Some(values) = self.samples.get_mut(sample) {
+            if values.len() <= i {
+                values.resize(i + 1, ".".to_string());
             }
+            values[i] = genotype.to_string();
+        }
+    }
+
+    /// REF followed by the ALT alleles, so allele indexes of a [`Genotype`] can be looked up
+    pub fn alleles(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.ref_.as_str()).chain(self.alt.iter().map(|x| x.as_str()))
+    }
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t",
+            self.chrom, self.pos, self.id, self.ref_
+        )?;
 
-            let mut format = HashMap::new();
-            for field in fields[8].split(':') {
-                format.insert(field.to_string(), "".to_string());
+        if self.alt.is_empty() {
+            write!(f, ".")?;
+        } else {
+            write!(f, "{}", self.alt.join(","))?;
+        }
+        write!(f, "\t{}\t{}\t", self.qual, self.filter)?;
+
+        if self.info.is_empty() {
+            write!(f, ".")?;
+        }
+        for (i, (key, value)) in self.info.iter().enumerate() {
+            if i > 0 {
+                write!(f, ";")?;
+            }
+            if value.is_empty() {
+                write!(f, "{}", key)?;
+            } else {
+                write!(f, "{}={}", key, value)?;
             }
+        }
 
-            let mut samples = Vec::new();
-            for sample in fields.iter().skip(9) {
-                let mut sample_fields = HashMap::new();
-                for (key, value) in format.iter() {
-                    sample_fields.insert(key.to_string(), value.to_string());
-                }
-                for (key, value) in sample.split(':').zip(sample_fields.iter_mut()) {
-                    *value.1 = key.to_string();
-                }
-                samples.push(sample_fields);
+        if !self.format.is_empty() {
+            write!(f, "\t{}", self.format.join(":"))?;
+            for sample in self.samples.iter() {
+                write!(f, "\t{}", sample.join(":"))?;
             }
+        }
+        Ok(())
+    }
+}
 
-            return Some(Record {
-                chrom,
-                pos,
-                id,
-                ref_,
-                alt,
-                qual,
-                filter,
-                info,
-                format,
-                samples,
-            });
+/// Writes a VCF header and records, as read by [`VcfParser`]
+pub struct VcfWriter<W: Write> {
+    inner: BufWriter<W>,
+    line: String,
+}
+
+impl<W: Write> VcfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        VcfWriter {
+            inner: BufWriter::new(inner),
+            line: String::new(),
         }
     }
+
+    /// Write the `##fileformat` line, the other meta lines and the `#CHROM` line
+    pub fn write_header(&mut self, header: &VcfHeader) -> Result<(), IoError> {
+        let version = if header.version.is_empty() {
+            "VCFv4.2"
+        } else {
+            &header.version
+        };
+        writeln!(self.inner, "##fileformat={}", version)?;
+        for meta in header.meta.iter() {
+            writeln!(self.inner, "##{}", meta)?;
+        }
+
+        write!(self.inner, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")?;
+        if !header.samples.is_empty() {
+            write!(self.inner, "\tFORMAT")?;
+            for sample in header.samples.iter() {
+                write!(self.inner, "\t{}", sample)?;
+            }
+        }
+        writeln!(self.inner)
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> Result<(), IoError> {
+        self.line.clear();
+        // Writing to a String does not fail
+        writeln!(self.line, "{}", record).unwrap();
+        self.inner.write_all(self.line.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
 }
 
-pub struct Record {
-    pub chrom: String,
-    pub pos: u64,
-    pub id: String,
-    pub ref_: String,
-    pub alt: String,
-    pub qual: String,
-    pub filter: String,
-    pub info: HashMap<String, String>,
-    pub format: HashMap<String, String>,
-    pub samples: Vec<HashMap<String, String>>,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VCF: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/small.vcf");
+
+    fn read() -> (VcfHeader, Vec<Record>) {
+        let mut parser = VcfParser::from_file(VCF).unwrap();
+        let records = parser.records().collect::<Result<_, _>>().unwrap();
+        (parser.header().clone(), records)
+    }
+
+    #[test]
+    fn header() {
+        let (header, _) = read();
+
+        assert_eq!(header.version, "VCFv4.2");
+        assert_eq!(header.samples, vec!["S1", "S2", "S3"]);
+        assert_eq!(
+            header.contigs().collect::<Vec<_>>(),
+            vec![("chr1", Some(1000)), ("chr2", None)]
+        );
+        assert_eq!(header.info("IMP").unwrap().ty, VcfType::Flag);
+        assert_eq!(
+            header.info("NOTE").unwrap().description,
+            "Free text, with a comma"
+        );
+        assert_eq!(header.format("DS").unwrap().number, "A");
+        assert!(header.meta.contains(&VcfMeta::Filter {
+            id: "LowQual".to_string(),
+            description: Some("Low quality, by GATK".to_string()),
+            extra: vec![
+                ("Source".to_string(), "\"GATK\"".to_string()),
+                ("Version".to_string(), "4.2".to_string())
+            ],
+        }));
+        assert!(header.meta.contains(&VcfMeta::Other(
+            "source=\"beagle.29Oct24.c8e.jar\"".to_string()
+        )));
+    }
+
+    #[test]
+    fn records() {
+        let (header, records) = read();
+
+        assert_eq!(records[1].alt, vec!["T", "GA"]);
+        assert_eq!(
+            records[1].alleles().collect::<Vec<_>>(),
+            vec!["G", "T", "GA"]
+        );
+        assert!(records[2].alt.is_empty());
+
+        assert_eq!(
+            records[0].info_value("AF", &header),
+            Some(Ok(VcfValue::Float(vec![Some(0.25)])))
+        );
+        assert_eq!(
+            records[0].info_value("IMP", &header),
+            Some(Ok(VcfValue::Flag))
+        );
+        assert_eq!(
+            records[1].info_value("AF", &header),
+            Some(Ok(VcfValue::Float(vec![Some(0.5), None])))
+        );
+
+        // FORMAT keys keep their order, and trailing values may be dropped
+        assert_eq!(records[1].sample_value(0, "DS"), Some("1,1"));
+        assert_eq!(records[0].sample_value(0, "DS"), None);
+        assert_eq!(
+            records[1].genotype(0),
+            Some(Ok(Genotype {
+                alleles: vec![Some(1), Some(2)],
+                phased: vec![true, true]
+            }))
+        );
+        assert_eq!(
+            records[0].genotype(2),
+            Some(Ok(Genotype {
+                alleles: vec![None, None],
+                phased: vec![true, false]
+            }))
+        );
+        assert_eq!(
+            records[1].genotype(2).unwrap().unwrap().alleles,
+            vec![Some(2)]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let (header, records) = read();
+
+        let mut out = Vec::new();
+        let mut writer = VcfWriter::new(&mut out);
+        writer.write_header(&header).unwrap();
+        for record in records.iter() {
+            // Including GTs written back, such as the mixed phase 0|1/2
+            let mut record = record.clone();
+            for sample in 0..record.samples.len() {
+                if let Some(Ok(genotype)) = record.genotype(sample) {
+                    record.set_genotype(sample, &genotype);
+                }
+            }
+            writer.write_record(&record).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        // Including ##FILTER=<ID=Imputed>, which has no Description
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            std::fs::read_to_string(VCF).unwrap()
+        );
+    }
 }
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Regenerate the TAF fixtures with taffy (https://github.com/ComparativeGenomicsToolkit/taffy),
+# then rerun cargo test: rows.taf from rows.maf, and index.taf.tai, the index of index.taf with
+# taffy's default block size of 10000.
+set -e
+cd "$(dirname "$0")"
+
+taffy view -i rows.maf > rows.taf
+taffy index -i index.taf